@@ -0,0 +1,56 @@
+//! Times each `SearchStrategy` of `NearestColor` on palettes of 2^5 to 2^12
+//! colors, next to the strategy that `NearestColor::new` picks for them:
+//! random palettes searched for random colors, and palettes clustered like
+//! those of images searched for colors near their own.
+//!
+//! `cargo bench --bench nearest_color`
+
+use quadtree_img::quantize::palette::Color;
+use quadtree_img::quantize::{DistanceMetric, NearestColor, SearchStrategy};
+use std::time::Instant;
+
+/// Repeatable pseudorandom opaque colors (a linear congruential generator).
+fn random_colors(count: usize, seed: u64) -> Vec<Color> {
+	let mut state = seed;
+	(0..count).map(|_| {
+		state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+		image::Rgba(((state >> 32) as u32 | 0xff).to_be_bytes())
+	}).collect()
+}
+
+/// Each color of `colors` moved by up to `spread` from one of `centers`, in
+/// turn.
+fn near(centers: &[Color], colors: &[Color], spread: u8) -> Vec<Color> {
+	colors.iter().enumerate().map(|(ind, c)| {
+		let center = centers[ind % centers.len()];
+		let [r, g, b] = [0, 1, 2].map(|ch| center.0[ch].saturating_add(c.0[ch] % (2 * spread)).saturating_sub(spread));
+		image::Rgba([r, g, b, 255])
+	}).collect()
+}
+
+/// Prints the time that each strategy takes per color of `queries`.
+fn compare(name: &str, colors: &[Color], queries: &[Color]) {
+	let chosen = NearestColor::new(colors.to_vec(), DistanceMetric::Euclidean).strategy();
+	print!("{:>5} colors, {}: new picks {:?};", colors.len(), name, chosen);
+	for strategy in [SearchStrategy::Linear, SearchStrategy::Cells, SearchStrategy::KdTree].iter().copied() {
+		let search = NearestColor::with_strategy(colors.to_vec(), DistanceMetric::Euclidean, strategy).unwrap();
+		let start = Instant::now();
+		let found = queries.iter().map(|c| search.find(c) as u64).sum::<u64>();
+		let elapsed = start.elapsed();
+		// Keeps the searches from being optimized out
+		assert!(found < u64::MAX);
+		print!(" {:?} {:.0} ns", strategy, elapsed.as_nanos() as f64 / queries.len() as f64);
+	}
+	println!();
+}
+
+fn main() {
+	let queries = random_colors(100_000, 1);
+	let centers = random_colors(8, 2);
+	for width in 5..=12 {
+		let colors = random_colors(1 << width, width as u64);
+		compare("random", &colors, &queries);
+		let clustered = near(&centers, &colors, 24);
+		compare("clustered", &clustered, &near(&clustered, &queries, 8));
+	}
+}
@@ -0,0 +1,26 @@
+//! Renders a QTI file to PNG with the palette chosen at runtime, as a
+//! `Box<dyn Palette>`: an `IdentityPalette` for files without a palette, and
+//! a `DynamicPaletteView` for the others.
+//!
+//! `cargo run --example render_dyn -- INPUT.qti OUTPUT.png`
+
+use quadtree_img::quantize::palette::Palette;
+use quadtree_img::{qti, QuadtreeNode};
+
+fn main() {
+	let args = std::env::args().collect::<Vec<_>>();
+	if args.len() != 3 {
+		eprintln!("Usage: render_dyn INPUT.qti OUTPUT.png");
+		std::process::exit(2);
+	}
+	let data = std::fs::read(&args[1]).expect("failure to read input file");
+	let (tree, palette): (QuadtreeNode<Box<dyn Palette>>, Box<dyn Palette>) =
+		QuadtreeNode::from_qti(&data).expect("failure to decode QTI data");
+	println!("{:?}", palette);
+	// Files encoded without gradients are marked flat
+	let gradient = !qti::read_header(&data).expect("failure to read QTI header").flat;
+	tree.render(&palette, gradient)
+		.expect("failure to render quadtree")
+		.save(&args[2])
+		.expect("failure to save output file");
+}
\ No newline at end of file
@@ -0,0 +1,1133 @@
+use image::error::ImageError;
+
+use crate::{codec, GradientFit, QuadtreeNode};
+use crate::quantize;
+use crate::quantize::palette::{IdentityPalette, Palette};
+use crate::qti;
+use crate::metrics;
+use crate::image as image_render;
+use crate::error::{AnalyzeError, DecodeError, DecodeImageError, DrawError, EncodeImageError};
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use std::io::{Read, Write};
+
+/// An error message and the status code to exit with.
+type CliResult<T> = Result<T, (String, i32)>;
+
+/// Helper function for `run`.
+fn fail<T>(msg: &str, code: i32) -> CliResult<T> {
+	Err((msg.to_string(), code))
+}
+
+/// Helper function for `run`; the failure for a QTI file of a format version
+/// that can't be read.
+fn unsupported_version<T>(version: u8) -> CliResult<T> {
+	fail(&format!("QTI version {} is not supported by this build", version), 6)
+}
+
+/// The paths of a file to convert (or `-` for standard input) and of where
+/// to write the output (or `-` for standard output), if given.
+#[derive(Clone, Copy)]
+struct Paths<'a> {
+	input: &'a str,
+	output: Option<&'a str>,
+}
+
+/// Helper function for `run`; reads the whole of the file at `path`, or of
+/// `stdin` if that is `-`.
+fn read_input(path: &str, stdin: &mut dyn Read) -> CliResult<Vec<u8>> {
+	let mut data = Vec::new();
+	let read = match path {
+		"-" => stdin.read_to_end(&mut data),
+		path => match File::open(path) {
+			Ok(mut f) => f.read_to_end(&mut data),
+			Err(_) => return fail("File not found or could not be read", 3)
+		}
+	};
+	match read {
+		Ok(_) => Ok(data),
+		Err(_) => fail("Could not read from input file", 3)
+	}
+}
+
+/// Helper function for `run`; where to write the output: the output path,
+/// or by default the input path with the file extension `ext`, or `None`
+/// for standard output, if the output is `-` or it isn't given and the
+/// input is.
+fn output_path(paths: Paths, ext: &str) -> Option<String> {
+	match (paths.output, paths.input) {
+		(Some("-"), _) | (None, "-") => None,
+		(Some(path), _) => Some(path.to_string()),
+		(None, input_path) => Some(input_path.rsplitn(2, '.').last().unwrap().to_string() + "." + ext)
+	}
+}
+
+/// Helper function for `run`; writes `data` to the file at `path`, or to
+/// `stdout` if that is `None`.
+fn write_output(path: Option<&str>, data: &[u8], stdout: &mut dyn Write) -> CliResult<()> {
+	let written = match path {
+		// Rust writes bytes to standard output as they are, even on Windows
+		None => stdout.write_all(data).and_then(|_| stdout.flush()),
+		Some(path) => match File::create(path) {
+			Ok(mut f) => f.write_all(data),
+			Err(_) => return fail("Could not open output file", 3)
+		}
+	};
+	match written {
+		Ok(()) => Ok(()),
+		Err(_) => fail("Could not write to output file", 3)
+	}
+}
+
+/// Helper function for `run`; reads a PNG or JFIF image from `data`, in the
+/// format that the extension of `path` names, if any, or else the format
+/// that the data looks like.
+fn load_image(data: &[u8], path: &str) -> CliResult<image::RgbaImage> {
+	let loaded = match image::ImageFormat::from_path(path) {
+		Ok(format) => image::load_from_memory_with_format(data, format),
+		Err(_) => image::load_from_memory(data)
+	};
+	match loaded {
+		Ok(i) => Ok(i.into_rgba8()),
+		Err(e) => {
+			let (msg, code) = match e {
+				ImageError::Decoding(_) => ("Invalid image data", 4),
+				ImageError::Limits(_) => ("Computation limits exceeded", 5),
+				ImageError::IoError(_) => ("File not found or could not be read", 3),
+				_ => ("An error occurred", 10)
+			};
+			fail(msg, code)
+		}
+	}
+}
+
+/// Helper function for `run`; parses an optional numeric argument.
+fn parse_arg<T: std::str::FromStr>(
+	clap_matches: &clap::ArgMatches,
+	name: &str,
+	default: &str,
+	desc: &str
+) -> CliResult<T> {
+	match clap_matches.value_of(name).unwrap_or(default).parse() {
+		Ok(n) => Ok(n),
+		Err(_) => fail(&format!("Non-numeric value for {}", desc), 2)
+	}
+}
+
+/// Helper function for `run`; parses an optional numeric argument that
+/// defaults to none.
+fn parse_optional_arg<T: std::str::FromStr>(
+	clap_matches: &clap::ArgMatches,
+	name: &str,
+	desc: &str
+) -> CliResult<Option<T>> {
+	match clap_matches.value_of(name).map(str::parse) {
+		Some(Ok(n)) => Ok(Some(n)),
+		Some(Err(_)) => fail(&format!("Non-numeric value for {}", desc), 2),
+		None => Ok(None)
+	}
+}
+
+/// Parses a hex color of the form `RRGGBB` or `RRGGBBAA` (optionally
+/// prefixed with `#`).
+fn parse_hex_color(s: &str) -> Option<quantize::palette::Color> {
+	let s = s.trim_start_matches('#');
+	if !(s.len() == 6 || s.len() == 8) || !s.is_ascii() {
+		return None;
+	}
+	let mut c = [255; 4];
+	for (ind, chunk) in s.as_bytes().chunks(2).enumerate() {
+		c[ind] = u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+	}
+	Some(image::Rgba(c))
+}
+
+/// The `clap` definition of the CLI's subcommands and their arguments.
+fn app() -> clap::App<'static, 'static> {
+	clap::App::new("quadtree_img")
+		.version("0.1.0")
+		.author("vkcz")
+		.about("Converts to and from a quadtree-based image compression format (QTI).")
+		.setting(clap::AppSettings::ArgRequiredElseHelp)
+		.setting(clap::AppSettings::ArgsNegateSubcommands)
+		.setting(clap::AppSettings::SubcommandsNegateReqs)
+		.setting(clap::AppSettings::VersionlessSubcommands)
+		.arg_from_usage("<INPUT> 'Path to input file, or - for standard input, to be decoded if it is QTI or else encoded, with the default settings; use a subcommand to choose the direction or settings`")
+		.arg_from_usage("[OUTPUT] 'Path to output file, or - for standard output; defaults to INPUT with a .qti or .png file extension, or standard output if INPUT is -`")
+		.subcommand(encode_app())
+		.subcommand(decode_app())
+		.subcommand(clap::SubCommand::with_name("info")
+			.about("Prints information about a file: a suggested palette size for PNG or JFIF, or the header of QTI")
+			.arg_from_usage("--palette 'Print each palette color of a QTI file, as #RRGGBBAA'")
+			.arg_from_usage("--deep 'Also decode the tree of a QTI file, and print its number of nodes at each depth, how many colors it uses, and how many of its nodes could be removed with no change to the image as drawn without gradients'")
+			.arg_from_usage("<INPUT> 'Path to input file, or - for standard input`"))
+}
+
+/// Helper for `app`; the `encode` subcommand.
+fn encode_app() -> clap::App<'static, 'static> {
+	clap::SubCommand::with_name("encode")
+		.about("Converts a PNG or JFIF image to QTI")
+		.arg_from_usage("-d, --dedup=[N] 'Color distance threshold for palette deduplication; defaults to 256'")
+		.arg_from_usage("--palette-algo=[ALGO] 'Palette generation algorithm, dedup or median-cut; defaults to dedup'")
+		.arg_from_usage("--palette-downsample=[N] 'Factor to scale the image down by before generating a dedup palette, to match leaf averaging; defaults to 1'")
+		.arg_from_usage("-c, --colors=[N] 'Maximum number of palette colors for median-cut; defaults to 256'")
+		.arg_from_usage("--metric=[METRIC] 'Color distance metric, euclidean, weighted-rgb or lab; defaults to euclidean'")
+		.arg_from_usage("--kmeans=[N] 'Number of k-means iterations to refine the palette with; defaults to 0'")
+		.arg_from_usage("--kmeans-epsilon=[E] 'Stop k-means refinement once no palette color moves more than E; defaults to 0'")
+		.arg_from_usage("-b, --blur=[N] 'Amount of precompression blur; defaults to 1'")
+		.arg_from_usage("--linear-blur 'Blur in linear light rather than sRGB, keeping edges between contrasting colors from darkening'")
+		.arg_from_usage("--transparent-below=[ALPHA] 'Give pixels with alpha below ALPHA a transparent palette color of their own, and keep them out of the blur, for crisp cutout edges; defaults to none'")
+		.arg_from_usage("-s, --sensitivity=[N] 'Noise sensitivity as a fraction S/(S+1); defaults to 63/64'")
+		.arg_from_usage("--snap=[N] 'Merge quadrants whose colors are all within squared distance N of the most common one, for better trimming; defaults to none'")
+		.arg_from_usage("--max-depth=[N] 'Subdivide at most N levels below the root, giving the deepest squares their most common colors, to bound the size of the output; defaults to no limit'")
+		.arg_from_usage("-t, --trim=[N] 'Number of times to trim output; defaults to 0'")
+		.arg_from_usage("--trim-error=[N] 'Merge leaves into their average color wherever none is more than squared distance N from it, after any --trim; defaults to none'")
+		.arg_from_usage("--max-bytes=[N] 'Merge the leaves that change the image least until the output is at most N bytes, after any other trimming; defaults to no limit'")
+		.arg_from_usage("--trim-depth=[N] 'Depth below which each trim removes leaves; defaults to 6'")
+		.arg_from_usage("--thumbnail 'Embed a small preview image in the output'")
+		.arg_from_usage("--compact-palette 'Write a version 4 file, storing exactly the palette colors used'")
+		.arg_from_usage("--lossless 'Store exact colors rather than a palette, with no blur or gradients and full sensitivity, so that the output is lossless unless trimmed; files are much larger'")
+		.arg_from_usage("--no-gradient 'Encode without gradients, marking the file flat; by default, files are encoded with gradients'")
+		.arg_from_usage("--gradient-fit=[FIT] 'How to pick gradient colors, corners or mesh (a least-squares fit); defaults to corners'")
+		.arg_from_usage("--stats 'Print the MSE, PSNR and SSIM of the encoded image, compared with the input, with premultiplied alpha, how much of the MSE comes from the palette and from the tree, and the size of the tree and how many of its subtrees are distinct'")
+		.arg_from_usage("-q, --quiet 'Print nothing but errors and requested --stats, rather than also the number of palette colors and the compression ratio'")
+		.args(&batch_args("qti"))
+}
+
+/// Helper for `app`; the `decode` subcommand.
+fn decode_app() -> clap::App<'static, 'static> {
+	clap::SubCommand::with_name("decode")
+		.about("Converts a QTI file to PNG (or another format, by the output's file extension); indexed, with the palette of the file, if that has at most 256 colors and the image is in them alone, as files encoded with --no-gradient are unless drawn with --gradient, --background or --smooth; RGBA otherwise, as gradients blend the palette's colors")
+		.arg_from_usage("-g, --gradient 'Render with gradients even if the file was encoded without them; by default, files are rendered in the mode they were encoded in'")
+		.arg(clap::Arg::from_usage("--no-gradient 'Render without gradients even if the file was encoded with them; rendering in the other mode than a file was encoded in gives a degraded but valid image'")
+			.conflicts_with("gradient"))
+		.arg_from_usage("-w, --width=[N] 'Output image width (and, for now, also height); must be a power of two; defaults to the dimensions stored in the file, if any, or else the size at which every node is at least one pixel'")
+		.arg_from_usage("--background=[COLOR] 'Hex color (RRGGBB or RRGGBBAA) to composite the image over; defaults to none'")
+		.arg_from_usage("--smooth 'Interpolate between the colors of all leaves larger than a pixel, not just some'")
+		.arg_from_usage("--average-branches 'With --smooth, blend leaves with the average colors of subdivided neighbors, rather than the colors those store for previews'")
+		.arg_from_usage("--max-depth=[N] 'Draw nodes at most N levels below the root, for a quick preview; defaults to no limit'")
+		.arg(clap::Arg::from_usage("--thumbnail=[N] 'Draw an N by N preview (N being a power of two), descending only the levels of the tree with a pixel per node; much faster than decoding at full size and scaling down'")
+			.conflicts_with_all(&["width", "max-depth"]))
+		.arg_from_usage("--max-size=[N] 'Largest output width (and height) to draw, so that files without stored dimensions can't take all memory, or 0 for no limit; defaults to 16384'")
+		.args(&batch_args("png"))
+}
+
+/// Helper for `encode_app` and `decode_app`; the arguments for the files
+/// to convert, into files with the extension `ext`.
+fn batch_args(ext: &'static str) -> Vec<clap::Arg<'static, 'static>> {
+	let input_help = match ext {
+		"qti" => "Path to input file, or - for standard input, then optionally to output file, or - for standard output; the output defaults to INPUT with a .qti file extension, or standard output if INPUT is -. With more than two paths, --out-dir or --recursive, or a directory first, each path is an input file or directory instead, and each image is converted to a file of the same name with a .qti extension",
+		_ => "Path to input file, or - for standard input, then optionally to output file, or - for standard output (as PNG); the output defaults to INPUT with a .png file extension, or standard output if INPUT is -. With more than two paths, --out-dir or --recursive, or a directory first, each path is an input file or directory instead, and each .qti file is converted to a file of the same name with a .png extension"
+	};
+	vec![
+		clap::Arg::with_name("INPUT").required(true).multiple(true).help(input_help),
+		clap::Arg::from_usage("--out-dir=[DIR] 'Directory to write the output files of a batch to, made if need be; defaults to that of each input'"),
+		clap::Arg::from_usage("-r, --recursive 'Also convert the files in subdirectories of input directories, keeping their layout under --out-dir'"),
+		clap::Arg::from_usage("-j, --jobs=[N] 'Number of files of a batch to convert at once, with the rayon feature; defaults to the number of cores'"),
+	]
+}
+
+/// `clap`-based CLI for working with QTI files, with the subcommands
+/// `encode` (PNG or JFIF to QTI), `decode` (QTI to PNG) and `info`; given
+/// no subcommand, QTI input is decoded and anything else is encoded, with
+/// the default settings.
+///
+/// `args` includes the program name, as with `std::env::args_os`. Messages
+/// are written to `stdout` and `stderr` rather than the process's own.
+///
+/// Returns the status code for the process to exit with:
+///
+/// 0: success
+///
+/// 1: `clap` error
+///
+/// 2: invalid arguments
+///
+/// 3: file I/O issues
+///
+/// 4: invalid image data
+///
+/// 5: computation limits exceeded
+///
+/// 6: unsupported QTI version
+///
+/// 10: other, potentially unknown error
+///
+/// A batch of files exits with the status of the first that failed, if any.
+pub fn run<I: IntoIterator<Item = OsString>>(
+	args: I,
+	stdout: &mut dyn Write,
+	stderr: &mut dyn Write
+) -> i32 {
+	run_with(args, &mut std::io::stdin(), stdout, stderr)
+}
+
+/// `run`, reading standard input from `stdin` rather than the process's own.
+pub(crate) fn run_with<I: IntoIterator<Item = OsString>>(
+	args: I,
+	stdin: &mut dyn Read,
+	stdout: &mut dyn Write,
+	stderr: &mut dyn Write
+) -> i32 {
+	let clap_matches = match app().get_matches_from_safe(args) {
+		Ok(m) => m,
+		Err(e) => return match e.kind {
+			clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => {
+				let _ = writeln!(stdout, "{}", e.message);
+				0
+			},
+			_ => {
+				let _ = writeln!(stderr, "{}", e.message);
+				1
+			}
+		}
+	};
+
+	let result = match clap_matches.subcommand() {
+		("encode", Some(m)) => convert_all(m, true, stdin, stdout, stderr),
+		("decode", Some(m)) => convert_all(m, false, stdin, stdout, stderr),
+		("info", Some(m)) => {
+			let input_path = m.value_of("INPUT").unwrap();
+			read_input(input_path, stdin).and_then(|source| print_info(m, input_path, &source, stdout))
+		},
+		// Without a subcommand, the direction is that of the input
+		_ => {
+			let paths = Paths { input: clap_matches.value_of("INPUT").unwrap(), output: clap_matches.value_of("OUTPUT") };
+			read_input(paths.input, stdin).and_then(|source| if qti::is_qti(&source) {
+				decode(&clap_matches, paths, &source, stdout, stderr)
+			} else {
+				encode(&clap_matches, paths, &source, stdout, stderr)
+			})
+		}
+	};
+	match result {
+		Ok(()) => 0,
+		Err((msg, code)) => {
+			// Batches report their own failures
+			if !msg.is_empty() {
+				let _ = writeln!(stderr, "{}", msg);
+			}
+			code
+		}
+	}
+}
+
+/// Helper function for `run`; converts the inputs of the `encode` subcommand
+/// (if `encoding`) or the `decode` one: one file, perhaps to a given output,
+/// or a batch of them (see `convert_batch`).
+fn convert_all(
+	clap_matches: &clap::ArgMatches,
+	encoding: bool,
+	stdin: &mut dyn Read,
+	stdout: &mut dyn Write,
+	stderr: &mut dyn Write
+) -> CliResult<()> {
+	let paths = clap_matches.values_of("INPUT").unwrap().collect::<Vec<_>>();
+	if paths.len() > 2 || clap_matches.is_present("out-dir") || clap_matches.is_present("recursive") ||
+			Path::new(paths[0]).is_dir() {
+		return convert_batch(clap_matches, encoding, &paths, stderr);
+	}
+	convert(clap_matches, encoding, Paths { input: paths[0], output: paths.get(1).copied() }, stdin, stdout, stderr)
+}
+
+/// Helper function for `convert_all`; converts one file.
+fn convert(
+	clap_matches: &clap::ArgMatches,
+	encoding: bool,
+	paths: Paths,
+	stdin: &mut dyn Read,
+	stdout: &mut dyn Write,
+	stderr: &mut dyn Write
+) -> CliResult<()> {
+	let source = read_input(paths.input, stdin)?;
+	if encoding {
+		encode(clap_matches, paths, &source, stdout, stderr)
+	} else {
+		decode(clap_matches, paths, &source, stdout, stderr)
+	}
+}
+
+/// Helper function for `convert_all`; converts each of the files in `paths`,
+/// and those in the directories in it (and their subdirectories, with
+/// `--recursive`), that are of the kind to convert, into files of the same
+/// names in `--out-dir` or alongside them.
+///
+/// Files that fail don't stop the others; they are reported along with a
+/// summary at the end, and the status is that of the first of them.
+fn convert_batch(
+	clap_matches: &clap::ArgMatches,
+	encoding: bool,
+	paths: &[&str],
+	stderr: &mut dyn Write
+) -> CliResult<()> {
+	let ext = if encoding { "qti" } else { "png" };
+	let out_dir = clap_matches.value_of("out-dir").map(Path::new);
+	// Each input, with its path relative to the output directory
+	let mut files = Vec::new();
+	let mut failures = Vec::new();
+	for path in paths.iter().map(Path::new) {
+		if !path.is_dir() {
+			files.push((path.to_path_buf(), PathBuf::from(path.file_name().unwrap_or_default())));
+		} else if let Err(e) = find_files(path, path, encoding, clap_matches.is_present("recursive"), &mut files) {
+			failures.push((path.to_path_buf(), e));
+		}
+	}
+	let convert_file = |(input, relative): &(PathBuf, PathBuf)| -> (Vec<u8>, CliResult<(u64, u64)>) {
+		let output = match out_dir {
+			Some(dir) => dir.join(relative).with_extension(ext),
+			None => input.with_extension(ext)
+		};
+		let mut messages = Vec::new();
+		let result = match (input.to_str(), output.to_str()) {
+			(Some("-"), _) => fail("Standard input can't be part of a batch", 2),
+			(Some(input), Some(output_str)) => output.parent()
+				.filter(|_| out_dir.is_some())
+				.map_or(Ok(()), |dir| std::fs::create_dir_all(dir).or_else(|_| fail("Could not make output directory", 3)))
+				.and_then(|_| convert(
+					clap_matches,
+					encoding,
+					Paths { input, output: Some(output_str) },
+					&mut std::io::empty(),
+					&mut std::io::sink(),
+					&mut messages
+				))
+				.map(|_| (
+					std::fs::metadata(input).map_or(0, |m| m.len()),
+					std::fs::metadata(&output).map_or(0, |m| m.len())
+				)),
+			_ => fail("Path is not valid Unicode", 2)
+		};
+		(messages, result)
+	};
+	#[cfg(feature = "rayon")]
+	let results = {
+		use rayon::prelude::*;
+		let jobs = parse_optional_arg(clap_matches, "jobs", "jobs")?;
+		let pool = match rayon::ThreadPoolBuilder::new().num_threads(jobs.unwrap_or(0)).build() {
+			Ok(pool) => pool,
+			Err(_) => return fail("Could not start worker threads", 10)
+		};
+		pool.install(|| files.par_iter().map(convert_file).collect::<Vec<_>>())
+	};
+	#[cfg(not(feature = "rayon"))]
+	let results = files.iter().map(convert_file).collect::<Vec<_>>();
+	let (mut succeeded, mut bytes_in, mut bytes_out) = (0, 0, 0);
+	for ((input, _), (messages, result)) in files.iter().zip(results) {
+		for line in String::from_utf8_lossy(&messages).lines() {
+			let _ = writeln!(stderr, "{}: {}", input.display(), line);
+		}
+		match result {
+			Ok((size_in, size_out)) => {
+				succeeded += 1;
+				bytes_in += size_in;
+				bytes_out += size_out;
+			},
+			Err(e) => failures.push((input.clone(), e))
+		}
+	}
+	for (path, (msg, _)) in failures.iter() {
+		let _ = writeln!(stderr, "{}: {}", path.display(), msg);
+	}
+	if !clap_matches.is_present("quiet") {
+		let _ = writeln!(stderr, "{} succeeded, {} failed; {} bytes in, {} bytes out",
+			succeeded, failures.len(), bytes_in, bytes_out);
+	}
+	match failures.into_iter().next() {
+		Some((_, (_, code))) => Err((String::new(), code)),
+		None => Ok(())
+	}
+}
+
+/// Helper function for `convert_batch`; adds the files in `dir` that are of
+/// the kind to convert (images if `encoding`, or else QTI files, by their
+/// extensions) to `files`, with their paths relative to `base`, in order of
+/// name, and with `recursive`, also those in its subdirectories.
+fn find_files(
+	base: &Path,
+	dir: &Path,
+	encoding: bool,
+	recursive: bool,
+	files: &mut Vec<(PathBuf, PathBuf)>
+) -> CliResult<()> {
+	let mut entries = match std::fs::read_dir(dir).and_then(|entries| entries.collect::<Result<Vec<_>, _>>()) {
+		Ok(entries) => entries.into_iter().map(|e| e.path()).collect::<Vec<_>>(),
+		Err(_) => return fail("Could not read directory", 3)
+	};
+	entries.sort();
+	for path in entries {
+		if path.is_dir() {
+			if recursive {
+				find_files(base, &path, encoding, recursive, files)?;
+			}
+			continue;
+		}
+		let wanted = if encoding {
+			image::ImageFormat::from_path(&path).is_ok()
+		} else {
+			path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("qti"))
+		};
+		if wanted {
+			// The path was found under `base`
+			let relative = path.strip_prefix(base).unwrap().to_path_buf();
+			files.push((path, relative));
+		}
+	}
+	Ok(())
+}
+
+/// The `encode` subcommand of `run`, for one file.
+fn encode(
+	clap_matches: &clap::ArgMatches,
+	paths: Paths,
+	source_data: &[u8],
+	stdout: &mut dyn Write,
+	stderr: &mut dyn Write
+) -> CliResult<()> {
+	let source = load_image(source_data, paths.input)?;
+	let sensitivity = parse_arg::<usize>(clap_matches, "sensitivity", "63", "sensitivity")?;
+	let gradient = !clap_matches.is_present("no-gradient");
+	let encode_options = codec::EncodeImageOptions::default()
+		.dedup(parse_arg(clap_matches, "dedup", "256", "dedup")?)
+		.palette_downsample(parse_arg(clap_matches, "palette-downsample", "1", "palette downsampling")?)
+		.max_colors(match clap_matches.value_of("palette-algo").unwrap_or("dedup") {
+			"dedup" => None,
+			"median-cut" => Some(parse_arg(clap_matches, "colors", "256", "colors")?),
+			_ => return fail("Invalid value for palette algorithm", 2)
+		})
+		.kmeans(quantize::RefineOptions {
+			max_iterations: parse_arg(clap_matches, "kmeans", "0", "k-means iterations")?,
+			convergence: parse_arg(clap_matches, "kmeans-epsilon", "0", "k-means convergence threshold")?
+		})
+		.metric(match clap_matches.value_of("metric").unwrap_or("euclidean") {
+			"euclidean" => quantize::DistanceMetric::Euclidean,
+			"weighted-rgb" => quantize::DistanceMetric::WeightedRgb,
+			"lab" => quantize::DistanceMetric::Lab,
+			_ => return fail("Invalid value for color distance metric", 2)
+		})
+		.blur(parse_arg(clap_matches, "blur", "1", "blur")?)
+		.linear_blur(clap_matches.is_present("linear-blur"))
+		.transparent_below(parse_optional_arg(clap_matches, "transparent-below", "transparency threshold")?)
+		.sensitivity(sensitivity as f32 / (sensitivity as f32 + 1.))
+		.gradient(match clap_matches.value_of("gradient-fit").unwrap_or("corners") {
+			_ if !gradient => None,
+			"corners" => Some(GradientFit::Corners),
+			"mesh" => Some(GradientFit::Mesh),
+			_ => return fail("Invalid value for gradient fit", 2)
+		})
+		.snap(parse_optional_arg(clap_matches, "snap", "snap tolerance")?)
+		.max_depth(parse_optional_arg(clap_matches, "max-depth", "max depth")?)
+		.trim(parse_arg(clap_matches, "trim", "0", "trim")?)
+		.trim_depth(parse_arg(clap_matches, "trim-depth", "6", "trim depth")?)
+		.trim_error(parse_optional_arg(clap_matches, "trim-error", "trim error")?)
+		.max_bytes(parse_optional_arg(clap_matches, "max-bytes", "max bytes")?)
+		.compact_palette(clap_matches.is_present("compact-palette"))
+		.thumbnail(clap_matches.is_present("thumbnail"));
+	if clap_matches.is_present("lossless") {
+		let encode_options = encode_options.blur(0.).sensitivity(1.).gradient(None);
+		let result = codec::encode_with_palette(&source, IdentityPalette, &encode_options);
+		return write_encoded(clap_matches, paths, stdout, stderr, (source_data, &source), result, &encode_options);
+	}
+	let result = codec::encode_to_parts(&source, &encode_options);
+	if let (Ok(ref encoded), false) = (&result, clap_matches.is_present("quiet")) {
+		let _ = writeln!(stderr, "{} colors in generated palette", encoded.palette.colors.len());
+	}
+	write_encoded(clap_matches, paths, stdout, stderr, (source_data, &source), result, &encode_options)
+}
+
+/// Helper for `encode`; reports errors and statistics, and writes the output.
+/// `source` is the input file's data and the image read from it.
+fn write_encoded<P: Palette + Default>(
+	clap_matches: &clap::ArgMatches,
+	paths: Paths,
+	stdout: &mut dyn Write,
+	stderr: &mut dyn Write,
+	(source_data, source): (&[u8], &image::RgbaImage),
+	result: Result<codec::Encoded<P>, EncodeImageError>,
+	encode_options: &codec::EncodeImageOptions
+) -> CliResult<()> {
+	let gradient = encode_options.gradient.is_some();
+	let encoded = match result {
+		Ok(e) => e,
+		Err(EncodeImageError::Analyze(AnalyzeError::EmptyImage)) => return fail("Input image is empty", 4),
+		Err(EncodeImageError::Analyze(AnalyzeError::EmptyPalette)) => return fail("Palette has no colors", 4),
+		// TODO: Add support for non-square/non-power-of-two images
+		Err(EncodeImageError::Analyze(_)) => return fail("Input image has invalid dimensions", 4),
+		Err(EncodeImageError::TooLarge) => return fail("Output cannot be made small enough for max bytes", 2),
+		// A color in the quadtree out of range of the palette; the quadtree
+		// is generated from the image, so this is a bug in the program
+		Err(EncodeImageError::Encode(e)) => return fail(&format!("Could not encode QTI: {:?}", e), 10)
+	};
+	let (tree, palette) = (&encoded.tree, &encoded.palette);
+	if clap_matches.is_present("stats") {
+		let mut rendered = image::RgbaImage::new(source.width(), source.height());
+		if let Err(e) = tree.to_image(&mut rendered, palette, None, None, gradient, None) {
+			return fail(&format!("Could not render encoded image: {:?}", e), 10);
+		}
+		let options = metrics::MetricOptions { alpha_mode: metrics::AlphaMode::PremultiplyFirst };
+		// The images have the same dimensions, so comparison cannot fail
+		let _ = writeln!(stderr, "MSE: {:.3}", metrics::mse(source, &rendered, &options).unwrap());
+		let _ = writeln!(stderr, "PSNR: {:.3} dB", metrics::psnr(source, &rendered, &options).unwrap());
+		let _ = writeln!(stderr, "SSIM: {:.5}", metrics::ssim(source, &rendered, &options).unwrap());
+		if let Ok((quantization, subdivision)) = metrics::error_breakdown(source, palette, tree, gradient, encode_options.metric, &options) {
+			let _ = writeln!(stderr, "MSE from quantization: {:.3}", quantization);
+			let _ = writeln!(stderr, "MSE from subdivision: {:.3}", subdivision);
+		}
+		let _ = writeln!(
+			stderr,
+			"Nodes: {} ({} leaves, {} distinct subtrees), depth {}",
+			tree.node_count(),
+			tree.leaf_count(),
+			tree.count_unique_subtrees(),
+			tree.depth()
+		);
+	}
+	write_output(output_path(paths, "qti").as_deref(), &encoded.data, stdout)?;
+	if !clap_matches.is_present("quiet") {
+		let _ = writeln!(
+			stderr,
+			"{} bytes in, {} bytes out; compression ratio {:.2}",
+			source_data.len(),
+			encoded.data.len(),
+			source_data.len() as f64 / encoded.data.len() as f64
+		);
+	}
+	Ok(())
+}
+
+/// The `decode` subcommand of `run`, for one file.
+fn decode(
+	clap_matches: &clap::ArgMatches,
+	paths: Paths,
+	source_data: &[u8],
+	stdout: &mut dyn Write,
+	stderr: &mut dyn Write
+) -> CliResult<()> {
+	let options = codec::DecodeImageOptions::default()
+		.width(parse_optional_arg(clap_matches, "width", "width")?)
+		.gradient(match (clap_matches.is_present("gradient"), clap_matches.is_present("no-gradient")) {
+			(true, _) => Some(true),
+			(_, true) => Some(false),
+			_ => None
+		})
+		.background(match clap_matches.value_of("background").map(parse_hex_color) {
+			Some(None) => return fail("Invalid value for background", 2),
+			Some(c) => c,
+			None => None
+		})
+		.upscale(if clap_matches.is_present("smooth") {
+			image_render::UpscaleMode::Smooth
+		} else {
+			image_render::UpscaleMode::Nearest
+		})
+		.max_depth(parse_optional_arg(clap_matches, "max-depth", "max depth")?)
+		.average_branches(clap_matches.is_present("average-branches"));
+	let options = match parse_optional_arg(clap_matches, "max-size", "max size")? {
+		Some(0) => options.max_size(None),
+		Some(size) => options.max_size(Some(size)),
+		None => options
+	};
+	let options = match parse_optional_arg(clap_matches, "thumbnail", "thumbnail size")? {
+		Some(size) => options.thumbnail(size),
+		None => options
+	};
+	// Padding is checked strictly, to warn of it before decoding again
+	let result = match codec::decode(source_data, &options.clone().strict(true)) {
+		Err(DecodeImageError::Decode(DecodeError::NonZeroPadding)) => {
+			let _ = writeln!(stderr, "warning: nonzero padding after QTI quadtree content");
+			codec::decode(source_data, &options)
+		},
+		result => result
+	};
+	let output = match result {
+		Ok(img) => img,
+		Err(DecodeImageError::Decode(DecodeError::UnsupportedVersion(v))) => return unsupported_version(v),
+		Err(DecodeImageError::Decode(_)) |
+		Err(DecodeImageError::Draw(DrawError::ColorOutOfRange { .. })) => return fail("Invalid image data", 4),
+		Err(DecodeImageError::Draw(_)) => return fail("Invalid output dimensions", 2),
+		Err(DecodeImageError::TooLarge) => return fail("Output image too large; see --max-size", 5)
+	};
+	let output_path = output_path(paths, "png");
+	// Standard output has no file extension to go by, so it gets PNG
+	let png = output_path.as_ref().is_none_or(|path| std::path::Path::new(path).extension()
+		.is_some_and(|ext| ext.eq_ignore_ascii_case("png")));
+	if let (false, Some(path)) = (png, &output_path) {
+		return match output.save(path) {
+			Ok(_) => Ok(()),
+			Err(_) => fail("Could not save output", 3)
+		};
+	}
+	let palette = qti::read_header(source_data).ok()
+		.filter(|header| !header.direct && header.palette_width() <= 8 && options.background.is_none())
+		.map(|header| header.palette_colors());
+	let mut data = Vec::new();
+	// Images in the colors of a small palette can be saved with it
+	let written = match palette.and_then(|palette| index_colors(&output, &palette).map(|indices| (palette, indices))) {
+		Some((palette, indices)) => write_indexed_png(&mut data, &output, &palette, &indices).is_ok(),
+		None => image::codecs::png::PngEncoder::new(&mut data)
+			.encode(&output, output.width(), output.height(), image::ColorType::Rgba8)
+			.is_ok()
+	};
+	if !written {
+		return fail("Could not save output", 3);
+	}
+	write_output(output_path.as_deref(), &data, stdout)
+}
+
+/// Helper function for `decode`; the index in `palette` of each pixel's
+/// color, if every pixel's color is in it.
+fn index_colors(img: &image::RgbaImage, palette: &[quantize::palette::Color]) -> Option<Vec<u8>> {
+	let mut indices = std::collections::HashMap::new();
+	for (ind, color) in palette.iter().enumerate().rev() {
+		indices.insert(*color, ind as u8);
+	}
+	img.pixels().map(|p| indices.get(p).copied()).collect()
+}
+
+/// Helper function for `decode`; writes an image as an indexed PNG with
+/// `palette` (of at most 256 colors), given the index of each pixel's color,
+/// at the fewest bits per pixel that fit the palette.
+fn write_indexed_png<W: Write>(
+	writer: W,
+	img: &image::RgbaImage,
+	palette: &[quantize::palette::Color],
+	indices: &[u8]
+) -> Result<(), png::EncodingError> {
+	let bits = match palette.len() {
+		0..=2 => 1,
+		3..=4 => 2,
+		5..=16 => 4,
+		_ => 8
+	};
+	let mut encoder = png::Encoder::new(writer, img.width(), img.height());
+	encoder.set_color(png::ColorType::Indexed);
+	encoder.set_depth(match bits {
+		1 => png::BitDepth::One,
+		2 => png::BitDepth::Two,
+		4 => png::BitDepth::Four,
+		_ => png::BitDepth::Eight
+	});
+	encoder.set_palette(palette.iter().flat_map(|c| c.0[..3].to_vec()).collect());
+	if palette.iter().any(|c| c.0[3] != 255) {
+		encoder.set_trns(palette.iter().map(|c| c.0[3]).collect());
+	}
+	// Rows are packed from the high bits, each starting on a new byte
+	let mut data = Vec::new();
+	for row in indices.chunks(img.width() as usize) {
+		for byte in row.chunks(8 / bits) {
+			data.push(byte.iter().enumerate().fold(0u8, |acc, (i, ind)| acc | ind << (8 - bits * (i + 1))));
+		}
+	}
+	encoder.write_header()?.write_image_data(&data)
+}
+
+/// The `info` subcommand of `run`.
+fn print_info(
+	clap_matches: &clap::ArgMatches,
+	input_path: &str,
+	source_data: &[u8],
+	stdout: &mut dyn Write
+) -> CliResult<()> {
+	if qti::is_qti(source_data) {
+		return print_qti_info(
+			source_data,
+			clap_matches.is_present("palette"),
+			clap_matches.is_present("deep"),
+			stdout
+		);
+	}
+	let source = load_image(source_data, input_path)?;
+	let report = quantize::suggest_color_count(&source);
+	let _ = writeln!(stdout, "Suggested palette size: {} colors (dedup threshold {})",
+		report.suggested, report.knee_threshold);
+	let _ = writeln!(stdout, "Colors by dedup threshold:");
+	for (thresh, clusters) in report.cluster_counts.iter() {
+		let _ = writeln!(stdout, "\t{}: {}", thresh, clusters);
+	}
+	let _ = writeln!(stdout, "Pixels covered by the most common colors:");
+	for (clusters, fraction) in report.coverage.iter() {
+		let _ = writeln!(stdout, "\t{}: {:.1}%", clusters, fraction * 100.);
+	}
+	Ok(())
+}
+
+/// Helper function for `print_info`; describes the header of a QTI file,
+/// and with `palette`, its palette colors. With `deep`, the tree is also
+/// decoded and described.
+fn print_qti_info(source: &[u8], palette: bool, deep: bool, stdout: &mut dyn Write) -> CliResult<()> {
+	let header = qti::read_header(source).or_else(invalid_qti)?;
+	let _ = writeln!(stdout, "QTI version {}", header.version);
+	let _ = writeln!(stdout, "File size: {} bytes", source.len());
+	if header.direct {
+		let _ = writeln!(stdout, "Palette: none, 32-bit RGBA per node");
+	} else {
+		let _ = writeln!(stdout, "Palette: {} colors, {} bits per node", header.palette.len(), header.palette_width());
+	}
+	if palette {
+		for (ind, c) in header.palette.iter().enumerate() {
+			let _ = writeln!(stdout, "\t{}: #{:02X}{:02X}{:02X}{:02X}", ind, c[0], c[1], c[2], c[3]);
+		}
+	}
+	if let Some((width, height)) = header.dimensions {
+		let _ = writeln!(stdout, "Dimensions: {}x{}", width, height);
+	}
+	let _ = writeln!(stdout, "Quadrant offsets: {}",
+		if header.quadrant_offsets.is_some() { "yes" } else { "no" });
+	let _ = writeln!(stdout, "Gradients: {}", if header.flat { "no" } else { "yes" });
+	match header.thumbnail {
+		Some(ref t) => {
+			let _ = writeln!(stdout, "Thumbnail: {}x{} ({} bytes)", t.width(), t.height(), t.len());
+		},
+		None => {
+			let _ = writeln!(stdout, "Thumbnail: none");
+		}
+	}
+	if !deep {
+		return Ok(());
+	}
+	let tree: QuadtreeNode<Box<dyn Palette>> = QuadtreeNode::read_qti(&mut &source[..]).or_else(invalid_qti)?.0;
+	let _ = writeln!(stdout, "Nodes: {} ({} leaves), depth {}", tree.node_count(), tree.leaf_count(), tree.depth());
+	let _ = writeln!(stdout, "Nodes by depth:");
+	for (depth, count) in tree.nodes_by_depth().iter().enumerate() {
+		let _ = writeln!(stdout, "\t{}: {}", depth, count);
+	}
+	let _ = writeln!(stdout, "Colors used: {}", tree.colors_used().len());
+	// Each node is a bit saying whether it has subsections, and a color
+	let redundant = tree.redundant_node_count();
+	let _ = writeln!(stdout, "Redundant nodes: {} (about {} bits)",
+		redundant, redundant as u64 * (1 + header.palette_width() as u64));
+	Ok(())
+}
+
+/// Helper function for `print_qti_info`; the failure for a QTI file that
+/// couldn't be read, naming what was wrong with it.
+fn invalid_qti<T>(e: DecodeError) -> CliResult<T> {
+	match e {
+		DecodeError::UnsupportedVersion(v) => unsupported_version(v),
+		e => fail(&format!("Invalid image data: {:?}", e), 4)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::test_image;
+
+	/// Runs the CLI with `args` (after the program name), giving its status
+	/// and what it wrote to standard output and standard error.
+	fn run_args<S: AsRef<std::ffi::OsStr>>(args: &[S]) -> (i32, Vec<u8>, String) {
+		run_piped(args, &[])
+	}
+
+	/// Like `run_args`, with `stdin` as standard input.
+	fn run_piped<S: AsRef<std::ffi::OsStr>>(args: &[S], mut stdin: &[u8]) -> (i32, Vec<u8>, String) {
+		let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+		let args = std::iter::once(OsString::from("quadtree_img")).chain(args.iter().map(|a| a.as_ref().to_owned()));
+		let code = run_with(args, &mut stdin, &mut stdout, &mut stderr);
+		(code, stdout, String::from_utf8(stderr).unwrap())
+	}
+
+	/// An empty directory for the files of the test `name`.
+	fn temp_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("quadtree_img_{}_{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	#[test]
+	fn converts_both_ways() {
+		let dir = temp_dir("converts_both_ways");
+		let (png, qti) = (dir.join("image.png"), dir.join("image.qti"));
+		test_image(32, 12).save(&png).unwrap();
+		// Without a subcommand or output, the output is named after the input
+		let (code, _, stderr) = run_args(&[&png]);
+		assert_eq!(code, 0, "{}", stderr);
+		assert!(qti::is_qti(&std::fs::read(&qti).unwrap()));
+		std::fs::remove_file(&png).unwrap();
+		assert_eq!(run_args(&[&qti]).0, 0);
+		let decoded = image::open(&png).unwrap().into_rgba8();
+		assert_eq!(decoded.dimensions(), (32, 32));
+
+		let out = dir.join("out.png");
+		assert_eq!(run_args(&[OsString::from("decode"), qti.into(), out.clone().into()]).0, 0);
+		assert_eq!(image::open(&out).unwrap().into_rgba8(), decoded);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn exit_codes() {
+		let dir = temp_dir("exit_codes");
+		let path = |name: &str| dir.join(name).into_os_string();
+		test_image(16, 13).save(dir.join("image.png")).unwrap();
+		std::fs::write(dir.join("garbage.png"), b"abc").unwrap();
+		let data = codec::encode(&test_image(16, 13), &Default::default()).unwrap();
+		std::fs::write(dir.join("truncated.qti"), &data[..data.len() / 2]).unwrap();
+		let mut future = data.clone();
+		future[6] = 255;
+		std::fs::write(dir.join("future.qti"), &future).unwrap();
+		let unsupported = OsString::from(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fake_piet.qti"));
+
+		assert_eq!(run_args::<&str>(&[]).0, 1);
+		let (code, stdout, _) = run_args(&["--help"]);
+		assert_eq!(code, 0);
+		assert!(!stdout.is_empty());
+		assert_eq!(run_args(&["encode".into(), "--bogus".into(), path("image.png")]).0, 1);
+		let (code, _, stderr) = run_args(&["encode".into(), "--dedup=abc".into(), path("image.png"), path("out.qti")]);
+		assert_eq!((code, stderr.as_str()), (2, "Non-numeric value for dedup\n"));
+		let (code, _, stderr) = run_args(&["encode".into(), path("missing.png"), path("out.qti")]);
+		assert_eq!((code, stderr.as_str()), (3, "File not found or could not be read\n"));
+		let (code, _, stderr) = run_args(&["encode".into(), path("garbage.png"), path("out.qti")]);
+		assert_eq!((code, stderr.as_str()), (4, "Invalid image data\n"));
+		let (code, _, stderr) = run_args(&["decode".into(), path("truncated.qti"), path("out.png")]);
+		assert_eq!((code, stderr.as_str()), (4, "Invalid image data\n"));
+		let (code, _, stderr) = run_args(&["decode".into(), unsupported, path("out.png")]);
+		assert_eq!((code, stderr.as_str()), (6, "QTI version 2 is not supported by this build\n"));
+		let (code, _, stderr) = run_args(&["decode".into(), path("future.qti"), path("out.png")]);
+		assert_eq!((code, stderr.as_str()), (6, "QTI version 255 is not supported by this build\n"));
+		assert!(!dir.join("out.qti").exists() && !dir.join("out.png").exists());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn median_cut_palettes() {
+		use std::ffi::OsStr;
+		let dir = temp_dir("median_cut_palettes");
+		let (png, qti) = (dir.join("image.png"), dir.join("image.qti"));
+		test_image(32, 14).save(&png).unwrap();
+		let args = |algo: &'static str| [
+			OsStr::new("encode"), OsStr::new(algo), OsStr::new("--colors=8"), png.as_os_str(), qti.as_os_str()
+		];
+		let (code, _, stderr) = run_args(&args("--palette-algo=median-cut"));
+		assert_eq!(code, 0, "{}", stderr);
+		assert!(qti::read_header(&std::fs::read(&qti).unwrap()).unwrap().palette.len() <= 8);
+		let (code, _, stderr) = run_args(&args("--palette-algo=octree"));
+		assert_eq!((code, stderr.as_str()), (2, "Invalid value for palette algorithm\n"));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn byte_limits_are_respected() {
+		let dir = temp_dir("byte_limits_are_respected");
+		let (png, qti) = (dir.join("image.png"), dir.join("image.qti"));
+		test_image(64, 16).save(&png).unwrap();
+		let args = |max_bytes: &str| [
+			OsString::from("encode"), format!("--max-bytes={}", max_bytes).into(), png.clone().into(), qti.clone().into()
+		];
+		let (code, _, stderr) = run_args(&args("800"));
+		assert_eq!(code, 0, "{}", stderr);
+		assert!(std::fs::metadata(&qti).unwrap().len() <= 800);
+		std::fs::remove_file(&qti).unwrap();
+		let (code, _, stderr) = run_args(&args("16"));
+		assert_eq!((code, stderr.as_str()), (2, "Output cannot be made small enough for max bytes\n"));
+		assert!(!qti.exists());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn subcommands_take_their_settings() {
+		let dir = temp_dir("subcommands_take_their_settings");
+		let (png, qti, out) = (dir.join("image.png"), dir.join("image.qti"), dir.join("out.png"));
+		let img = test_image(64, 31);
+		img.save(&png).unwrap();
+		let args = |args: &[&str], paths: &[&PathBuf]| args.iter().map(OsString::from)
+			.chain(paths.iter().map(|p| p.as_os_str().to_owned()))
+			.collect::<Vec<_>>();
+
+		let settings = ["encode", "-d", "64", "-s", "15", "-t", "1", "-b", "0", "--no-gradient", "--quiet"];
+		let (code, stdout, stderr) = run_args(&args(&settings, &[&png, &qti]));
+		assert_eq!((code, stdout.is_empty(), stderr.as_str()), (0, true, ""));
+		let options = codec::EncodeImageOptions::default().dedup(64).sensitivity(15. / 16.).trim(1).blur(0.).gradient(None);
+		let data = std::fs::read(&qti).unwrap();
+		assert_eq!(data, codec::encode(&img, &options).unwrap());
+
+		assert_eq!(run_args(&args(&["decode", "--width", "16"], &[&qti, &out])).0, 0);
+		let expected = codec::decode(&data, &codec::DecodeImageOptions::default().width(Some(16))).unwrap();
+		assert_eq!(image::open(&out).unwrap().into_rgba8(), expected);
+
+		let (code, stdout, _) = run_args(&args(&["info", "--deep"], &[&qti]));
+		assert_eq!(code, 0);
+		let stdout = String::from_utf8(stdout).unwrap();
+		let header = qti::read_header(&data).unwrap();
+		let (tree, _) = QuadtreeNode::<quantize::palette::DynamicPaletteView>::from_qti(&data).unwrap();
+		for line in [
+			format!("QTI version {}\n", header.version),
+			format!("File size: {} bytes\n", data.len()),
+			format!("Palette: {} colors, {} bits per node\n", header.palette.len(), header.palette_width()),
+			"Gradients: no\n".to_string(),
+			format!("Nodes: {} ({} leaves), depth {}\n", tree.node_count(), tree.leaf_count(), tree.depth()),
+		] {
+			assert!(stdout.contains(&line), "{:?} not in {:?}", line, stdout);
+		}
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn pipes_through_standard_input_and_output() {
+		let img = test_image(32, 32);
+		let mut png = Vec::new();
+		image::DynamicImage::ImageRgba8(img.clone()).write_to(&mut png, image::ImageOutputFormat::Png).unwrap();
+		let expected = codec::decode(&codec::encode(&img, &Default::default()).unwrap(), &Default::default()).unwrap();
+		// With and without a subcommand, and with the output given as `-` or
+		// left to default to standard output
+		for (encode_args, decode_args) in [(&["encode", "-", "-"][..], &["decode", "-", "-"][..]), (&["-"], &["-"])] {
+			let (code, qti_data, stderr) = run_piped(encode_args, &png);
+			assert_eq!(code, 0, "{}", stderr);
+			assert_eq!(qti_data, codec::encode(&img, &Default::default()).unwrap());
+			let (code, png_data, stderr) = run_piped(decode_args, &qti_data);
+			assert_eq!(code, 0, "{}", stderr);
+			let decoded = image::load_from_memory_with_format(&png_data, image::ImageFormat::Png).unwrap();
+			assert_eq!(decoded.into_rgba8(), expected);
+		}
+		assert!(!Path::new("-.qti").exists() && !Path::new("-.png").exists());
+	}
+
+	#[test]
+	fn batches_go_on_past_failures() {
+		let dir = temp_dir("batches_go_on_past_failures");
+		let input = dir.join("in");
+		std::fs::create_dir_all(input.join("sub")).unwrap();
+		for (name, seed) in [("a.png", 33), ("b.png", 34), ("sub/c.png", 35)] {
+			test_image(16, seed).save(input.join(name)).unwrap();
+		}
+		std::fs::write(input.join("bad.png"), b"abc").unwrap();
+		std::fs::write(input.join("notes.txt"), b"not an image").unwrap();
+		let size = |path: PathBuf| std::fs::metadata(path).unwrap().len();
+
+		// The directory's images, but not those in its subdirectory
+		let out = dir.join("out");
+		let (code, _, stderr) = run_args(&[
+			OsString::from("encode"), "--jobs=2".into(), "--out-dir".into(), out.clone().into(), input.clone().into()
+		]);
+		assert_eq!(code, 4);
+		assert!(stderr.contains(&format!("{}: Invalid image data\n", input.join("bad.png").display())), "{}", stderr);
+		let bytes_in = size(input.join("a.png")) + size(input.join("b.png"));
+		let bytes_out = size(out.join("a.qti")) + size(out.join("b.qti"));
+		assert!(stderr.ends_with(&format!("2 succeeded, 1 failed; {} bytes in, {} bytes out\n", bytes_in, bytes_out)), "{}", stderr);
+		assert!(!out.join("bad.qti").exists() && !out.join("notes.qti").exists() && !out.join("sub").exists());
+
+		// and with `--recursive`, those too, in the same layout
+		let (code, _, _) = run_args(&[
+			OsString::from("encode"), "-r".into(), "--out-dir".into(), out.clone().into(), input.clone().into()
+		]);
+		assert_eq!(code, 4);
+		assert!(qti::is_qti(&std::fs::read(out.join("sub/c.qti")).unwrap()));
+
+		// More than two paths convert alongside themselves, and with no
+		// failures succeed
+		let qti_paths = ["a.qti", "b.qti", "sub/c.qti"].iter().map(|name| out.join(name)).collect::<Vec<_>>();
+		let mut args = vec![OsString::from("decode")];
+		args.extend(qti_paths.iter().map(|p| p.clone().into_os_string()));
+		let (code, _, stderr) = run_args(&args);
+		assert_eq!(code, 0, "{}", stderr);
+		assert!(stderr.starts_with("3 succeeded, 0 failed; "), "{}", stderr);
+		for path in qti_paths.iter() {
+			let expected = codec::decode(&std::fs::read(path).unwrap(), &Default::default()).unwrap();
+			assert_eq!(image::open(path.with_extension("png")).unwrap().into_rgba8(), expected);
+		}
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	/// The header of the PNG data `data`.
+	fn png_info(data: &[u8]) -> png::Info {
+		png::Decoder::new(data).read_info().unwrap().1.info().clone()
+	}
+
+	#[test]
+	fn small_palettes_decode_to_indexed_pngs() {
+		let dir = temp_dir("small_palettes_decode_to_indexed_pngs");
+		let (flat, wide, out) = (dir.join("flat.qti"), dir.join("wide.qti"), dir.join("out.png"));
+		// Four colors, one of them translucent, in four quadrants
+		let colors = [[200, 30, 30, 255], [30, 200, 30, 255], [30, 30, 200, 128], [240, 240, 240, 255]];
+		let img = image::RgbaImage::from_fn(64, 64, |x, y| image::Rgba(colors[(x / 32 + y / 32 * 2) as usize]));
+		let data = codec::encode(&img, &codec::EncodeImageOptions::default().blur(0.).gradient(None)).unwrap();
+		std::fs::write(&flat, &data).unwrap();
+		let decode = |input: &PathBuf, settings: &[&str]| {
+			let mut args = vec![OsString::from("decode")];
+			args.extend(settings.iter().map(OsString::from));
+			args.extend([input.clone().into_os_string(), out.clone().into_os_string()]);
+			let (code, _, stderr) = run_args(&args);
+			assert_eq!(code, 0, "{}", stderr);
+			let data = std::fs::read(&out).unwrap();
+			(png_info(&data), image::load_from_memory(&data).unwrap().into_rgba8())
+		};
+
+		// Flat files are drawn in their palette's colors alone, so they are
+		// saved with it, at the fewest bits that fit it
+		let (info, decoded) = decode(&flat, &[]);
+		assert_eq!(decoded, img);
+		let header = qti::read_header(&data).unwrap();
+		assert_eq!(header.palette_width(), 2);
+		assert_eq!((info.color_type, info.bit_depth), (png::ColorType::Indexed, png::BitDepth::Two));
+		let palette = header.palette_colors();
+		assert_eq!(info.palette.unwrap(), palette.iter().flat_map(|c| c.0[..3].to_vec()).collect::<Vec<_>>());
+		assert_eq!(info.trns.unwrap(), palette.iter().map(|c| c.0[3]).collect::<Vec<_>>());
+
+		// A background blends the translucent color with it
+		let (info, decoded) = decode(&flat, &["--background", "000000"]);
+		assert_eq!((info.color_type, info.bit_depth), (png::ColorType::RGBA, png::BitDepth::Eight));
+		assert_eq!(decoded.get_pixel(40, 40).0[3], 255);
+
+		// and a palette wider than 8 bits can't be a PNG's
+		let wide_palette = quantize::palette::DynamicPaletteView::with_width(colors.iter().map(|c| image::Rgba(*c)).collect(), 9);
+		let quadrants = img.enumerate_pixels().map(|(x, y, _)| x / 32 + y / 32 * 2).collect::<Vec<_>>();
+		let mut tree = QuadtreeNode::default();
+		tree.mount_with(&quadrants, &wide_palette, &Default::default()).unwrap();
+		let qti_options = qti::EncodeOptions { flat: true, ..Default::default() };
+		std::fs::write(&wide, tree.to_qti_with(&wide_palette, &qti_options).unwrap()).unwrap();
+		let (info, decoded) = decode(&wide, &["--width", "64"]);
+		assert_eq!((info.color_type, decoded), (png::ColorType::RGBA, img));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn thumbnails_descend_log2_n_levels() {
+		let dir = temp_dir("thumbnails_descend_log2_n_levels");
+		let (qti, out) = (dir.join("image.qti"), dir.join("out.png"));
+		let data = codec::encode(&test_image(64, 36), &Default::default()).unwrap();
+		std::fs::write(&qti, &data).unwrap();
+		let (tree, palette) = QuadtreeNode::<quantize::palette::DynamicPaletteView>::from_qti(&data).unwrap();
+		assert!(tree.depth() > 4);
+		let decode = |thumbnail: &str, settings: &[&str]| {
+			let mut args = vec![OsString::from("decode"), format!("--thumbnail={}", thumbnail).into()];
+			args.extend(settings.iter().map(OsString::from));
+			args.extend([qti.clone().into_os_string(), out.clone().into_os_string()]);
+			run_args(&args)
+		};
+
+		for size in [1, 8, 16] {
+			let (code, _, stderr) = decode(&size.to_string(), &[]);
+			assert_eq!(code, 0, "{}", stderr);
+			let mut expected = image::RgbaImage::new(size, size);
+			tree.to_image_with(&mut expected, &palette, &image_render::RenderOptions {
+				gradient: true,
+				max_depth: Some(size.trailing_zeros() as usize),
+				..Default::default()
+			}).unwrap();
+			assert!(image::open(&out).unwrap().into_rgba8() == expected, "size {}", size);
+		}
+		std::fs::remove_file(&out).unwrap();
+		let (code, _, stderr) = decode("24", &[]);
+		assert_eq!((code, stderr.as_str()), (2, "Invalid output dimensions\n"));
+		assert_eq!(decode("16", &["--width", "16"]).0, 1);
+		assert_eq!(decode("16", &["--max-depth", "4"]).0, 1);
+		assert!(!out.exists());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn info_reports_thumbnails() {
+		let dir = temp_dir("info_reports_thumbnails");
+		let (png, qti) = (dir.join("image.png"), dir.join("image.qti"));
+		test_image(64, 15).save(&png).unwrap();
+		for (flag, line) in [("--thumbnail", "Thumbnail: 32x32 (4096 bytes)\n"), ("--quiet", "Thumbnail: none\n")] {
+			assert_eq!(run_args(&[OsString::from("encode"), flag.into(), png.clone().into(), qti.clone().into()]).0, 0);
+			let (code, stdout, _) = run_args(&[OsString::from("info"), qti.clone().into()]);
+			assert_eq!(code, 0);
+			assert!(String::from_utf8(stdout).unwrap().contains(line));
+		}
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,561 @@
+use crate::QuadtreeNode;
+use crate::error::*;
+use crate::image::{AnalyzeOptions, RenderOptions, UpscaleMode};
+use crate::qti;
+use crate::quantize::{self, DistanceMetric, PaletteOptions, RefineOptions};
+use crate::quantize::palette::{Color, DynamicPalette, DynamicPaletteView, IdentityPalette, Palette};
+use crate::GradientFit;
+
+/// Settings for `encode`; the defaults are those of the CLI.
+///
+/// Each field has a method of the same name that sets it, for building
+/// options up in a chain:
+/// `EncodeImageOptions::default().blur(0.).sensitivity(0.9)`.
+#[derive(Clone, Debug)]
+pub struct EncodeImageOptions {
+	/// Color distance threshold for palette deduplication (see
+	/// `generate_palette`); defaults to 256.
+	pub dedup: u32,
+	/// Factor to scale the image down by before generating a dedup palette
+	/// (see `PaletteOptions::downsample`); defaults to 1.
+	pub palette_downsample: u32,
+	/// If given, the palette is generated with median cut, with at most this
+	/// many colors, rather than by deduplication; defaults to `None`.
+	pub max_colors: Option<usize>,
+	/// How much to refine the palette with k-means; defaults to no rounds.
+	pub kmeans: RefineOptions,
+	/// How color differences are measured; defaults to `Euclidean`.
+	pub metric: DistanceMetric,
+	/// Amount of Gaussian blur to apply before analysis; defaults to 1.
+	pub blur: f32,
+	/// See `AnalyzeOptions::linear_blur`; defaults to false.
+	pub linear_blur: bool,
+	/// See `AnalyzeOptions::transparent_below`; with a generated palette,
+	/// the pixels it covers are left out of palette generation, and
+	/// transparent black is added as the first color. Defaults to `None`.
+	pub transparent_below: Option<u8>,
+	/// How much of a square, from 0 to 1, must be the same color for it not
+	/// to be split (see `mount`); defaults to 63/64.
+	pub sensitivity: f32,
+	/// How to fit gradients, or `None` for a flat image; defaults to
+	/// `Some(GradientFit::Corners)`.
+	pub gradient: Option<GradientFit>,
+	/// See `AnalyzeOptions::snap`; defaults to `None`.
+	pub snap: Option<u32>,
+	/// See `AnalyzeOptions::max_depth`; bounds the size of the tree, and
+	/// so of the output, whatever the image. Defaults to `None`.
+	pub max_depth: Option<u8>,
+	/// Number of times to `trim` the tree; defaults to 0.
+	pub trim: usize,
+	/// Depth below which each `trim` removes leaves; defaults to 6.
+	pub trim_depth: isize,
+	/// If given, the tree is also trimmed with `trim_by_error`; defaults to
+	/// `None`.
+	pub trim_error: Option<u32>,
+	/// If given, the tree is trimmed with `trim_to_node_count` until the
+	/// output is at most this many bytes; defaults to `None`.
+	pub max_bytes: Option<usize>,
+	/// See `qti::EncodeOptions::compact_palette`; defaults to false.
+	pub compact_palette: bool,
+	/// See `qti::EncodeOptions::embed_thumbnail`; defaults to false.
+	pub thumbnail: bool,
+}
+
+impl Default for EncodeImageOptions {
+	fn default() -> Self {
+		EncodeImageOptions {
+			dedup: 256,
+			palette_downsample: 1,
+			max_colors: None,
+			kmeans: RefineOptions { max_iterations: 0, convergence: 0. },
+			metric: DistanceMetric::Euclidean,
+			blur: 1.,
+			linear_blur: false,
+			transparent_below: None,
+			sensitivity: 63. / 64.,
+			gradient: Some(GradientFit::Corners),
+			snap: None,
+			max_depth: None,
+			trim: 0,
+			trim_depth: 6,
+			trim_error: None,
+			max_bytes: None,
+			compact_palette: false,
+			thumbnail: false,
+		}
+	}
+}
+
+impl EncodeImageOptions {
+	option_setters! {
+		dedup: u32,
+		palette_downsample: u32,
+		max_colors: Option<usize>,
+		kmeans: RefineOptions,
+		metric: DistanceMetric,
+		blur: f32,
+		linear_blur: bool,
+		transparent_below: Option<u8>,
+		sensitivity: f32,
+		gradient: Option<GradientFit>,
+		snap: Option<u32>,
+		max_depth: Option<u8>,
+		trim: usize,
+		trim_depth: isize,
+		trim_error: Option<u32>,
+		max_bytes: Option<usize>,
+		compact_palette: bool,
+		thumbnail: bool,
+	}
+
+	/// Limits the palette to `2^width` colors, generated with median cut.
+	pub fn palette_width(self, width: u8) -> Self {
+		self.max_colors(Some(1usize.checked_shl(width as u32).unwrap_or(usize::MAX)))
+	}
+}
+
+/// Settings for `decode`; the defaults are those of the CLI.
+///
+/// As with `EncodeImageOptions`, each field has a setter of the same name.
+#[derive(Clone, Debug)]
+pub struct DecodeImageOptions {
+	/// Output image width (and height), which must be a power of two;
+	/// defaults to the dimensions stored in the file, if any, or else the
+	/// tree's `natural_size`.
+	pub width: Option<u32>,
+	/// Whether to render with gradients; defaults to whether the file was
+	/// encoded with them.
+	pub gradient: Option<bool>,
+	/// See `RenderOptions::background`.
+	pub background: Option<Color>,
+	/// See `RenderOptions::upscale`.
+	pub upscale: UpscaleMode,
+	/// See `RenderOptions::max_depth`.
+	pub max_depth: Option<usize>,
+	/// See `RenderOptions::average_branches`.
+	pub average_branches: bool,
+	/// See `qti::DecodeOptions::strict`; with it, files with nonzero padding
+	/// give `DecodeImageError::Decode(NonZeroPadding)`. Defaults to false.
+	pub strict: bool,
+	/// The largest width (and height) to draw at, or `None` for no limit;
+	/// images that would be larger give `DecodeImageError::TooLarge` rather
+	/// than being allocated. Files need not declare their dimensions, and
+	/// a deep tree's `natural_size` may be up to 2^31. Defaults to
+	/// `DEFAULT_MAX_SIZE`.
+	pub max_size: Option<u32>,
+}
+
+/// Default `DecodeImageOptions::max_size`: an image of 1 GiB.
+pub const DEFAULT_MAX_SIZE: u32 = 1 << 14;
+
+impl Default for DecodeImageOptions {
+	fn default() -> Self {
+		DecodeImageOptions {
+			width: None,
+			gradient: None,
+			background: None,
+			upscale: Default::default(),
+			max_depth: None,
+			average_branches: false,
+			strict: false,
+			max_size: Some(DEFAULT_MAX_SIZE),
+		}
+	}
+}
+
+impl DecodeImageOptions {
+	option_setters! {
+		width: Option<u32>,
+		gradient: Option<bool>,
+		background: Option<Color>,
+		upscale: UpscaleMode,
+		max_depth: Option<usize>,
+		average_branches: bool,
+		strict: bool,
+		max_size: Option<u32>,
+	}
+
+	/// Draws a `size` by `size` preview (`size` being a power of two),
+	/// descending only the `log2(size)` levels of the tree that have a
+	/// pixel each, and filling each square there with its node's color.
+	/// The whole tree is still read, but drawing it takes no longer than
+	/// the preview has pixels.
+	pub fn thumbnail(self, size: u32) -> Self {
+		self.width(Some(size)).max_depth(Some(size.trailing_zeros() as usize))
+	}
+}
+
+/// An image encoded by `encode_to_parts` or `encode_with_palette`: the QTI
+/// data, and the tree and palette it holds.
+#[derive(Debug)]
+pub struct Encoded<P: Palette + Default = DynamicPaletteView> {
+	pub data: Vec<u8>,
+	pub tree: QuadtreeNode<P>,
+	pub palette: P,
+}
+
+/// Encodes an image as QTI data, generating a palette for it.
+///
+/// The image must be square, with a side length that is a power of two.
+///
+/// With the default options, smooth images with a little noise (such as
+/// photos) come back from `decode` at a PSNR (see `metrics::psnr`) of over
+/// 30 dB; images with more detail than the palette or the sensitivity keep
+/// come back worse.
+pub fn encode(img: &image::RgbaImage, options: &EncodeImageOptions) -> Result<Vec<u8>, EncodeImageError> {
+	encode_to_parts(img, options).map(|encoded| encoded.data)
+}
+
+/// Like `encode`, but also gives the tree and palette that were encoded.
+pub fn encode_to_parts(img: &image::RgbaImage, options: &EncodeImageOptions) -> Result<Encoded, EncodeImageError> {
+	let cleared = options.transparent_below.map(|alpha| quantize::clear_transparent(img, alpha));
+	let palette_source = cleared.as_ref().unwrap_or(img);
+	let palette: DynamicPaletteView = match options.max_colors {
+		// Leaving room for the transparent entry
+		Some(colors) if options.transparent_below.is_some() =>
+			quantize::generate_palette_median_cut(palette_source, std::cmp::max(colors, 2) - 1),
+		Some(colors) => quantize::generate_palette_median_cut(palette_source, colors),
+		None => quantize::generate_palette_with(palette_source, &PaletteOptions {
+			dedup_thresh: options.dedup,
+			downsample: options.palette_downsample,
+			metric: options.metric
+		})
+	};
+	let (palette, _) = quantize::refine_palette_kmeans_with(palette_source, palette, &options.kmeans);
+	let palette = match options.transparent_below {
+		Some(alpha) => quantize::with_transparent_entry(quantize::palette_colors(&palette), alpha).into(),
+		None => palette
+	};
+	encode_with_palette(img, palette, options)
+}
+
+/// Like `encode_to_parts`, but with a given palette rather than a generated
+/// one, so the palette settings in `options` are ignored.
+///
+/// With `IdentityPalette`, every color is stored exactly; for the image to
+/// be too, `options` should have no blur, a sensitivity of 1, no gradients
+/// and no trimming.
+pub fn encode_with_palette<P: Palette + Default>(
+	img: &image::RgbaImage,
+	palette: P,
+	options: &EncodeImageOptions
+) -> Result<Encoded<P>, EncodeImageError> {
+	let mut tree: QuadtreeNode<_> = Default::default();
+	let analyze_options = AnalyzeOptions::default()
+		.sensitivity((options.sensitivity.clamp(0., 1.) * 16384.) as usize)
+		.blur(options.blur)
+		.linear_blur(options.linear_blur)
+		.transparent_below(options.transparent_below)
+		.gradient_fit(options.gradient)
+		.metric(options.metric)
+		.snap(options.snap)
+		.max_depth(options.max_depth);
+	tree.from_image_with(img, &palette, &analyze_options).map_err(EncodeImageError::Analyze)?;
+	for _ in 0..options.trim {
+		tree.trim(options.trim_depth);
+	}
+	if let Some(max_error) = options.trim_error {
+		tree.trim_by_error(&palette, max_error);
+	}
+	let qti_options = qti::EncodeOptions {
+		dimensions: Some(img.dimensions()),
+		embed_thumbnail: options.thumbnail,
+		compact_palette: options.compact_palette,
+		flat: options.gradient.is_none(),
+		..Default::default()
+	};
+	if let Some(max_bytes) = options.max_bytes {
+		// Each node takes a bit plus the palette width; the header and
+		// palette don't shrink, so trim by the excess until it fits
+		let node_bits = palette.width() as usize + 1;
+		loop {
+			let size = tree.encoded_size_estimate_with(&palette, &qti_options);
+			if size <= max_bytes {
+				break;
+			}
+			let count = tree.node_count();
+			let excess = (size - max_bytes) * 8;
+			tree.trim_to_node_count(count.saturating_sub(excess.div_ceil(node_bits)), &palette);
+			if tree.node_count() == count {
+				return Err(EncodeImageError::TooLarge);
+			}
+		}
+	}
+	let data = tree.to_qti_with(&palette, &qti_options).map_err(EncodeImageError::Encode)?;
+	Ok(Encoded { data, tree, palette })
+}
+
+/// Decodes QTI data into an image.
+pub fn decode(data: &[u8], options: &DecodeImageOptions) -> Result<image::RgbaImage, DecodeImageError> {
+	let header = qti::read_header(data).ok();
+	// Files without a palette need a direct one to decode
+	if header.as_ref().is_some_and(|h| h.direct) {
+		decode_with::<IdentityPalette>(data, header, options)
+	} else {
+		decode_with::<DynamicPaletteView>(data, header, options)
+	}
+}
+
+/// Helper for `decode`, with the palette type to decode with.
+fn decode_with<P: DynamicPalette + Default + std::fmt::Debug>(
+	data: &[u8],
+	header: Option<qti::Header>,
+	options: &DecodeImageOptions
+) -> Result<image::RgbaImage, DecodeImageError> {
+	let qti_options = qti::DecodeOptions { strict: options.strict, ..Default::default() };
+	let (tree, palette): (QuadtreeNode<_>, P) =
+		QuadtreeNode::from_qti_with(data, &qti_options).map_err(DecodeImageError::Decode)?;
+	render(&tree, &palette, header.as_ref(), options)
+}
+
+/// Helper for `decode` and `QtiDecoder`; draws a decoded tree as set in
+/// `options`, with the settings in its file's `header` (if that could be
+/// read) as defaults.
+fn render<P: Palette + Default>(
+	tree: &QuadtreeNode<P>,
+	palette: &P,
+	header: Option<&qti::Header>,
+	options: &DecodeImageOptions
+) -> Result<image::RgbaImage, DecodeImageError> {
+	let width = output_width(tree, header, options)?;
+	let mut output = image::RgbaImage::new(width, width);
+	tree.to_image_with(&mut output, palette, &RenderOptions {
+		// Files are rendered in the mode they were encoded in by default
+		gradient: options.gradient.unwrap_or_else(|| header.is_none_or(|header| !header.flat)),
+		background: options.background,
+		upscale: options.upscale,
+		max_depth: options.max_depth,
+		average_branches: options.average_branches
+	}).map_err(DecodeImageError::Draw)?;
+	Ok(output)
+}
+
+/// Helper for `render`; the width (and height) to draw a tree at, or
+/// `TooLarge` if that is over `options.max_size`.
+fn output_width<P: Palette + Default>(
+	tree: &QuadtreeNode<P>,
+	header: Option<&qti::Header>,
+	options: &DecodeImageOptions
+) -> Result<u32, DecodeImageError> {
+	// A tree of a uniform image is a single leaf, but should still come out at
+	// the size it went in at
+	let width = options.width.unwrap_or_else(|| header
+		.and_then(|h| h.dimensions)
+		.map(|(w, _)| w)
+		.filter(|w| w.is_power_of_two())
+		.unwrap_or_else(|| tree.natural_size()));
+	if options.max_size.is_some_and(|max_size| width > max_size) {
+		return Err(DecodeImageError::TooLarge);
+	}
+	Ok(width)
+}
+
+/// QTI data read into a tree, for rendering through `image::ImageDecoder`:
+/// `image::DynamicImage::from_decoder(QtiDecoder::new(reader)?)` reads it
+/// as an RGBA image, like the other formats that `image` reads.
+///
+/// `image` has no way to add formats to those that `image::open` and
+/// `image::load_from_memory` recognize, so QTI data has to be read through
+/// this directly.
+#[derive(Debug)]
+pub struct QtiDecoder {
+	tree: QuadtreeNode<Box<dyn Palette>>,
+	palette: Box<dyn Palette>,
+	header: qti::Header,
+	options: DecodeImageOptions,
+}
+
+impl QtiDecoder {
+	/// Reads QTI data from `reader`, with the defaults of `decode`.
+	pub fn new<R: std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Self::with_options(reader, Default::default())
+	}
+
+	/// Reads QTI data from `reader`, to be rendered as set in `options`;
+	/// `DecodeImageOptions::width` sets the size of files without stored
+	/// dimensions (or overrides those).
+	pub fn with_options<R: std::io::Read>(reader: &mut R, options: DecodeImageOptions) -> Result<Self, DecodeError> {
+		let (tree, palette, header) = QuadtreeNode::read_qti_with_header(reader, &Default::default())?;
+		Ok(QtiDecoder { tree, palette, header, options })
+	}
+
+	/// The header of the QTI data.
+	pub fn header(&self) -> &qti::Header {
+		&self.header
+	}
+
+	/// Helper for the `image::ImageDecoder` methods.
+	fn render(&self) -> image::ImageResult<image::RgbaImage> {
+		render(&self.tree, &self.palette, Some(&self.header), &self.options).map_err(|e| {
+			image::ImageError::Decoding(image::error::DecodingError::new(
+				image::error::ImageFormatHint::Name("QTI".to_string()),
+				format!("{:?}", e)
+			))
+		})
+	}
+}
+
+impl<'a> image::ImageDecoder<'a> for QtiDecoder {
+	type Reader = std::io::Cursor<Vec<u8>>;
+
+	/// Images over `DecodeImageOptions::max_size` are 0 by 0, so that
+	/// nothing is allocated for them before reading them gives the error.
+	fn dimensions(&self) -> (u32, u32) {
+		let width = output_width(&self.tree, Some(&self.header), &self.options).unwrap_or(0);
+		(width, width)
+	}
+
+	fn color_type(&self) -> image::ColorType {
+		image::ColorType::Rgba8
+	}
+
+	fn into_reader(self) -> image::ImageResult<Self::Reader> {
+		Ok(std::io::Cursor::new(self.render()?.into_raw()))
+	}
+
+	fn read_image(self, buf: &mut [u8]) -> image::ImageResult<()> {
+		buf.copy_from_slice(&self.render()?);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::test_image;
+
+	#[test]
+	fn encoding_is_repeatable() {
+		let img = test_image(64, 10);
+		let data = encode(&img, &Default::default()).unwrap();
+		for _ in 0..4 {
+			assert_eq!(encode(&img, &Default::default()).unwrap(), data);
+		}
+		let median_cut = EncodeImageOptions::default().max_colors(Some(16));
+		assert_eq!(encode(&img, &median_cut).unwrap(), encode(&img, &median_cut).unwrap());
+	}
+
+	#[test]
+	fn empty_images_are_rejected() {
+		let empty = image::RgbaImage::new(0, 0);
+		for options in [
+			EncodeImageOptions::default(),
+			EncodeImageOptions::default().palette_downsample(4),
+			EncodeImageOptions::default().max_colors(Some(16)).transparent_below(Some(128)),
+		] {
+			let result = encode(&empty, &options);
+			assert!(matches!(result, Err(EncodeImageError::Analyze(AnalyzeError::EmptyImage))), "{:?}", result);
+		}
+	}
+
+	#[test]
+	fn solid_images_round_trip() {
+		for size in [1, 2, 64] {
+			let red = image::RgbaImage::from_pixel(size, size, image::Rgba([255, 0, 0, 255]));
+			for options in [
+				EncodeImageOptions::default(),
+				EncodeImageOptions::default().max_colors(Some(16)),
+				EncodeImageOptions::default().palette_downsample(4),
+			] {
+				let encoded = encode_to_parts(&red, &options).unwrap();
+				assert_eq!(encoded.tree.node_count(), 1);
+				assert_eq!(encoded.palette.to_rgba(encoded.tree.color).unwrap(), image::Rgba([255, 0, 0, 255]));
+				assert_eq!(qti::read_header(&encoded.data).unwrap().palette_width(), 1);
+				assert!(decode(&encoded.data, &Default::default()).unwrap() == red, "{}x{}", size, size);
+			}
+		}
+	}
+
+	#[test]
+	fn byte_limits_are_respected() {
+		let img = test_image(64, 11);
+		let full = encode(&img, &Default::default()).unwrap().len();
+		for max_bytes in [full, full * 3 / 4, full / 2, full / 3] {
+			let data = encode(&img, &EncodeImageOptions::default().max_bytes(Some(max_bytes))).unwrap();
+			assert!(data.len() <= max_bytes, "{} bytes for a limit of {}", data.len(), max_bytes);
+			assert!(decode(&data, &Default::default()).is_ok());
+		}
+		// Not even the header and palette fit
+		let result = encode(&img, &EncodeImageOptions::default().max_bytes(Some(16)));
+		assert!(matches!(result, Err(EncodeImageError::TooLarge)), "{:?}", result);
+	}
+
+	#[test]
+	fn round_trips_within_the_psnr_bound() {
+		for seed in 1..4 {
+			let img = test_image(128, seed);
+			let decoded = decode(&encode(&img, &Default::default()).unwrap(), &Default::default()).unwrap();
+			let psnr = crate::metrics::psnr(&img, &decoded, &Default::default()).unwrap();
+			assert!(psnr > 30., "{} dB", psnr);
+		}
+	}
+
+	#[test]
+	fn decoder_matches_from_qti_and_to_image() {
+		use image::ImageDecoder;
+		let img = test_image(64, 30);
+		let encoded = encode_to_parts(&img, &Default::default()).unwrap();
+		// Without stored dimensions, so at the natural size or as set
+		let data = encoded.tree.to_qti(&encoded.palette).unwrap();
+		let (tree, palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+		for width in [None, Some(16), Some(128)] {
+			let size = width.unwrap_or_else(|| tree.natural_size());
+			let mut expected = image::RgbaImage::new(size, size);
+			let render_options = RenderOptions { gradient: true, ..Default::default() };
+			tree.to_image_with(&mut expected, &palette, &render_options).unwrap();
+
+			let decoder = QtiDecoder::with_options(&mut &data[..], DecodeImageOptions::default().width(width)).unwrap();
+			assert_eq!(decoder.dimensions(), expected.dimensions());
+			assert_eq!(decoder.color_type(), image::ColorType::Rgba8);
+			let decoded = image::DynamicImage::from_decoder(decoder).unwrap();
+			assert!(decoded.as_rgba8() == Some(&expected), "width {:?}", width);
+		}
+	}
+
+	#[test]
+	fn palette_widths_round_trip() {
+		use image::ImageDecoder;
+		use crate::quantize::palette::{DynamicPalette, PaletteView5, PaletteView6, PaletteView7, PaletteView8};
+
+		/// The QTI data of `data` decoded as a `P`, and encoded again.
+		fn reencoded<P: DynamicPalette + Default + std::fmt::Debug>(data: &[u8]) -> Vec<u8> {
+			let (tree, palette) = QuadtreeNode::<P>::from_qti(data).unwrap();
+			tree.to_qti(&palette).unwrap()
+		}
+
+		let img = test_image(64, 7);
+		for width in 5..=8 {
+			let encoded = encode_to_parts(&img, &EncodeImageOptions::default().palette_width(width)).unwrap();
+			let header = qti::read_header(&encoded.data).unwrap();
+			assert_eq!((header.palette_width(), header.palette.len()), (width, 1 << width));
+
+			let (tree, palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&encoded.data).unwrap();
+			assert_eq!((&tree, &palette), (&encoded.tree, &encoded.palette));
+			let (boxed_tree, boxed_palette) = QuadtreeNode::<Box<dyn Palette>>::read_qti(&mut &encoded.data[..]).unwrap();
+			assert_eq!(boxed_palette.get_slice(), encoded.palette.get_slice());
+			assert_eq!(boxed_tree.to_qti(&boxed_palette).unwrap(), encoded.tree.to_qti(&encoded.palette).unwrap());
+			let fixed = match width {
+				5 => reencoded::<PaletteView5>(&encoded.data),
+				6 => reencoded::<PaletteView6>(&encoded.data),
+				7 => reencoded::<PaletteView7>(&encoded.data),
+				_ => reencoded::<PaletteView8>(&encoded.data)
+			};
+			assert!(fixed == reencoded::<DynamicPaletteView>(&encoded.data), "width {}", width);
+			let mismatched = match width {
+				5 => QuadtreeNode::<PaletteView6>::from_qti(&encoded.data).err(),
+				_ => QuadtreeNode::<PaletteView5>::from_qti(&encoded.data).err()
+			};
+			assert!(matches!(mismatched, Some(DecodeError::WidthMismatch)));
+
+			let decoded = decode(&encoded.data, &Default::default()).unwrap();
+			assert_eq!(decoded.dimensions(), (64, 64));
+			let mut expected = image::RgbaImage::new(64, 64);
+			tree.to_image_with(&mut expected, &palette, &RenderOptions { gradient: true, ..Default::default() }).unwrap();
+			assert!(decoded == expected, "width {}", width);
+			let decoder = QtiDecoder::new(&mut &encoded.data[..]).unwrap();
+			let mut streamed = vec![0; decoder.total_bytes() as usize];
+			decoder.read_image(&mut streamed).unwrap();
+			assert!(streamed == decoded.into_raw(), "width {}", width);
+		}
+	}
+}
@@ -1,30 +1,61 @@
+/// Used internally to generate the setters of options structs.
+macro_rules! option_setters {
+	($($field:ident: $t:ty),* $(,)?) => {
+		$(
+			#[doc = concat!("Sets `", stringify!($field), "`.")]
+			pub fn $field(mut self, $field: $t) -> Self {
+				self.$field = $field;
+				self
+			}
+		)*
+	};
+}
+
+pub mod cli;
+pub mod codec;
 pub mod node;
 
+pub use codec::{decode, encode, DecodeImageOptions, EncodeImageOptions};
 pub use node::*;
 
-use quantize::palette::{Palette};
+/// Helpers shared by the tests of several modules.
+#[cfg(test)]
+pub(crate) mod test_util {
+	/// Repeatable pseudorandom numbers (a linear congruential generator), for
+	/// noise images and palettes without a dependency.
+	pub struct Lcg(pub u64);
 
-use std::collections::HashMap;
+	impl Lcg {
+		pub fn next_u32(&mut self) -> u32 {
+			self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+			(self.0 >> 33) as u32
+		}
 
-impl<P: Palette + Default> node::QuadtreeNode<P> {
+		/// A number below `n`.
+		pub fn below(&mut self, n: u32) -> u32 {
+			self.next_u32() % n
+		}
+	}
 
-	/// "Trims" the tree by removing leaf nodes.
-	///
-	/// Only leaf nodes past a depth of `depth` and with color repetition
-	/// will be removed.
-	pub fn trim(&mut self, depth: isize) {
-		if let Some(sections) = &mut self.sections {
-			if depth <= 0 && sections.iter().all(|s| s.sections.is_none()) {
-				// Count unique colors
-				let col_f = sections.iter().fold(HashMap::new(),
-					|mut m, e| { *m.entry(e.color).or_insert(0) += 1; m });
-				let freq = col_f.values().collect::<Vec<_>>();
-				if freq.len() == 3 || (freq.len() == 2 && **freq.iter().max().unwrap() == 3) {
-					self.sections = None;
-				}
+	/// A colorful `size` by `size` image: gradients in each channel, with a
+	/// disc of another color and a little noise.
+	pub fn test_image(size: u32, seed: u64) -> image::RgbaImage {
+		let mut rng = Lcg(seed);
+		let scale = |v: u32| (v * 255 / std::cmp::max(size - 1, 1)) as u8;
+		image::RgbaImage::from_fn(size, size, |x, y| {
+			let (dx, dy) = (x as i64 - size as i64 / 3, y as i64 - size as i64 / 2);
+			let noise = rng.below(9) as u8;
+			if 9 * (dx * dx + dy * dy) < (size * size) as i64 {
+				image::Rgba([250 - noise, 200 + noise, 40, 255])
 			} else {
-				sections.iter_mut().for_each(|s| s.trim(depth - 1));
+				image::Rgba([scale(x), scale(y), scale(size - 1 - x) / 2 + noise, 255])
 			}
-		}
+		})
+	}
+
+	/// A square of `size` by `size` color numbers below `colors`.
+	pub fn noise(size: usize, colors: u32, seed: u64) -> Vec<u32> {
+		let mut rng = Lcg(seed);
+		(0..size * size).map(|_| rng.below(colors)).collect()
 	}
-}
\ No newline at end of file
+}
@@ -1,3 +1,24 @@
+/// Reason why a palette couldn't give the color for a color number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteError {
+	/// The color number is past the last one of the palette, `max`.
+	OutOfRange { index: u32, max: u32 },
+}
+
+impl PaletteError {
+	/// `OutOfRange` for color number `index` of a palette of `width` bits.
+	pub fn out_of_range(index: u32, width: u8) -> Self {
+		PaletteError::OutOfRange { index, max: ((1u64 << width) - 1) as u32 }
+	}
+
+	/// The color number that the palette couldn't give a color for.
+	pub fn index(&self) -> u32 {
+		match *self {
+			PaletteError::OutOfRange { index, .. } => index
+		}
+	}
+}
+
 /// Reason why a quadtree couldn't be rendered to an image buffer.
 #[derive(Debug)]
 pub enum DrawError {
@@ -5,24 +26,45 @@ pub enum DrawError {
 	NonSquare,
 	/// The image buffer's dimensions are not powers of two.
 	NonPowerOfTwo,
-	/// A color specified in the quadtree is outside the range of the palette.
-	ColorOutOfRange,
+	/// The color number `index` of the node at `path` (from the node that
+	/// was drawn or averaged) is outside the range of the palette.
+	ColorOutOfRange { index: u32, path: super::TreePath },
 }
 
 /// Reason why an image couldn't be turned into a quadtree.
 #[derive(Debug)]
 pub enum AnalyzeError {
+	/// The image has no pixels.
+	EmptyImage,
 	/// The image buffer's dimensions are not equal; the image is not a square.
 	NonSquare,
 	/// The image buffer's dimensions are not powers of two.
 	NonPowerOfTwo,
+	/// The palette has no colors.
+	EmptyPalette,
+	/// The palette has only `len` colors, which a width narrower than its
+	/// `width` would hold, and `AnalyzeOptions::strict_palette_width` is set.
+	SparsePalette { len: usize, width: u8 },
 }
 
 /// Reason why a quadtree couldn't be encoded.
 #[derive(Debug)]
 pub enum EncodeError {
-	/// A color specified in the quadtree is outside the range of the palette.
-	ColorOutOfRange,
+	/// The color number `index` of the node at `path` is outside the range
+	/// of the palette; this is the first such node in the order that nodes
+	/// are encoded.
+	ColorOutOfRange { index: u32, path: super::TreePath },
+	/// The palette's width is not from 1 to 32 bits.
+	InvalidWidth,
+}
+
+/// Reason why a quadtree couldn't be written out as QTI data.
+#[derive(Debug)]
+pub enum QtiWriteError {
+	/// The quadtree couldn't be encoded; nothing was written.
+	Encode(EncodeError),
+	/// Writing failed, possibly after some of the data was written.
+	Io(std::io::Error),
 }
 
 /// Reason why a quadtree encoding couldn't be decoded.
@@ -32,15 +74,76 @@ pub enum DecodeError {
 	InsufficientData,
 	/// There was no valid QTI file header.
 	MissingHeader,
+	/// The QTI header is present, but its format version (given) is not
+	/// one that this build can decode.
+	UnsupportedVersion(u8),
 	/// `GenericPalette` could not stored a palette of the necessary size.
 	PaletteTooLarge,
+	/// The tree is deeper than needed for the image dimensions declared in
+	/// the header, or than `DecodeOptions::max_depth`.
+	TooDeep,
+	/// There are nonzero bits after the end of the quadtree content.
+	NonZeroPadding,
+	/// The palette type has a fixed width other than that of the file's
+	/// palette, or is direct where the file has a palette (or vice versa).
+	WidthMismatch,
+	/// Reading the data failed, other than by it ending too soon (which is
+	/// `InsufficientData`).
+	Io(std::io::Error),
 }
 
 /// Reason why an "image" of palette colors couldn't be made into a quadtree.
 #[derive(Debug)]
 pub enum MountError {
-	/// The size of the "image" buffer is not a power of 4.
+	/// The size of the "image" buffer is not a power of 4, or the region
+	/// given to `mount` is not a square of a power-of-two size within it.
 	InvalidSize,
-	/// A pixel has a color outside the extent of the palette.
+	/// A pixel has a color number outside the extent of the palette: not
+	/// below `1 << width`.
+	ColorOutOfRange,
+}
+
+/// Reason why a quadtree couldn't be moved onto another palette.
+#[derive(Debug)]
+pub enum ReindexError {
+	/// A color specified in the quadtree is outside the range of its palette.
 	ColorOutOfRange,
-}
\ No newline at end of file
+	/// The new palette has no colors.
+	EmptyPalette,
+}
+
+/// Reason why quadtrees couldn't be combined into one.
+#[derive(Debug)]
+pub enum CombineError {
+	/// The quadrant with this index is deeper than allowed.
+	TooDeep(usize),
+}
+
+/// Reason why two images couldn't be compared.
+#[derive(Debug)]
+pub enum CompareError {
+	/// The images do not have the same dimensions.
+	DimensionMismatch,
+}
+
+/// Reason why `codec::encode` couldn't encode an image.
+#[derive(Debug)]
+pub enum EncodeImageError {
+	/// The image couldn't be analyzed into a quadtree.
+	Analyze(AnalyzeError),
+	/// The quadtree couldn't be encoded.
+	Encode(EncodeError),
+	/// The output can't be trimmed down to `max_bytes`.
+	TooLarge,
+}
+
+/// Reason why `codec::decode` couldn't decode an image.
+#[derive(Debug)]
+pub enum DecodeImageError {
+	/// The QTI data couldn't be decoded.
+	Decode(DecodeError),
+	/// The quadtree couldn't be drawn at the requested size.
+	Draw(DrawError),
+	/// The image would be wider than `DecodeImageOptions::max_size`.
+	TooLarge,
+}
@@ -1,5 +1,7 @@
 use super::error::*;
+use super::quantize::DistanceMetric;
 use super::quantize::palette::{Color, Palette};
+use super::TreePath;
 
 fn color_lerp(a: Color, b: Color, n: f64) -> Color {
 	image::Rgba::<u8>([
@@ -10,6 +12,211 @@ fn color_lerp(a: Color, b: Color, n: f64) -> Color {
 	])
 }
 
+/// Composites `fg` over `bg` ("over" operator, non-premultiplied).
+fn color_over(fg: Color, bg: Color) -> Color {
+	let fg_a = fg.0[3] as f64 / 255.;
+	let bg_a = bg.0[3] as f64 / 255. * (1. - fg_a);
+	let out_a = fg_a + bg_a;
+	if out_a == 0. {
+		return image::Rgba([0; 4]);
+	}
+	let channel = |i: usize| ((fg.0[i] as f64 * fg_a + bg.0[i] as f64 * bg_a) / out_a).round() as u8;
+	image::Rgba([channel(0), channel(1), channel(2), (out_a * 255.).round() as u8])
+}
+
+/// Fills the square of `size` at `pos` with `c`, clipped to the image.
+fn fill_square(img: &mut image::RgbaImage, pos: (u32, u32), size: u32, c: Color) {
+	let (width, height) = img.dimensions();
+	if pos.0 >= width || pos.1 >= height {
+		return;
+	}
+	let row = c.0.repeat(std::cmp::min(size, width - pos.0) as usize);
+	let raw: &mut [u8] = img;
+	for y in pos.1..std::cmp::min(pos.1 + size, height) {
+		let start = (y as usize * width as usize + pos.0 as usize) * 4;
+		raw[start..start + row.len()].copy_from_slice(&row);
+	}
+}
+
+/// The path to the node drawn over the square of `size` at `pos`, `depth`
+/// levels below the node that drawing started from.
+fn path_at(pos: (u32, u32), size: u32, depth: usize) -> TreePath {
+	let (x, y) = (pos.0 / size, pos.1 / size);
+	(0..depth).rev().map(|level| ((y >> level & 1) << 1 | (x >> level & 1)) as u8).collect()
+}
+
+/// `DrawError::ColorOutOfRange` for `error`, of the node at `path_at(pos,
+/// size, depth)`.
+fn out_of_range(error: PaletteError, pos: (u32, u32), size: u32, depth: usize) -> DrawError {
+	DrawError::ColorOutOfRange { index: error.index(), path: path_at(pos, size, depth) }
+}
+
+/// Gaussian blur of `img`, in linear light if `linear`; alpha is blurred as
+/// it is. Pixels with alpha below `transparent_below` (if given) are left as
+/// they are and don't bleed into the others, which are blurred only with
+/// each other.
+fn blur_image(img: &image::RgbaImage, sigma: f32, linear: bool, transparent_below: Option<u8>) -> image::RgbaImage {
+	use super::quantize::{linear_to_srgb, srgb_to_linear};
+	let to_float = |v: u8| if linear { srgb_to_linear(v) as f32 } else { v as f32 / 255. };
+	let from_float = |v: f32| if linear {
+		linear_to_srgb(v as f64)
+	} else {
+		(v.clamp(0., 1.) * 255.).round() as u8
+	};
+	let kept = |p: &Color| transparent_below.is_none_or(|alpha| p.0[3] >= alpha);
+	let (width, height) = img.dimensions();
+	let blurred = image::imageops::blur(
+		&image::ImageBuffer::<image::Rgba<f32>, _>::from_fn(width, height, |x, y| {
+			let p = img.get_pixel(x, y);
+			if !kept(p) {
+				return image::Rgba([0.; 4]);
+			}
+			let [r, g, b, a] = p.0;
+			image::Rgba([to_float(r), to_float(g), to_float(b), a as f32 / 255.])
+		}),
+		sigma
+	);
+	// How much of each blurred pixel comes from kept pixels, to divide by
+	let weights = transparent_below.map(|_| image::imageops::blur(
+		&image::ImageBuffer::<image::Luma<f32>, _>::from_fn(width, height, |x, y| {
+			image::Luma([if kept(img.get_pixel(x, y)) { 1. } else { 0. }])
+		}),
+		sigma
+	));
+	image::RgbaImage::from_fn(width, height, |x, y| {
+		let p = img.get_pixel(x, y);
+		if !kept(p) {
+			return *p;
+		}
+		let weight = weights.as_ref().map_or(1., |w| w.get_pixel(x, y).0[0]).max(f32::MIN_POSITIVE);
+		let [r, g, b, a] = blurred.get_pixel(x, y).0.map(|v| v / weight);
+		image::Rgba([from_float(r), from_float(g), from_float(b), (a.clamp(0., 1.) * 255.).round() as u8])
+	})
+}
+
+/// How `to_image_with` fills leaf nodes larger than one pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UpscaleMode {
+	/// Solid squares of the leaf's color (apart from `gradient` rendering).
+	#[default]
+	Nearest,
+	/// Bilinear interpolation between the colors of the leaf and its
+	/// siblings, for every leaf with a parent node rather than just those
+	/// whose siblings are all leaves. Unlike with `gradient`, each color is
+	/// placed at the center of its quadrant rather than at the parent's
+	/// corner, as suits leaves that were single pixels of a smaller image;
+	/// the outer quarter of the parent is flat.
+	Smooth,
+}
+
+/// Options for drawing a quadtree with `to_image_with`.
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+	/// See `to_image`.
+	pub gradient: bool,
+	/// See `to_image`.
+	pub background: Option<Color>,
+	/// How to fill leaves larger than one pixel.
+	pub upscale: UpscaleMode,
+	/// If given, nodes more than this many levels below the root are not
+	/// drawn; nodes at that level are drawn as solid squares of their own
+	/// colors, as if they were leaves (but without `gradient` or `upscale`
+	/// interpolation). This gives a quick, coarse preview.
+	pub max_depth: Option<usize>,
+	/// With `UpscaleMode::Smooth`, whether leaves are blended with the
+	/// average colors (see `average_color`) of their subdivided siblings,
+	/// rather than those siblings' own colors (typically their most common
+	/// ones). Averages blend across edges, so this is not always closer to
+	/// the original image.
+	pub average_branches: bool,
+}
+
+/// Options for analyzing an image into a quadtree with `from_image_with`
+/// (or a square of color numbers with `mount_with`).
+///
+/// Each field has a setter of the same name, for building options up in a
+/// chain: `AnalyzeOptions::default().blur(0.).max_depth(Some(4))`. More
+/// settings (such as dithering) may be added as fields, so build on
+/// `default()` with setters or `..Default::default()` rather than listing
+/// every field.
+#[derive(Clone, Debug)]
+pub struct AnalyzeOptions {
+	/// How much of a square (fraction out of 16384) must be the same color
+	/// for its subsections to be disregarded; 16384 keeps every detail.
+	pub sensitivity: usize,
+	/// If given (and not empty), the sensitivity for squares at each depth
+	/// (0 for the whole image) in place of `sensitivity`, with squares
+	/// deeper than the list covers using its last entry; for demanding
+	/// near-uniformity of large squares while tolerating more variance in
+	/// small ones, or the reverse.
+	pub sensitivity_by_depth: Option<Vec<usize>>,
+	/// Amount of Gaussian blur to apply to the image before analysis, to
+	/// remove noise.
+	pub blur: f32,
+	/// Whether to blur in linear light rather than directly on the sRGB
+	/// values, which darkens edges between contrasting colors and shifts
+	/// their hues; slower.
+	pub linear_blur: bool,
+	/// If given, pixels with alpha below this are all given the palette's
+	/// most transparent color (see `quantize::transparent_index`), rather
+	/// than the nearest, and are kept out of the blur of the other pixels;
+	/// this keeps the edges of cutouts crisp, without halos.
+	pub transparent_below: Option<u8>,
+	/// How to pick the colors of gradients, if there are to be any, so
+	/// that the tree looks best rendered with `gradient`; `None` for a tree
+	/// to be rendered flat.
+	pub gradient_fit: Option<super::GradientFit>,
+	/// How pixel colors are matched to palette entries.
+	pub metric: DistanceMetric,
+	/// If given, a branch whose subsections are all leaves within this
+	/// squared distance of its own color becomes a leaf itself. This gives
+	/// larger uniform squares, which `trim` works better with.
+	pub snap: Option<u32>,
+	/// If given, no node is more than this many levels below the root.
+	pub max_depth: Option<u8>,
+	/// Whether to give `AnalyzeError::SparsePalette` for palettes whose
+	/// colors would fit a width a bit narrower (a `PaletteView8` of ten
+	/// colors, say), so every node of the tree wastes bits, rather than
+	/// using them as they are. Either way, pixels are only matched to the
+	/// colors that the palette has.
+	pub strict_palette_width: bool,
+}
+
+impl Default for AnalyzeOptions {
+	/// The defaults of the CLI: a sensitivity of 63/64 at every depth, blur
+	/// of 1 (in sRGB), gradients picked with `GradientFit::Corners`, and no
+	/// snapping or depth limit.
+	fn default() -> Self {
+		AnalyzeOptions {
+			sensitivity: 16128,
+			sensitivity_by_depth: None,
+			blur: 1.,
+			linear_blur: false,
+			transparent_below: None,
+			gradient_fit: Some(super::GradientFit::Corners),
+			metric: DistanceMetric::Euclidean,
+			snap: None,
+			max_depth: None,
+			strict_palette_width: false,
+		}
+	}
+}
+
+impl AnalyzeOptions {
+	option_setters! {
+		sensitivity: usize,
+		sensitivity_by_depth: Option<Vec<usize>>,
+		blur: f32,
+		linear_blur: bool,
+		transparent_below: Option<u8>,
+		gradient_fit: Option<super::GradientFit>,
+		metric: DistanceMetric,
+		snap: Option<u32>,
+		max_depth: Option<u8>,
+		strict_palette_width: bool,
+	}
+}
+
 impl<P: Palette + Default> super::QuadtreeNode<P> {
 	/// Attempts to generate an image into the supplied buffer
 	/// from this quadtree node and its "branches" and "leaves".
@@ -24,14 +231,22 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 	///
 	/// `gradient` indicates whether leaf nodes will be presented as
 	/// solid squares of color or bilinear gradients between the leaf
-	/// nodes below the relevant branch.
+	/// nodes below the relevant branch. Only branches whose subsections are
+	/// all leaves are drawn as gradients; see `UpscaleMode::Smooth` for
+	/// blending the others too.
+	///
+	/// If `background` is given, every color is alpha-composited over it
+	/// before being written; an opaque background thus gives an opaque
+	/// image (e.g. for JFIF output). With `None`, the palette's RGBA values
+	/// are copied as-is.
 	pub fn to_image(
 		&self,
 		img: &mut image::RgbaImage,
 		palette: &P,
 		size: Option<u32>,
 		start_pos: Option<(u32, u32)>,
-		gradient: bool
+		gradient: bool,
+		background: Option<Color>
 	) -> Result<(), DrawError> {
 		// Check input validity
 		if img.width() != img.height() {
@@ -42,100 +257,585 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 			return Err(DrawError::NonPowerOfTwo);
 		}
 
-		// Draw current node
-		let curr_size = size.unwrap_or_else(|| img.width());
-		let curr_pos = start_pos.unwrap_or((0, 0));
-		match palette.to_rgba(self.color) {
-			Ok(c) => image::imageops::replace(
-				img,
-				&image::RgbaImage::from_pixel(curr_size, curr_size, c),
-				curr_pos.0,
-				curr_pos.1,
-			),
-			Err(_) => return Err(DrawError::ColorOutOfRange),
-		}
+		let options = RenderOptions { gradient, background, ..Default::default() };
+		self.draw(
+			img,
+			palette,
+			size.unwrap_or_else(|| img.width()),
+			start_pos.unwrap_or((0, 0)),
+			&options,
+			0
+		)
+	}
+
+	/// Helper for `to_image` and `to_image_with`; draws with
+	/// `UpscaleMode::Nearest`. `depth` is the level of this node.
+	fn draw(
+		&self,
+		img: &mut image::RgbaImage,
+		palette: &P,
+		curr_size: u32,
+		curr_pos: (u32, u32),
+		options: &RenderOptions,
+		depth: usize
+	) -> Result<(), DrawError> {
+		// Draw current node, unless its subsections will cover it
+		let composite = |c: Color| options.background.map_or(c, |bg| color_over(c, bg));
+		let c = palette.to_rgba(self.color).map_err(|e| out_of_range(e, curr_pos, curr_size, depth))?;
+		let sects = match self.sections {
+			Some(ref sects) if curr_size > 1 && options.max_depth.is_none_or(|max| depth < max) => sects,
+			_ => {
+				fill_square(img, curr_pos, curr_size, composite(c));
+				return Ok(());
+			}
+		};
 
 		// Recursion
-		if curr_size > 1 {
-			if let Some(ref sects) = self.sections {
-				if gradient && sects.iter().all(|s| s.sections.is_none()) {
-					for row in curr_pos.1..(curr_pos.1 + curr_size) {
-						for col in curr_pos.0..(curr_pos.0 + curr_size) {
-							let sect_colors = sects.iter()
-								.map(|s| palette.to_rgba(s.color))
-								.fold(Ok(Vec::new()), |v, n| match (v, n) {
-									(Ok(mut l), Ok(c)) => { l.push(c); Ok(l) },
-									_ => Err(DrawError::ColorOutOfRange)
-								})?;
-							let x_n = ((col - curr_pos.0) as f64) / curr_size as f64;
-							let y_n = ((row - curr_pos.1) as f64) / curr_size as f64;
-							let imm_c = color_lerp(
-								color_lerp(sect_colors[0], sect_colors[1], x_n),
-								color_lerp(sect_colors[2], sect_colors[3], x_n),
-								y_n
-							);
-							img.put_pixel(col, row, imm_c);
-						}
-					}
-				} else {
-					let positions = [
-						(curr_pos.0, curr_pos.1),
-						(curr_pos.0 + curr_size / 2, curr_pos.1),
-						(curr_pos.0, curr_pos.1 + curr_size / 2),
-						(curr_pos.0 + curr_size / 2, curr_pos.1 + curr_size / 2),
-					];
-					for (ind, section) in sects.iter().enumerate() {
-						section.to_image(
-							img,
-							palette,
-							Some(curr_size / 2),
-							Some(positions[ind]),
-							gradient
-						)?;
-					}
+		let positions = [
+			(curr_pos.0, curr_pos.1),
+			(curr_pos.0 + curr_size / 2, curr_pos.1),
+			(curr_pos.0, curr_pos.1 + curr_size / 2),
+			(curr_pos.0 + curr_size / 2, curr_pos.1 + curr_size / 2),
+		];
+		if options.gradient && sects.iter().all(|s| s.sections.is_none()) {
+			let mut sect_colors = [image::Rgba([0; 4]); 4];
+			for ((c, s), pos) in sect_colors.iter_mut().zip(sects.iter()).zip(positions.iter()) {
+				*c = palette.to_rgba(s.color).map_err(|e| out_of_range(e, *pos, curr_size / 2, depth + 1))?;
+			}
+			for row in curr_pos.1..(curr_pos.1 + curr_size) {
+				for col in curr_pos.0..(curr_pos.0 + curr_size) {
+					let x_n = ((col - curr_pos.0) as f64) / curr_size as f64;
+					let y_n = ((row - curr_pos.1) as f64) / curr_size as f64;
+					let imm_c = color_lerp(
+						color_lerp(sect_colors[0], sect_colors[1], x_n),
+						color_lerp(sect_colors[2], sect_colors[3], x_n),
+						y_n
+					);
+					img.put_pixel(col, row, composite(imm_c));
 				}
 			}
+		} else {
+			for (ind, section) in sects.iter().enumerate() {
+				section.draw(
+					img,
+					palette,
+					curr_size / 2,
+					positions[ind],
+					options,
+					depth + 1
+				)?;
+			}
 		}
 
 		Ok(())
 	}
 
-	/// Analyzes a traditional image into a quadtree, "rounding" pixel colors
-	/// to the nearest entries in the palette.
+	/// Draws the quadtree into the supplied buffer, like `to_image`, with the
+	/// settings in `options`.
+	pub fn to_image_with(
+		&self,
+		img: &mut image::RgbaImage,
+		palette: &P,
+		options: &RenderOptions
+	) -> Result<(), DrawError> {
+		if img.width() != img.height() {
+			return Err(DrawError::NonSquare);
+		}
+		if !img.width().is_power_of_two() {
+			return Err(DrawError::NonPowerOfTwo);
+		}
+		let size = img.width();
+		match options.upscale {
+			UpscaleMode::Nearest => self.draw(img, palette, size, (0, 0), options, 0),
+			UpscaleMode::Smooth => self.draw_smooth(img, palette, ((0, 0), size), options, 0, None)
+		}
+	}
+
+	/// Helper for `to_image_with`; draws with `UpscaleMode::Smooth`.
+	///
+	/// The node covers the square at the given position and of the given
+	/// size. `depth` is the level of this node, and `parent` is the colors of
+	/// this node and its siblings, and the position and size of their
+	/// parent, if there is one.
+	fn draw_smooth(
+		&self,
+		img: &mut image::RgbaImage,
+		palette: &P,
+		(pos, size): ((u32, u32), u32),
+		options: &RenderOptions,
+		depth: usize,
+		parent: Option<(&[Color; 4], (u32, u32), u32)>
+	) -> Result<(), DrawError> {
+		let composite = |c: Color| options.background.map_or(c, |bg| color_over(c, bg));
+		let capped = options.max_depth.is_some_and(|max| depth >= max);
+		match (&self.sections, parent) {
+			_ if capped => {
+				let c = palette.to_rgba(self.color).map_err(|e| out_of_range(e, pos, size, depth))?;
+				fill_square(img, pos, size, composite(c));
+			},
+			(Some(sects), _) if size > 1 => {
+				let half = size / 2;
+				let positions = [
+					(pos.0, pos.1),
+					(pos.0 + half, pos.1),
+					(pos.0, pos.1 + half),
+					(pos.0 + half, pos.1 + half),
+				];
+				let mut sect_colors = [image::Rgba([0; 4]); 4];
+				for ((c, s), sect_pos) in sect_colors.iter_mut().zip(sects.iter()).zip(positions.iter()) {
+					*c = if options.average_branches && s.sections.is_some() {
+						s.average_color_at(palette, path_at(*sect_pos, half, depth + 1))?
+					} else {
+						palette.to_rgba(s.color).map_err(|e| out_of_range(e, *sect_pos, half, depth + 1))?
+					};
+				}
+				for (section, sect_pos) in sects.iter().zip(positions.iter()) {
+					section.draw_smooth(
+						img,
+						palette,
+						(*sect_pos, half),
+						options,
+						depth + 1,
+						Some((&sect_colors, pos, size))
+					)?;
+				}
+			},
+			(None, Some((colors, p_pos, p_size))) if size > 1 => {
+				// Interpolation between the centers of the parent's
+				// quadrants, over the part of the parent that this leaf
+				// covers
+				let center_n = |offset: u32| ((offset as f64 + 0.5) / p_size as f64 * 2. - 0.5).clamp(0., 1.);
+				for row in pos.1..(pos.1 + size) {
+					for col in pos.0..(pos.0 + size) {
+						let x_n = center_n(col - p_pos.0);
+						let y_n = center_n(row - p_pos.1);
+						let imm_c = color_lerp(
+							color_lerp(colors[0], colors[1], x_n),
+							color_lerp(colors[2], colors[3], x_n),
+							y_n
+						);
+						img.put_pixel(col, row, composite(imm_c));
+					}
+				}
+			},
+			_ => {
+				// A single pixel, or a leaf with no siblings to blend with
+				let c = palette.to_rgba(self.color).map_err(|e| out_of_range(e, pos, size, depth))?;
+				fill_square(img, pos, size, composite(c));
+			}
+		}
+		Ok(())
+	}
+
+	/// Averages the colors of the leaf nodes below this node (or this node's
+	/// own color, if it is a leaf), weighted by the area they cover.
 	///
-	/// See documentation on `mount` for the meaning of `sensitivity`.
+	/// Will return an `Err` if the color in a quadtree node does not fit in
+	/// the provided palette.
+	pub fn average_color(&self, palette: &P) -> Result<Color, DrawError> {
+		self.average_color_at(palette, Vec::new())
+	}
+
+	/// Helper for `average_color` and `draw_smooth`; `path` is where this
+	/// node is, for errors.
+	fn average_color_at(&self, palette: &P, mut path: TreePath) -> Result<Color, DrawError> {
+		let avg = self.average_channels(palette, &mut path)?;
+		Ok(image::Rgba([
+			avg[0].round() as u8,
+			avg[1].round() as u8,
+			avg[2].round() as u8,
+			avg[3].round() as u8,
+		]))
+	}
+
+	/// Helper for `average_color_at`.
+	fn average_channels(&self, palette: &P, path: &mut TreePath) -> Result<[f64; 4], DrawError> {
+		match self.sections {
+			Some(ref sects) => sects.iter().enumerate().try_fold([0.; 4], |mut sum, (ind, s)| {
+				path.push(ind as u8);
+				for (total, channel) in sum.iter_mut().zip(s.average_channels(palette, path)?.iter()) {
+					*total += channel / 4.;
+				}
+				path.pop();
+				Ok(sum)
+			}),
+			None => palette.to_rgba(self.color)
+				.map(|c| [c.0[0] as f64, c.0[1] as f64, c.0[2] as f64, c.0[3] as f64])
+				.map_err(|e| DrawError::ColorOutOfRange { index: e.index(), path: path.clone() })
+		}
+	}
+
+	/// The smallest image width (and height) at which every node is drawn at
+	/// least one pixel wide: `2^depth`, saturating at `2^31`.
+	pub fn natural_size(&self) -> u32 {
+		1 << std::cmp::min(self.depth(), 31)
+	}
+
+	/// Renders the quadtree into a new image of its `natural_size`.
 	///
-	/// `blur` is the amount of Gaussian blur to apply to the image before
-	/// quadtreeifying (to remove noise).
+	/// See `to_image` for the meaning of `gradient` and the possible errors.
+	pub fn render(&self, palette: &P, gradient: bool) -> Result<image::RgbaImage, DrawError> {
+		let size = self.natural_size();
+		let mut img = image::RgbaImage::new(size, size);
+		self.to_image(&mut img, palette, None, None, gradient, None)?;
+		Ok(img)
+	}
+
+	/// Like `from_image_with`, with the settings of `AnalyzeOptions` given
+	/// separately (and the others left at their defaults).
 	///
 	/// `gradient` indicates whether or not to generate the quadtree in a way
 	/// such that the resultant restored image will be of higher quality
-	/// (in theory) if `gradient` is passed as `true` to `to_image`.
+	/// (in theory) if `gradient` is passed as `true` to `to_image`; gradients
+	/// are picked with `GradientFit::Corners`.
+	#[deprecated(note = "use `from_image_with` and `AnalyzeOptions` instead")]
 	pub fn from_image(
 		&mut self,
 		img: &image::RgbaImage,
 		palette: &P,
 		sensitivity: usize,
 		blur: f32,
-		gradient: bool
+		gradient: bool,
+		metric: DistanceMetric
+	) -> Result<(), AnalyzeError> {
+		self.from_image_with(img, palette, &AnalyzeOptions {
+			sensitivity,
+			blur,
+			gradient_fit: if gradient { Some(super::GradientFit::Corners) } else { None },
+			metric,
+			..Default::default()
+		})
+	}
+
+	/// Analyzes a traditional image into a quadtree, "rounding" pixel colors
+	/// to the nearest entries in the palette, with the settings in `options`.
+	///
+	/// An image whose pixels are all the same color gives a single leaf.
+	pub fn from_image_with(
+		&mut self,
+		img: &image::RgbaImage,
+		palette: &P,
+		options: &AnalyzeOptions
 	) -> Result<(), AnalyzeError> {
 		// Validate image size
+		if img.width() == 0 || img.height() == 0 {
+			return Err(AnalyzeError::EmptyImage);
+		}
 		if img.width() != img.height() {
 			return Err(AnalyzeError::NonSquare);
 		}
 		if !img.width().is_power_of_two() {
 			return Err(AnalyzeError::NonPowerOfTwo);
 		}
+		// There would be no color to give any pixel
+		if palette.is_empty() {
+			return Err(AnalyzeError::EmptyPalette);
+		}
+		// A palette type wider than its colors need; only the populated
+		// colors are used for quantization
+		if options.strict_palette_width && palette.width() > 1 && palette.len() <= 1 << (palette.width() - 1) {
+			return Err(AnalyzeError::SparsePalette { len: palette.len(), width: palette.width() });
+		}
+
+		// A uniform image is a single leaf, with nothing to blur or analyze
+		let first = *img.get_pixel(0, 0);
+		if img.pixels().all(|p| *p == first) {
+			let quantized = super::quantize::quantize_to_palette(
+				&image::RgbaImage::from_pixel(1, 1, first),
+				palette,
+				options.metric
+			);
+			self.color = match options.transparent_below {
+				Some(alpha) if first.0[3] < alpha => super::quantize::transparent_index(palette),
+				_ => quantized[0]
+			};
+			self.sections = None;
+			return Ok(());
+		}
 
-		let img_tr = if blur == 0. { img.to_owned() } else { image::imageops::blur(img, blur) };
-		let palettified = super::quantize::quantize_to_palette(
+		let img_tr = if options.blur == 0. {
+			img.to_owned()
+		} else if options.linear_blur || options.transparent_below.is_some() {
+			blur_image(img, options.blur, options.linear_blur, options.transparent_below)
+		} else {
+			image::imageops::blur(img, options.blur)
+		};
+		let mut palettified = super::quantize::quantize_to_palette(
 			&img_tr,
-			palette
+			palette,
+			options.metric
 		);
-		match self.mount(&palettified, palette, None, None, sensitivity, gradient) {
+		if let Some(alpha) = options.transparent_below {
+			let transparent = super::quantize::transparent_index(palette);
+			for (color, pixel) in palettified.iter_mut().zip(img.pixels()) {
+				if pixel.0[3] < alpha {
+					*color = transparent;
+				}
+			}
+		}
+		match self.mount_with(&palettified, palette, options) {
 			Ok(_) => (),
 			Err(_) => unreachable!("error in mounting")
 		}
 		Ok(())
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::quantize::{generate_palette_median_cut, DistanceMetric};
+	use crate::quantize::palette::{DynamicPaletteView, PaletteView1, PaletteView8};
+	use crate::test_util::test_image;
+	use crate::{GradientFit, QuadtreeNode};
+
+	/// Draws `tree` at `size` by `size`, with gradients if `gradient`.
+	fn draw(tree: &QuadtreeNode<DynamicPaletteView>, palette: &DynamicPaletteView, size: u32, gradient: bool) -> image::RgbaImage {
+		let mut out = image::RgbaImage::new(size, size);
+		tree.to_image_with(&mut out, palette, &RenderOptions { gradient, ..Default::default() }).unwrap();
+		out
+	}
+
+	/// The sum of the squared differences of the channels of `a` and `b`.
+	fn squared_error(a: &image::RgbaImage, b: &image::RgbaImage) -> u64 {
+		a.pixels().zip(b.pixels())
+			.flat_map(|(p, q)| p.0.iter().zip(q.0.iter()))
+			.map(|(&x, &y)| (x as i64 - y as i64).pow(2) as u64)
+			.sum()
+	}
+
+	#[test]
+	fn backgrounds_are_composited_under_colors() {
+		let (red, blue) = (image::Rgba([255, 0, 0, 128]), image::Rgba([0, 0, 255, 255]));
+		assert_eq!(color_over(red, blue), image::Rgba([128, 0, 127, 255]));
+		assert_eq!(color_over(image::Rgba([255, 0, 0, 0]), blue), blue);
+		assert_eq!(color_over(red, image::Rgba([0; 4])), red);
+		assert_eq!(color_over(image::Rgba([9; 4]), image::Rgba([0; 4])), image::Rgba([9; 4]));
+		assert_eq!(color_over(image::Rgba([255, 0, 0, 0]), image::Rgba([0, 0, 255, 0])), image::Rgba([0; 4]));
+
+		// Over an opaque background every pixel is opaque, and without one
+		// the palette's RGBA is drawn as it is
+		let palette: DynamicPaletteView = vec![red, image::Rgba([0, 255, 0, 255])].into();
+		let leaf = |color| QuadtreeNode::<DynamicPaletteView> { color, ..Default::default() };
+		let tree = QuadtreeNode { color: 0, sections: Some(Box::new([leaf(0), leaf(1), leaf(1), leaf(0)])), ..Default::default() };
+		for gradient in [false, true] {
+			let (mut plain, mut composited) = (image::RgbaImage::new(8, 8), image::RgbaImage::new(8, 8));
+			tree.to_image(&mut plain, &palette, None, None, gradient, None).unwrap();
+			tree.to_image(&mut composited, &palette, None, None, gradient, Some(blue)).unwrap();
+			for (pixel, drawn) in composited.pixels().zip(plain.pixels()) {
+				assert_eq!(*pixel, color_over(*drawn, blue));
+				assert_eq!(pixel.0[3], 255);
+			}
+			assert_eq!(*plain.get_pixel(0, 0), red);
+			assert_eq!(*composited.get_pixel(0, 0), image::Rgba([128, 0, 127, 255]));
+		}
+	}
+
+	#[test]
+	fn linear_blur_brightens_edges() {
+		let img = image::RgbaImage::from_fn(16, 16, |x, _| if x < 8 {
+			image::Rgba([255, 0, 0, 255])
+		} else {
+			image::Rgba([0, 255, 0, 255])
+		});
+		let (srgb, linear) = (blur_image(&img, 2., false, None), blur_image(&img, 2., true, None));
+		let brightness = |p: &Color| p.0[0] as u32 + p.0[1] as u32;
+		for x in 6..10 {
+			assert!(brightness(linear.get_pixel(x, 8)) > brightness(srgb.get_pixel(x, 8)) + 40, "x = {}", x);
+		}
+		// The two pixels either side of the edge mirror each other; in sRGB
+		// their red and green add up to about one full channel, as though
+		// dimmed, while in linear light they add up to well over it
+		for img in [&srgb, &linear] {
+			assert_eq!(img.get_pixel(7, 8).0[..2], [img.get_pixel(8, 8).0[1], img.get_pixel(8, 8).0[0]]);
+		}
+		assert!(brightness(srgb.get_pixel(7, 8)) < 270);
+		assert!(brightness(linear.get_pixel(7, 8)) > 350);
+		// Far from the edge, the colors are as they were
+		assert_eq!(*linear.get_pixel(0, 0), *img.get_pixel(0, 0));
+		assert_eq!(*linear.get_pixel(15, 15), *img.get_pixel(15, 15));
+	}
+
+	#[test]
+	fn empty_images_are_rejected() {
+		let palette: DynamicPaletteView = vec![image::Rgba([0, 0, 0, 255])].into();
+		for (width, height) in [(0, 0), (0, 4), (4, 0)] {
+			let mut tree = QuadtreeNode::default();
+			let result = tree.from_image_with(&image::RgbaImage::new(width, height), &palette, &Default::default());
+			assert!(matches!(result, Err(AnalyzeError::EmptyImage)), "{}x{}: {:?}", width, height, result);
+		}
+	}
+
+	#[test]
+	fn strict_palette_width_rejects_sparse_palettes() {
+		let img = test_image(16, 9);
+		let colors = generate_palette_median_cut::<DynamicPaletteView>(&img, 10).get_slice().unwrap().to_vec();
+		let palette = PaletteView8::from(colors.clone());
+		let mut tree = QuadtreeNode::default();
+		let strict = AnalyzeOptions::default().strict_palette_width(true);
+		let result = tree.from_image_with(&img, &palette, &strict);
+		assert!(matches!(result, Err(AnalyzeError::SparsePalette { len: 10, width: 8 })), "{:?}", result);
+		tree.from_image_with(&img, &palette, &Default::default()).unwrap();
+		assert!(tree.render(&palette, false).unwrap().pixels().all(|p| colors.contains(p)));
+
+		// Palettes needing their full width pass, as do those of one bit
+		let wide = PaletteView8::from((0..129).map(|c| image::Rgba([c as u8, 0, 0, 255])).collect::<Vec<_>>());
+		assert!(QuadtreeNode::default().from_image_with(&img, &wide, &strict).is_ok());
+		let one_bit = PaletteView1::from(vec![image::Rgba([0, 0, 0, 255])]);
+		assert!(QuadtreeNode::default().from_image_with(&img, &one_bit, &strict).is_ok());
+	}
+
+	#[test]
+	#[allow(deprecated)]
+	fn from_image_matches_from_image_with() {
+		let img = test_image(32, 8);
+		let palette: DynamicPaletteView = generate_palette_median_cut(&img, 16);
+		for (sensitivity, blur, gradient, metric) in [
+			(16128, 1., true, DistanceMetric::Euclidean),
+			(12000, 0., false, DistanceMetric::Lab),
+			(16383, 2.5, true, DistanceMetric::WeightedRgb),
+		] {
+			let mut tree = QuadtreeNode::default();
+			tree.from_image(&img, &palette, sensitivity, blur, gradient, metric).unwrap();
+			let mut expected = QuadtreeNode::default();
+			let options = AnalyzeOptions::default()
+				.sensitivity(sensitivity)
+				.blur(blur)
+				.gradient_fit(if gradient { Some(GradientFit::Corners) } else { None })
+				.metric(metric);
+			expected.from_image_with(&img, &palette, &options).unwrap();
+			assert_eq!(tree, expected);
+		}
+	}
+
+	#[test]
+	fn smooth_leaves_blend_with_subdivided_siblings() {
+		let (black, white, blue) = (image::Rgba([0, 0, 0, 255]), image::Rgba([255; 4]), image::Rgba([0, 0, 255, 255]));
+		let palette: DynamicPaletteView = vec![black, white, blue].into();
+		let leaf = |color| QuadtreeNode::<DynamicPaletteView> { color, ..Default::default() };
+		let branch = |sections, color| QuadtreeNode { color, sections: Some(Box::new(sections)), ..Default::default() };
+		let subdivided = branch([leaf(1), leaf(1), leaf(1), leaf(2)], 1);
+		let tree = branch([leaf(0), leaf(1), leaf(0), subdivided], 0);
+
+		// With `gradient`, the leaves are solid, as a sibling is subdivided
+		let flat = draw(&tree, &palette, 32, true);
+		assert!(flat.enumerate_pixels().filter(|(x, y, _)| *x < 16 && *y < 16).all(|(_, _, c)| *c == black));
+
+		// The inner corner of the first leaf is half a pixel short of the
+		// centers of the others' quadrants
+		let t = 15.5 / 16. - 0.5;
+		for (average_branches, corner) in [(false, white), (true, image::Rgba([191, 191, 255, 255]))] {
+			let options = RenderOptions { upscale: UpscaleMode::Smooth, average_branches, ..Default::default() };
+			let mut smooth = image::RgbaImage::new(32, 32);
+			tree.to_image_with(&mut smooth, &palette, &options).unwrap();
+			let expected = color_lerp(color_lerp(black, white, t), color_lerp(black, corner, t), t);
+			assert_eq!(*smooth.get_pixel(15, 15), expected);
+			assert_eq!(*smooth.get_pixel(0, 0), black);
+		}
+	}
+
+	#[test]
+	fn smooth_upscaling_is_closer_than_nearest() {
+		let lerp = |a: f64, b: f64, t: f64| (a + (b - a) * t).round() as u8;
+		let img = image::RgbaImage::from_fn(64, 64, |x, y| {
+			let t = ((x as f64 - 20.).hypot(y as f64 - 30.) / 60.).min(1.);
+			image::Rgba([lerp(255., 20., t), lerp(240., 10., t), lerp(200., 60., t), 255])
+		});
+		let palette: DynamicPaletteView = generate_palette_median_cut(&img, 64);
+		let analyze = |size| {
+			let small = image::imageops::resize(&img, size, size, image::imageops::FilterType::Triangle);
+			let mut tree = QuadtreeNode::default();
+			tree.from_image_with(&small, &palette, &AnalyzeOptions::default().blur(0.)).unwrap();
+			tree
+		};
+		let render = |tree: &QuadtreeNode<DynamicPaletteView>, upscale| {
+			let options = RenderOptions { gradient: upscale == UpscaleMode::Nearest, upscale, ..Default::default() };
+			let mut out = image::RgbaImage::new(64, 64);
+			tree.to_image_with(&mut out, &palette, &options).unwrap();
+			out
+		};
+		let truth = render(&analyze(64), UpscaleMode::Nearest);
+		for size in [8, 16, 32] {
+			let coarse = analyze(size);
+			let error = |upscale| squared_error(&truth, &render(&coarse, upscale));
+			let (nearest, smooth) = (error(UpscaleMode::Nearest), error(UpscaleMode::Smooth));
+			assert!(smooth < nearest, "{}x{}: smooth {} vs nearest {}", size, size, smooth, nearest);
+		}
+	}
+
+	#[test]
+	fn mesh_fits_gradients_more_closely() {
+		// A linear gradient in four bands of color, drawn from a palette of
+		// every gray, so that the square becomes a single gradient
+		let band = |x: u32| 40 + 50 * (x / 4) as u8;
+		let img = image::RgbaImage::from_fn(16, 16, |x, _| image::Rgba([band(x), band(x), band(x), 255]));
+		let palette = DynamicPaletteView::from((0..=255).map(|v| image::Rgba([v, v, v, 255])).collect::<Vec<_>>());
+		let error = |fit| {
+			let mut tree = QuadtreeNode::default();
+			let options = AnalyzeOptions { blur: 0., gradient_fit: Some(fit), ..Default::default() };
+			tree.from_image_with(&img, &palette, &options).unwrap();
+			assert_eq!(tree.depth(), 1);
+			squared_error(&img, &draw(&tree, &palette, 16, true))
+		};
+		let (corners, mesh) = (error(GradientFit::Corners), error(GradientFit::Mesh));
+		assert!(mesh < corners, "mesh {} vs corners {}", mesh, corners);
+	}
+
+	/// Draws `node` as `to_image` first did: painting every node's square
+	/// whole, then drawing its subsections over it.
+	fn reference_draw(
+		node: &QuadtreeNode<DynamicPaletteView>,
+		img: &mut image::RgbaImage,
+		palette: &DynamicPaletteView,
+		size: u32,
+		pos: (u32, u32),
+		gradient: bool
+	) {
+		let c = palette.to_rgba(node.color).unwrap();
+		image::imageops::replace(img, &image::RgbaImage::from_pixel(size, size, c), pos.0, pos.1);
+		let sects = match node.sections {
+			Some(ref sects) if size > 1 => sects,
+			_ => return
+		};
+		if gradient && sects.iter().all(|s| s.sections.is_none()) {
+			let colors = sects.iter().map(|s| palette.to_rgba(s.color).unwrap()).collect::<Vec<_>>();
+			for row in pos.1..pos.1 + size {
+				for col in pos.0..pos.0 + size {
+					let x_n = (col - pos.0) as f64 / size as f64;
+					let y_n = (row - pos.1) as f64 / size as f64;
+					let c = color_lerp(color_lerp(colors[0], colors[1], x_n), color_lerp(colors[2], colors[3], x_n), y_n);
+					img.put_pixel(col, row, c);
+				}
+			}
+		} else {
+			let half = size / 2;
+			for (ind, section) in sects.iter().enumerate() {
+				let section_pos = (pos.0 + (ind as u32 & 1) * half, pos.1 + (ind as u32 >> 1) * half);
+				reference_draw(section, img, palette, half, section_pos, gradient);
+			}
+		}
+	}
+
+	#[test]
+	fn drawing_matches_reference() {
+		let img = test_image(64, 5);
+		let palette: DynamicPaletteView = generate_palette_median_cut(&img, 16);
+		let mut tree = QuadtreeNode::default();
+		tree.from_image_with(&img, &palette, &Default::default()).unwrap();
+		assert!(tree.depth() >= 5);
+		for gradient in [false, true] {
+			// Smaller than, the same size as and larger than the tree's own
+			for size in [16, 64, 256] {
+				let mut reference = image::RgbaImage::new(size, size);
+				reference_draw(&tree, &mut reference, &palette, size, (0, 0), gradient);
+				assert!(draw(&tree, &palette, size, gradient) == reference, "{} pixels, gradient {}", size, gradient);
+			}
+			// Into part of a larger image
+			let mut drawn = img.clone();
+			tree.to_image(&mut drawn, &palette, Some(32), Some((32, 0)), gradient, None).unwrap();
+			let mut reference = img.clone();
+			reference_draw(&tree, &mut reference, &palette, 32, (32, 0), gradient);
+			assert!(drawn == reference);
+		}
+	}
+}
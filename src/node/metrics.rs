@@ -0,0 +1,261 @@
+use super::error::*;
+use super::quantize::{self, DistanceMetric};
+use super::quantize::palette::{Color, Palette};
+
+/// How the alpha channel is treated when comparing images.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlphaMode {
+	/// Alpha is compared like the color channels, but with the given weight
+	/// relative to each of them; `Weighted(1.)` treats all four channels
+	/// equally.
+	Weighted(f32),
+	/// The color channels are multiplied by alpha before comparison, so
+	/// that differences in the color of transparent pixels don't count.
+	PremultiplyFirst,
+	/// Pixels that are fully transparent in both images are left out.
+	IgnoreTransparentPixels,
+}
+
+impl Default for AlphaMode {
+	fn default() -> Self {
+		AlphaMode::Weighted(1.)
+	}
+}
+
+/// Options for the comparisons in this module.
+#[derive(Clone, Debug, Default)]
+pub struct MetricOptions {
+	pub alpha_mode: AlphaMode,
+}
+
+/// Weights of the red, green, blue and alpha channels.
+fn channel_weights(options: &MetricOptions) -> [f64; 4] {
+	match options.alpha_mode {
+		AlphaMode::Weighted(w) => [1., 1., 1., w as f64],
+		_ => [1.; 4],
+	}
+}
+
+/// A pair of pixels to compare, as channel values ready for comparison.
+type PixelPair = ([f64; 4], [f64; 4]);
+
+/// Pairs of pixels to compare, by row; pixels that are left out are `None`.
+fn prepared_pixels(
+	a: &image::RgbaImage,
+	b: &image::RgbaImage,
+	options: &MetricOptions
+) -> Result<Vec<Option<PixelPair>>, CompareError> {
+	if a.dimensions() != b.dimensions() {
+		return Err(CompareError::DimensionMismatch);
+	}
+	let prepare = |c: &Color| {
+		let mut v = [c.0[0] as f64, c.0[1] as f64, c.0[2] as f64, c.0[3] as f64];
+		if options.alpha_mode == AlphaMode::PremultiplyFirst {
+			for channel in v.iter_mut().take(3) {
+				*channel *= c.0[3] as f64 / 255.;
+			}
+		}
+		v
+	};
+	Ok(a.pixels().zip(b.pixels())
+		.map(|(pa, pb)| match options.alpha_mode {
+			AlphaMode::IgnoreTransparentPixels if pa.0[3] == 0 && pb.0[3] == 0 => None,
+			_ => Some((prepare(pa), prepare(pb)))
+		})
+		.collect())
+}
+
+/// Mean squared error between two images of the same dimensions, per
+/// (weighted) channel, on a scale of 0 to 255 squared.
+///
+/// Gives 0 if every pixel is left out.
+pub fn mse(
+	a: &image::RgbaImage,
+	b: &image::RgbaImage,
+	options: &MetricOptions
+) -> Result<f64, CompareError> {
+	let weights = channel_weights(options);
+	let weight_sum = weights.iter().sum::<f64>();
+	let (total, count) = prepared_pixels(a, b, options)?.iter()
+		.flatten()
+		.map(|(pa, pb)| (0..4)
+			.map(|ch| weights[ch] * (pa[ch] - pb[ch]).powi(2))
+			.sum::<f64>() / weight_sum)
+		.fold((0., 0usize), |(t, n), e| (t + e, n + 1));
+	Ok(if count == 0 { 0. } else { total / count as f64 })
+}
+
+/// Peak signal-to-noise ratio between two images of the same dimensions, in
+/// decibels; infinite for identical images.
+pub fn psnr(
+	a: &image::RgbaImage,
+	b: &image::RgbaImage,
+	options: &MetricOptions
+) -> Result<f64, CompareError> {
+	Ok(10. * (255f64.powi(2) / mse(a, b, options)?).log10())
+}
+
+/// Side length of the windows that `ssim` compares.
+const SSIM_WINDOW: u32 = 8;
+
+/// Structural similarity between two images of the same dimensions, from -1
+/// to 1 (identical); the mean over 8×8 windows of the (weighted) mean over
+/// channels.
+///
+/// Windows with every pixel left out are skipped; gives 1 if every pixel is
+/// left out.
+pub fn ssim(
+	a: &image::RgbaImage,
+	b: &image::RgbaImage,
+	options: &MetricOptions
+) -> Result<f64, CompareError> {
+	// Standard stabilizing constants for a range of 255
+	const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+	const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+	let pixels = prepared_pixels(a, b, options)?;
+	let weights = channel_weights(options);
+	let weight_sum = weights.iter().sum::<f64>();
+	let (width, height) = a.dimensions();
+	let mut total = 0.;
+	let mut windows = 0;
+	for win_y in (0..height).step_by(SSIM_WINDOW as usize) {
+		for win_x in (0..width).step_by(SSIM_WINDOW as usize) {
+			let window = (win_y..std::cmp::min(win_y + SSIM_WINDOW, height))
+				.flat_map(|y| (win_x..std::cmp::min(win_x + SSIM_WINDOW, width))
+					.map(move |x| (y * width + x) as usize))
+				.filter_map(|ind| pixels[ind])
+				.collect::<Vec<_>>();
+			if window.is_empty() {
+				continue;
+			}
+			let n = window.len() as f64;
+			let mut similarity = 0.;
+			for (ch, weight) in weights.iter().enumerate() {
+				let mean_a = window.iter().map(|p| p.0[ch]).sum::<f64>() / n;
+				let mean_b = window.iter().map(|p| p.1[ch]).sum::<f64>() / n;
+				let (mut var_a, mut var_b, mut cov) = (0., 0., 0.);
+				for (pa, pb) in window.iter() {
+					var_a += (pa[ch] - mean_a).powi(2) / n;
+					var_b += (pb[ch] - mean_b).powi(2) / n;
+					cov += (pa[ch] - mean_a) * (pb[ch] - mean_b) / n;
+				}
+				similarity += weight *
+					((2. * mean_a * mean_b + C1) * (2. * cov + C2)) /
+					((mean_a.powi(2) + mean_b.powi(2) + C1) * (var_a + var_b + C2));
+			}
+			total += similarity / weight_sum;
+			windows += 1;
+		}
+	}
+	Ok(if windows == 0 { 1. } else { total / windows as f64 })
+}
+
+/// Splits the error of `tree` as an encoding of `original` by where it comes
+/// from, as `(quantization, subdivision)`: the `mse` of the image with each
+/// pixel matched to its closest palette color (by `metric`) against
+/// `original`, and of the tree (rendered at the size of `original`, with
+/// `gradient` as in `to_image`) against that quantized image.
+///
+/// The two add up to roughly the error of the tree against `original`. Blur
+/// and trimming count as subdivision error; a large quantization error calls
+/// for a bigger or better palette, and a large subdivision error for a
+/// higher sensitivity or less trimming.
+///
+/// Will return an `Err` if `original` can't be drawn into (see `to_image`),
+/// or if the tree has a color outside the palette.
+pub fn error_breakdown<P: Palette + Default>(
+	original: &image::RgbaImage,
+	palette: &P,
+	tree: &super::QuadtreeNode<P>,
+	gradient: bool,
+	metric: DistanceMetric,
+	options: &MetricOptions
+) -> Result<(f64, f64), DrawError> {
+	let mut rendered = image::RgbaImage::new(original.width(), original.height());
+	tree.to_image(&mut rendered, palette, None, None, gradient, None)?;
+	let indices = quantize::quantize_to_palette(original, palette, metric);
+	let quantized = image::RgbaImage::from_fn(original.width(), original.height(), |x, y|
+		palette.to_rgba(indices[(y * original.width() + x) as usize]).unwrap_or(image::Rgba([0; 4])));
+	// The images all have the same dimensions, so comparison cannot fail
+	Ok((
+		mse(original, &quantized, options).unwrap(),
+		mse(&quantized, &rendered, options).unwrap()
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::image::AnalyzeOptions;
+	use crate::quantize::generate_palette_median_cut;
+	use crate::quantize::palette::DynamicPaletteView;
+	use crate::test_util::test_image;
+	use crate::QuadtreeNode;
+
+	/// A 16 by 16 image, opaque on the left and with `right` (of the given
+	/// alpha) on the right.
+	fn half_covered(right: [u8; 3], right_alpha: u8, red_shift: u8) -> image::RgbaImage {
+		image::RgbaImage::from_fn(16, 16, |x, y| match x {
+			0..=7 => image::Rgba([(x * 16) as u8 + red_shift, (y * 16) as u8, 100, 255]),
+			_ => image::Rgba([right[0], right[1], right[2], right_alpha]),
+		})
+	}
+
+	#[test]
+	fn alpha_modes_rank_transparent_differences() {
+		let reference = half_covered([10, 20, 30], 0, 0);
+		// Differs only in the colors of transparent pixels
+		let hidden = half_covered([250, 0, 200], 0, 0);
+		// Differs slightly in the opaque pixels
+		let shifted = half_covered([10, 20, 30], 0, 8);
+		let options = |alpha_mode| MetricOptions { alpha_mode };
+
+		for weight in [1., 0.] {
+			let weighted = options(AlphaMode::Weighted(weight));
+			assert!(mse(&reference, &hidden, &weighted).unwrap() > mse(&reference, &shifted, &weighted).unwrap());
+			assert!(ssim(&reference, &hidden, &weighted).unwrap() < 1.);
+		}
+		for mode in [AlphaMode::PremultiplyFirst, AlphaMode::IgnoreTransparentPixels] {
+			let options = options(mode);
+			assert_eq!(mse(&reference, &hidden, &options).unwrap(), 0.);
+			assert_eq!(psnr(&reference, &hidden, &options).unwrap(), f64::INFINITY);
+			assert!((ssim(&reference, &hidden, &options).unwrap() - 1.).abs() < 1e-9);
+			assert!(mse(&reference, &shifted, &options).unwrap() > 0.);
+		}
+
+		// Nearly transparent pixels still count unless premultiplied
+		let faint = half_covered([10, 20, 30], 1, 0);
+		let faint_hidden = half_covered([250, 0, 200], 1, 0);
+		let premultiplied = mse(&faint, &faint_hidden, &options(AlphaMode::PremultiplyFirst)).unwrap();
+		let ignored = mse(&faint, &faint_hidden, &options(AlphaMode::IgnoreTransparentPixels)).unwrap();
+		assert!(premultiplied > 0. && premultiplied < 1.);
+		assert!(ignored > mse(&reference, &shifted, &options(AlphaMode::IgnoreTransparentPixels)).unwrap());
+
+		// A pixel transparent in only one image is compared
+		let uncovered = half_covered([10, 20, 30], 255, 0);
+		assert!(mse(&reference, &uncovered, &options(AlphaMode::IgnoreTransparentPixels)).unwrap() > 0.);
+	}
+
+	#[test]
+	fn error_components_add_up() {
+		let img = test_image(64, 7);
+		let palette: DynamicPaletteView = generate_palette_median_cut(&img, 16);
+		for (sensitivity, gradient) in [(16128, false), (16128, true), (4096, false)] {
+			let options = AnalyzeOptions { sensitivity, gradient_fit: gradient.then_some(Default::default()), ..Default::default() };
+			let mut tree = QuadtreeNode::default();
+			tree.from_image_with(&img, &palette, &options).unwrap();
+			let metric_options = MetricOptions::default();
+			let (quantization, subdivision) =
+				error_breakdown(&img, &palette, &tree, gradient, Default::default(), &metric_options).unwrap();
+			let mut rendered = image::RgbaImage::new(64, 64);
+			tree.to_image(&mut rendered, &palette, None, None, gradient, None).unwrap();
+			let total = mse(&img, &rendered, &metric_options).unwrap();
+			// The errors are squared distances, so they only add up exactly
+			// where they are at right angles; otherwise, by Cauchy–Schwarz,
+			// they are off by at most twice their geometric mean
+			let sum = quantization + subdivision;
+			assert!((total - sum).abs() <= 2. * (quantization * subdivision).sqrt(), "{} against {}", total, sum);
+			assert!((total - sum).abs() < 0.3 * sum, "{} against {}", total, sum);
+		}
+	}
+}
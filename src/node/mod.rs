@@ -13,24 +13,208 @@ pub mod quantize;
 pub struct QuadtreeNode<P: quantize::palette::Palette + Default> {
 	pub color: u32,
 	pub sections: Option<Box<[QuadtreeNode<P>; 4]>>,
-	_pal: std::marker::PhantomData<P>
+	// Trees hold no palette, so they can go between threads (as `mount`
+	// sends them with the `rayon` feature) whatever the palette type
+	_pal: std::marker::PhantomData<fn() -> P>
 }
 
+/// Compares the trees' structure and color numbers (not the colors they
+/// stand for, as the palette isn't part of the tree).
+impl<P: quantize::palette::Palette + Default> PartialEq for QuadtreeNode<P> {
+	fn eq(&self, other: &Self) -> bool {
+		self.color == other.color && self.sections == other.sections
+	}
+}
+
+impl<P: quantize::palette::Palette + Default> Eq for QuadtreeNode<P> {}
+
+/// Path from a node to one below it: the index (0 to 3) of the subsection
+/// to take at each level, in the same order as `sections`.
+pub type TreePath = Vec<u8>;
+
+/// How `mount` picks the colors of the four leaves of a branch that is to be
+/// rendered as a gradient between them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum GradientFit {
+	/// The most common color near each corner of the branch's square.
+	#[default]
+	Corners,
+	/// The colors whose bilinear interpolation (as drawn by `to_image` with
+	/// `gradient`) best fits the square, by least squares, each then
+	/// rounded to the closest palette color.
+	Mesh,
+}
+
+/// Smallest square that `mount` splits among threads, with the `rayon`
+/// feature.
+#[cfg(feature = "rayon")]
+const PARALLEL_MOUNT_MIN_SIZE: usize = 64;
+
 impl<P: quantize::palette::Palette + Default> QuadtreeNode<P> {
-	/// Takes a "square" of color numbers to match the given palette
-	/// and arranges it into an efficient quadtree.
-	///
-	/// The "square" must be a `Vec<u32>` with the length being a power of 4.
-	/// This is because powers of 4 are squares of powers of 2.
+	/// The number of levels of nodes below this one; 0 for a leaf node.
+	pub fn depth(&self) -> usize {
+		self.sections.as_ref()
+			.map_or(0, |s| 1 + s.iter().map(QuadtreeNode::depth).max().unwrap_or(0))
+	}
+
+	/// The number of nodes in the tree, branches and leaves alike; each takes
+	/// one bit plus the palette width to encode.
+	pub fn node_count(&self) -> usize {
+		1 + self.sections.as_ref()
+			.map_or(0, |s| s.iter().map(QuadtreeNode::node_count).sum())
+	}
+
+	/// The number of leaf nodes in the tree.
+	pub fn leaf_count(&self) -> usize {
+		self.sections.as_ref()
+			.map_or(1, |s| s.iter().map(QuadtreeNode::leaf_count).sum())
+	}
+
+	/// The number of nodes at each level of the tree, from this node's (1)
+	/// down to the deepest; `depth() + 1` entries.
+	pub fn nodes_by_depth(&self) -> Vec<usize> {
+		let mut counts = Vec::new();
+		self.count_by_depth(0, &mut counts);
+		counts
+	}
+
+	/// Helper for `nodes_by_depth`.
+	fn count_by_depth(&self, depth: usize, counts: &mut Vec<usize>) {
+		if counts.len() <= depth {
+			counts.push(0);
+		}
+		counts[depth] += 1;
+		if let Some(ref sects) = self.sections {
+			sects.iter().for_each(|s| s.count_by_depth(depth + 1, counts));
+		}
+	}
+
+	/// The distinct color numbers of the nodes in the tree, branches
+	/// (whose colors are drawn in previews and gradients) as well as
+	/// leaves.
+	pub fn colors_used(&self) -> std::collections::BTreeSet<u32> {
+		let mut colors = std::collections::BTreeSet::new();
+		self.collect_colors(&mut colors);
+		colors
+	}
+
+	/// Helper for `colors_used`.
+	fn collect_colors(&self, colors: &mut std::collections::BTreeSet<u32>) {
+		colors.insert(self.color);
+		if let Some(ref sects) = self.sections {
+			sects.iter().for_each(|s| s.collect_colors(colors));
+		}
+	}
+
+	/// The number of nodes that `normalize` would remove: those below
+	/// branches that come to have four leaves of one color. Each takes as
+	/// many bits to encode as any other node, for nothing that a leaf
+	/// wouldn't show.
+	pub fn redundant_node_count(&self) -> usize {
+		let mut count = 0;
+		self.normalized_color(&mut count);
+		count
+	}
+
+	/// Helper for `redundant_node_count`; the color of this node if it
+	/// would be a leaf after `normalize`, adding the nodes that would be
+	/// removed from below it to `count`.
+	fn normalized_color(&self, count: &mut usize) -> Option<u32> {
+		let sects = match self.sections {
+			Some(ref sects) => sects,
+			None => return Some(self.color)
+		};
+		let colors = sects.iter().map(|s| s.normalized_color(count)).collect::<Vec<_>>();
+		match colors[0] {
+			Some(color) if colors.iter().all(|c| *c == Some(color)) => {
+				*count += 4;
+				Some(color)
+			},
+			_ => None
+		}
+	}
+
+	/// Hashes the structure of the tree along with the RGBA colors its nodes
+	/// resolve to in `palette`, so that the same tree stored against palettes
+	/// in different orders gives the same hash.
 	///
-	/// `sensitivity` should be a number from 0 to 16384 indicating how much
-	/// of a square (fraction out of 16384) must be the same color in order
-	/// to disregard subesctions. If you don't know what you're doing, pass
-	/// 16384.
+	/// Colors out of the range of the palette are hashed as a distinct value
+	/// rather than causing an error.
+	pub fn visual_hash(&self, palette: &P) -> u64 {
+		use std::hash::Hasher;
+		let mut hasher = std::collections::hash_map::DefaultHasher::new();
+		self.visual_hash_into(palette, &mut hasher);
+		hasher.finish()
+	}
+
+	/// Recursive helper for `visual_hash`.
+	fn visual_hash_into<H: std::hash::Hasher>(&self, palette: &P, hasher: &mut H) {
+		use std::hash::Hash;
+		palette.to_rgba(self.color).ok().map(|c| c.0).hash(hasher);
+		self.sections.is_some().hash(hasher);
+		if let Some(ref sects) = self.sections {
+			for section in sects.iter() {
+				section.visual_hash_into(palette, hasher);
+			}
+		}
+	}
+
+	/// Copies the tree onto a different palette, replacing each color
+	/// number with that of the closest color in `to` (an exact match, if
+	/// there is one), by squared Euclidean distance in RGBA.
 	///
-	/// For outside callers: leave `size` and `start_pos` as `None`.
+	/// Will return an `Err` if a color in the tree can't be looked up in
+	/// `from`, or if `to` has no colors.
+	#[doc(alias = "remap")]
+	pub fn reindex_to_palette<Q: quantize::palette::Palette + Default>(
+		&self,
+		from: &P,
+		to: &Q
+	) -> Result<QuadtreeNode<Q>, error::ReindexError> {
+		if to.is_empty() {
+			return Err(error::ReindexError::EmptyPalette);
+		}
+		let nearest = quantize::NearestColor::for_palette(to, Default::default());
+		self.reindex_with(from, &nearest, &mut std::collections::HashMap::new())
+	}
+
+	/// Helper for `reindex_to_palette`; `cache` maps color numbers in the
+	/// old palette to the new one.
+	fn reindex_with<Q: quantize::palette::Palette + Default>(
+		&self,
+		from: &P,
+		nearest: &quantize::NearestColor,
+		cache: &mut std::collections::HashMap<u32, u32>
+	) -> Result<QuadtreeNode<Q>, error::ReindexError> {
+		let color = match cache.get(&self.color) {
+			Some(c) => *c,
+			None => {
+				let rgba = from.to_rgba(self.color)
+					.map_err(|_| error::ReindexError::ColorOutOfRange)?;
+				let c = nearest.find(&rgba);
+				cache.insert(self.color, c);
+				c
+			}
+		};
+		let sections = match self.sections {
+			Some(ref sects) => {
+				let mut new_sects: Box<[QuadtreeNode<Q>; 4]> = Default::default();
+				for (new, old) in new_sects.iter_mut().zip(sects.iter()) {
+					*new = old.reindex_with(from, nearest, cache)?;
+				}
+				Some(new_sects)
+			},
+			None => None
+		};
+		Ok(QuadtreeNode { color, sections, _pal: std::marker::PhantomData })
+	}
+
+	/// Like `mount_with`, with the settings of `AnalyzeOptions` given
+	/// separately, and optionally for just the square of `size` at
+	/// `start_pos` (`None` for the whole square).
 	///
-	/// `gradient` has a similar meaning as it does for `from_image`.
+	/// `gradient` picks gradients with `GradientFit::Corners`.
+	#[deprecated(note = "use `mount_with` and `AnalyzeOptions` instead")]
 	pub fn mount(
 		&mut self,
 		image: &[u32],
@@ -45,64 +229,150 @@ impl<P: quantize::palette::Palette + Default> QuadtreeNode<P> {
 		}
 		// Square root
 		let row_len = image.len() >> (image.len().trailing_zeros() >> 1);
-		// Find most common color in corresponding section.
-		let size = size.unwrap_or(row_len);
-		let start_pos = start_pos.unwrap_or((0, 0));
-		let abundance_map = (start_pos.1..start_pos.1 + size).flat_map(|row| image[
-			(row * row_len + start_pos.0)..(row * row_len + start_pos.0 + size)
-			].iter())
-			.fold(std::collections::HashMap::new(), |mut h, n| {
-				*h.entry(n).or_insert(0) += 1isize;
-				h
-			});
-		let mut abundance_sort = abundance_map.iter()
-			.map(|e| (-e.1, e.0))
-			.collect::<Vec<_>>();
-		abundance_sort.sort();
-		let abundance_res = abundance_sort[0];
-		self.color = **abundance_res.1;
-		// Validate color. This should be validated for every pixel, but
-		// due to recursion that goes down through every pixel, it will be handled.
-		if self.color > 1 << palette.width() {
-			return Err(error::MountError::ColorOutOfRange);
+		// The region must be a nonempty square within the whole one
+		let (size, start_pos) = (size.unwrap_or(row_len), start_pos.unwrap_or((0, 0)));
+		if !size.is_power_of_two() || start_pos.0 + size > row_len || start_pos.1 + size > row_len {
+			return Err(error::MountError::InvalidSize);
+		}
+		let context = MountContext::new(image, row_len, palette, &image::AnalyzeOptions {
+			sensitivity,
+			gradient_fit: if gradient { Some(GradientFit::Corners) } else { None },
+			..Default::default()
+		});
+		self.mount_region(&context, size, start_pos, &mut Histogram::new(palette.width()))?;
+		if gradient {
+			self.normalize_gradients();
+		} else {
+			self.normalize();
 		}
+		Ok(())
+	}
+
+	/// Takes a "square" of color numbers to match the given palette
+	/// and arranges it into an efficient quadtree, with the settings in
+	/// `options` (apart from `blur` and `metric`, which are for
+	/// `from_image_with`).
+	///
+	/// The "square" must be a slice with the length being a power of 4.
+	/// This is because powers of 4 are squares of powers of 2.
+	///
+	/// The tree is `normalize`d, or with gradients, `normalize_gradients`d,
+	/// so that no branch has four leaves of one color where a leaf of that
+	/// color would be drawn the same.
+	///
+	/// Every color number is checked against the palette width, as in
+	/// `encode_v1`, so `1 << width` itself is rejected along with anything
+	/// above it.
+	pub fn mount_with(
+		&mut self,
+		image: &[u32],
+		palette: &P,
+		options: &image::AnalyzeOptions
+	) -> Result<(), error::MountError> {
+		if !image.len().is_power_of_two() || image.len().trailing_zeros() % 2 == 1 {
+			return Err(error::MountError::InvalidSize);
+		}
+		let row_len = image.len() >> (image.len().trailing_zeros() >> 1);
+		let context = MountContext::new(image, row_len, palette, options);
+		self.mount_region(&context, row_len, (0, 0), &mut Histogram::new(palette.width()))?;
+		if options.gradient_fit.is_some() {
+			self.normalize_gradients();
+		} else {
+			self.normalize();
+		}
+		Ok(())
+	}
+
+	/// Helper for `mount`; mounts the square of `size` at `start_pos`,
+	/// counting colors with `histogram`, which is left empty.
+	fn mount_region(
+		&mut self,
+		context: &MountContext,
+		size: usize,
+		start_pos: (usize, usize),
+		histogram: &mut Histogram
+	) -> Result<(), error::MountError> {
+		self.split_region(context, size, start_pos, histogram)?;
+		// Snap subsections close enough to this node's color into it
+		if let (Some(tolerance), Some(sections)) = (context.snap, &self.sections) {
+			let color = context.color(self.color);
+			if sections.iter().all(|s| s.sections.is_none() &&
+					quantize::DistanceMetric::Euclidean.distance(&context.color(s.color), &color) <= tolerance) {
+				self.sections = None;
+			}
+		}
+		Ok(())
+	}
+
+	/// Helper for `mount_region`; picks the color of the square and, if it
+	/// isn't uniform enough, its subsections.
+	fn split_region(
+		&mut self,
+		context: &MountContext,
+		size: usize,
+		start_pos: (usize, usize),
+		histogram: &mut Histogram
+	) -> Result<(), error::MountError> {
+		// A single pixel is a leaf of its own color, with nothing to count
+		if size == 1 {
+			let color = context.image[start_pos.1 * context.row_len + start_pos.0];
+			if color as u64 >= 1 << context.width as u64 {
+				return Err(error::MountError::ColorOutOfRange);
+			}
+			self.color = color;
+			return Ok(());
+		}
+		// Find most common colors in corresponding section.
+		let abundance_four = histogram.top_four(context, size, start_pos)?;
+		self.color = abundance_four[0].1;
+		// Squares at the depth limit are leaves, whatever their colors
+		let depth = (context.row_len / size).trailing_zeros();
+		if context.max_depth.is_some_and(|max_depth| depth >= max_depth as u32) {
+			return Ok(());
+		}
+		let sensitivity = context.sensitivity(depth as usize);
 		// Recursion
-		if size > 1 && (-abundance_res.0 as usize) < (sensitivity * size * size) / 16384 {
+		if size > 1 && abundance_four[0].0 < (sensitivity * size * size) / 16384 {
 			self.sections = Some(Default::default());
-			let abundance_four = abundance_sort.iter().chain(std::iter::repeat(&(0, &&0)).take(4)).take(4);
-			if gradient && size > 2 && abundance_four.map(|x| if -x.0 as usize > (sensitivity * size * size) / 65536
-					{ -x.0 as usize } else { 0 }).sum::<usize>() > (sensitivity * size * size) / 16384 {
+			if context.gradient_fit.is_some() && size > 2 && abundance_four.iter().map(|x| if x.0 > (sensitivity * size * size) / 65536
+					{ x.0 } else { 0 }).sum::<usize>() > (sensitivity * size * size) / 16384 {
+				if let Some(ref mesh) = context.mesh {
+					let colors = mesh.fit(context, size, start_pos);
+					for (section, color) in self.sections.as_mut().unwrap().iter_mut().zip(colors) {
+						section.color = color;
+					}
+					return Ok(());
+				}
 				for sect_ind in 0..4 {
 					let off = size / 4;
 					let x_off = (sect_ind & 1) * 6 * off / 2;
 					let y_off = (sect_ind & 2) * 3 * off / 2;
-					let mut abundance_sort = ((start_pos.1 + y_off)..(start_pos.1 + y_off + off)).flat_map(|row| image[
-						(row * row_len + start_pos.0 + x_off)..(row * row_len + start_pos.0 + x_off + off)
-						].iter())
-						.fold(std::collections::HashMap::new(), |mut h, n| {
-							*h.entry(n).or_insert(0) += 1;
-							h
-						})
-						.into_iter()
-						.map(|e| (-e.1, e.0))
-						.collect::<Vec<_>>();
-					abundance_sort.sort();
-					self.sections.as_mut().unwrap()[sect_ind].color = *abundance_sort[0].1;
+					self.sections.as_mut().unwrap()[sect_ind].color = histogram
+						.top_four(context, off, (start_pos.0 + x_off, start_pos.1 + y_off))?[0].1;
 				}
 			} else {
-				for sect_ind in 0..4 {
-					self.sections.as_mut().unwrap()[sect_ind]
-						.mount(
-							image,
-							palette,
-							Some(size / 2),
-							Some((
-								start_pos.0 + (sect_ind & 1) * (size / 2),
-								start_pos.1 + (sect_ind >> 1) * (size / 2),
-							)),
-							sensitivity,
-							gradient
-						)?;
+				let section_pos = |sect_ind: usize| (
+					start_pos.0 + (sect_ind & 1) * (size / 2),
+					start_pos.1 + (sect_ind >> 1) * (size / 2),
+				);
+				let sections = self.sections.as_mut().unwrap();
+				#[cfg(feature = "rayon")]
+				{
+					use rayon::prelude::*;
+					// The quadrants are independent; smaller ones aren't
+					// worth the overhead of splitting up
+					if size >= context.parallel_min_size {
+						return sections.par_iter_mut().enumerate()
+							.try_for_each(|(sect_ind, section)| section.mount_region(
+								context,
+								size / 2,
+								section_pos(sect_ind),
+								&mut Histogram::new(context.width)
+							));
+					}
+				}
+				for (sect_ind, section) in sections.iter_mut().enumerate() {
+					section.mount_region(context, size / 2, section_pos(sect_ind), histogram)?;
 				}
 			}
 		}
@@ -110,5 +380,534 @@ impl<P: quantize::palette::Palette + Default> QuadtreeNode<P> {
 	}
 }
 
+/// The parts of a call to `mount` that stay the same through its recursion.
+struct MountContext<'a> {
+	image: &'a [u32],
+	row_len: usize,
+	/// The palette's width, as the palette itself isn't needed (nor sent
+	/// between threads).
+	width: u8,
+	sensitivity: usize,
+	/// Sensitivities by depth, overriding `sensitivity` if not empty.
+	sensitivity_by_depth: Vec<usize>,
+	gradient_fit: Option<GradientFit>,
+	snap: Option<u32>,
+	max_depth: Option<u8>,
+	/// Palette colors, if needed for `GradientFit::Mesh` or `snap`.
+	colors: Vec<quantize::palette::Color>,
+	/// Whether color numbers are colors themselves, rather than in `colors`.
+	direct: bool,
+	/// For `GradientFit::Mesh`.
+	mesh: Option<MeshFit>,
+	/// Smallest square split among threads; `PARALLEL_MOUNT_MIN_SIZE` but
+	/// in tests.
+	#[cfg(feature = "rayon")]
+	parallel_min_size: usize,
+}
+
+impl<'a> MountContext<'a> {
+	fn new<P: quantize::palette::Palette>(
+		image: &'a [u32],
+		row_len: usize,
+		palette: &P,
+		options: &image::AnalyzeOptions
+	) -> Self {
+		let is_mesh = options.gradient_fit == Some(GradientFit::Mesh);
+		let colors = if (is_mesh || options.snap.is_some()) && !palette.is_direct() {
+			quantize::palette_colors(palette)
+		} else {
+			Vec::new()
+		};
+		let mesh = if is_mesh {
+			let nearest = quantize::NearestColor::for_palette(palette, quantize::DistanceMetric::Euclidean);
+			Some(MeshFit { nearest })
+		} else {
+			None
+		};
+		MountContext {
+			image,
+			row_len,
+			width: palette.width(),
+			sensitivity: options.sensitivity,
+			sensitivity_by_depth: options.sensitivity_by_depth.clone().unwrap_or_default(),
+			gradient_fit: options.gradient_fit,
+			snap: options.snap,
+			max_depth: options.max_depth,
+			colors,
+			direct: palette.is_direct(),
+			mesh,
+			#[cfg(feature = "rayon")]
+			parallel_min_size: PARALLEL_MOUNT_MIN_SIZE,
+		}
+	}
+
+	/// The sensitivity for squares `depth` levels below the whole image.
+	fn sensitivity(&self, depth: usize) -> usize {
+		match self.sensitivity_by_depth.last() {
+			Some(last) => *self.sensitivity_by_depth.get(depth).unwrap_or(last),
+			None => self.sensitivity
+		}
+	}
+
+	/// The palette color of a color number; transparent if past the end
+	/// of the palette.
+	fn color(&self, n: u32) -> quantize::palette::Color {
+		if self.direct {
+			return ::image::Rgba(n.to_be_bytes());
+		}
+		self.colors.get(n as usize).copied().unwrap_or(::image::Rgba([0; 4]))
+	}
+}
+
+/// Closest-color search for fitting gradients with `GradientFit::Mesh`.
+struct MeshFit {
+	nearest: quantize::NearestColor,
+}
+
+impl MeshFit {
+	/// Fits the four leaf colors for the square of `size` (at least 2) at
+	/// `start_pos`.
+	fn fit(&self, context: &MountContext, size: usize, start_pos: (usize, usize)) -> [u32; 4] {
+		// Each pixel is drawn as the sum of the leaf colors weighted by
+		// `(1 - x) (1 - y)`, `x (1 - y)`, `(1 - x) y` and `x y`, with `x`
+		// and `y` from 0 to `(size - 1) / size`. These are products of the
+		// same two weights on each axis, so the matrix of the least-squares
+		// equations is that of one axis (inverted here) multiplied by itself.
+		let weights = |i: usize| [1. - i as f64 / size as f64, i as f64 / size as f64];
+		let (mut aa, mut ab, mut bb) = (0., 0., 0.);
+		for i in 0..size {
+			let [a, b] = weights(i);
+			aa += a * a;
+			ab += a * b;
+			bb += b * b;
+		}
+		let det = aa * bb - ab * ab;
+		let inverse = [[bb / det, -ab / det], [-ab / det, aa / det]];
+		let mut sums = [[0f64; 4]; 4];
+		for y in 0..size {
+			let row_start = (start_pos.1 + y) * context.row_len + start_pos.0;
+			for (x, color) in context.image[row_start..row_start + size].iter().enumerate() {
+				let color = context.color(*color);
+				let (w_x, w_y) = (weights(x), weights(y));
+				for (leaf, sum) in sums.iter_mut().enumerate() {
+					let weight = w_y[leaf >> 1] * w_x[leaf & 1];
+					for (channel, value) in sum.iter_mut().enumerate() {
+						*value += weight * color.0[channel] as f64;
+					}
+				}
+			}
+		}
+		let mut ret = [0; 4];
+		for (leaf, leaf_color) in ret.iter_mut().enumerate() {
+			let mut color = [0u8; 4];
+			for (channel, value) in color.iter_mut().enumerate() {
+				*value = (0..4)
+					.map(|other| inverse[leaf >> 1][other >> 1] * inverse[leaf & 1][other & 1] * sums[other][channel])
+					.sum::<f64>()
+					.round()
+					.clamp(0., 255.) as u8;
+			}
+			*leaf_color = self.nearest.find(&::image::Rgba(color));
+		}
+		ret
+	}
+}
+
+/// Most palette colors that `Histogram` counts in a `Vec`, rather than a
+/// `HashMap`.
+const HISTOGRAM_DENSE_LIMIT: usize = 1 << 16;
+
+/// Reusable counts of the color numbers in a region, for `mount`.
+struct Histogram {
+	/// Counts of the first `HISTOGRAM_DENSE_LIMIT` color numbers.
+	dense: Vec<usize>,
+	/// Counts of any others, for very wide palettes.
+	sparse: std::collections::HashMap<u32, usize>,
+	/// Color numbers with nonzero counts, in order of first appearance.
+	seen: Vec<u32>,
+}
+
+impl Histogram {
+	fn new(width: u8) -> Self {
+		Histogram {
+			dense: vec![0; std::cmp::min(1 << width as u64, HISTOGRAM_DENSE_LIMIT as u64) as usize],
+			sparse: Default::default(),
+			seen: Vec::new(),
+		}
+	}
+
+	/// Counts the colors in the square of `size` at `start_pos`, giving the
+	/// four most common as `(count, color)`, most common first (and lowest
+	/// color number first in case of ties), padded with `(0, 0)`.
+	///
+	/// Leaves the histogram empty.
+	fn top_four(
+		&mut self,
+		context: &MountContext,
+		size: usize,
+		start_pos: (usize, usize)
+	) -> Result<[(usize, u32); 4], error::MountError> {
+		for row in start_pos.1..start_pos.1 + size {
+			let row_start = row * context.row_len + start_pos.0;
+			for &color in context.image[row_start..row_start + size].iter() {
+				if color as u64 >= 1 << context.width as u64 {
+					self.clear();
+					return Err(error::MountError::ColorOutOfRange);
+				}
+				let count = match self.dense.get_mut(color as usize) {
+					Some(count) => count,
+					None => self.sparse.entry(color).or_insert(0)
+				};
+				if *count == 0 {
+					self.seen.push(color);
+				}
+				*count += 1;
+			}
+		}
+		let mut top = [(0, 0); 4];
+		for &color in self.seen.iter() {
+			let count = self.dense.get(color as usize).copied()
+				.unwrap_or_else(|| self.sparse[&color]);
+			// Insertion into `top`, which is ordered best first
+			let better = |other: &(usize, u32)| count > other.0 || (count == other.0 && color < other.1);
+			if let Some(ind) = top.iter().position(better) {
+				top[ind..].rotate_right(1);
+				top[ind] = (count, color);
+			}
+		}
+		self.clear();
+		Ok(top)
+	}
+
+	fn clear(&mut self) {
+		for color in self.seen.drain(..) {
+			if let Some(count) = self.dense.get_mut(color as usize) {
+				*count = 0;
+			}
+		}
+		self.sparse.clear();
+	}
+}
+
 pub mod image;
-pub mod qti;
\ No newline at end of file
+pub mod metrics;
+pub mod qti;
+mod tree;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::noise;
+	use quantize::palette::{Color, DynamicPaletteView, Palette};
+
+	/// `n` distinct opaque colors.
+	fn colors(n: usize) -> Vec<Color> {
+		(0..n).map(|c| ::image::Rgba([(c * 40) as u8, 255 - (c * 20) as u8, (c >> 2) as u8, 255])).collect()
+	}
+
+	/// A copy of `tree` with every color number passed through `f`.
+	fn renumbered<P: quantize::palette::Palette + Default>(tree: &QuadtreeNode<P>, f: &dyn Fn(u32) -> u32) -> QuadtreeNode<P> {
+		QuadtreeNode {
+			color: f(tree.color),
+			sections: tree.sections.as_ref().map(|s| Box::new([
+				renumbered(&s[0], f), renumbered(&s[1], f), renumbered(&s[2], f), renumbered(&s[3], f)
+			])),
+			_pal: std::marker::PhantomData
+		}
+	}
+
+	#[test]
+	fn visual_hash_ignores_palette_order() {
+		let palette = DynamicPaletteView::from(colors(6));
+		let mut tree = QuadtreeNode::default();
+		tree.mount_with(&noise(16, 6, 9), &palette, &image::AnalyzeOptions::default().gradient_fit(None)).unwrap();
+		let mut reversed = colors(6);
+		reversed.reverse();
+		let reversed = DynamicPaletteView::from(reversed);
+		let reindexed = renumbered(&tree, &|c| 5 - c);
+		assert_eq!(reindexed.visual_hash(&reversed), tree.visual_hash(&palette));
+
+		let mut changed = colors(6);
+		changed[tree.color as usize].0[0] ^= 1;
+		assert_ne!(tree.visual_hash(&DynamicPaletteView::from(changed)), tree.visual_hash(&palette));
+	}
+
+	#[test]
+	fn reindexing_picks_the_closest_colors() {
+		let from = DynamicPaletteView::from(colors(6));
+		let mounted = || {
+			let mut tree = QuadtreeNode::default();
+			tree.mount_with(&noise(16, 6, 10), &from, &image::AnalyzeOptions::default().gradient_fit(None)).unwrap();
+			tree
+		};
+		let tree = mounted();
+		let to_colors = vec![colors(6)[1], colors(6)[4], ::image::Rgba([0, 0, 0, 255])];
+		let to = DynamicPaletteView::from(to_colors.clone());
+		let reindexed = tree.reindex_to_palette(&from, &to).unwrap();
+		assert_eq!(reindexed.node_count(), tree.node_count());
+		let (mut drawn, mut expected) = (::image::RgbaImage::new(16, 16), ::image::RgbaImage::new(16, 16));
+		reindexed.to_image_with(&mut drawn, &to, &Default::default()).unwrap();
+		tree.to_image_with(&mut expected, &from, &Default::default()).unwrap();
+		for pixel in expected.pixels_mut() {
+			let distance = |c: &Color| quantize::DistanceMetric::Euclidean.distance(c, pixel);
+			*pixel = *to_colors.iter().min_by_key(|c| distance(c)).unwrap();
+		}
+		assert!(drawn == expected);
+
+		let mut out_of_range = mounted();
+		out_of_range.subtree_mut(&[2, 1]).unwrap().color = 6;
+		assert!(matches!(out_of_range.reindex_to_palette(&from, &to), Err(error::ReindexError::ColorOutOfRange)));
+		let empty = DynamicPaletteView::from(Vec::new());
+		assert!(matches!(tree.reindex_to_palette(&from, &empty), Err(error::ReindexError::EmptyPalette)));
+	}
+
+	#[test]
+	#[allow(deprecated)]
+	fn mount_matches_mount_with() {
+		let palette = DynamicPaletteView::from(colors(5));
+		let image = noise(32, 5, 20);
+		for (sensitivity, gradient) in [(16128, false), (16128, true), (8192, true)] {
+			let mut tree = QuadtreeNode::default();
+			tree.mount(&image, &palette, None, None, sensitivity, gradient).unwrap();
+			let mut expected = QuadtreeNode::default();
+			let gradient_fit = if gradient { Some(GradientFit::Corners) } else { None };
+			let options = image::AnalyzeOptions::default().sensitivity(sensitivity).gradient_fit(gradient_fit);
+			expected.mount_with(&image, &palette, &options).unwrap();
+			assert_eq!(tree, expected);
+		}
+	}
+
+	/// Checks that mounting accepts color number `last` of `palette`, and
+	/// rejects the next one, wherever it is in the square mounted.
+	#[allow(deprecated)]
+	fn check_mount_boundary<P: quantize::palette::Palette + Default>(palette: &P, last: u32) {
+		let mut image = vec![last; 16];
+		let options = image::AnalyzeOptions::default();
+		let mut tree = QuadtreeNode::<P>::default();
+		assert!(tree.mount_with(&image, palette, &options).is_ok());
+		assert!(tree.mount(&image, palette, None, None, 16128, true).is_ok());
+		let past = match last.checked_add(1) {
+			Some(past) => past,
+			None => return
+		};
+		image[15] = past;
+		assert!(matches!(tree.mount_with(&image, palette, &options), Err(error::MountError::ColorOutOfRange)));
+		assert!(matches!(tree.mount(&image, palette, None, None, 16128, false), Err(error::MountError::ColorOutOfRange)));
+		assert!(matches!(tree.mount(&image, palette, Some(2), Some((2, 2)), 16128, true), Err(error::MountError::ColorOutOfRange)));
+		assert!(matches!(tree.mount(&image, palette, Some(1), Some((3, 3)), 16128, true), Err(error::MountError::ColorOutOfRange)));
+		// Squares without it are fine
+		assert!(tree.mount(&image, palette, Some(2), Some((0, 2)), 16128, true).is_ok());
+		assert!(tree.mount(&image, palette, Some(1), Some((2, 3)), 16128, false).is_ok());
+	}
+
+	#[test]
+	fn mount_boundaries() {
+		check_mount_boundary(&quantize::palette::PaletteView1::default(), 1);
+		check_mount_boundary(&quantize::palette::PaletteView8::default(), 255);
+		// Mounting only checks the width, not the length of the list
+		check_mount_boundary(&DynamicPaletteView::from(colors(3)), 3);
+		check_mount_boundary(&quantize::palette::PaletteView::<32>::default(), u32::MAX);
+	}
+
+	#[test]
+	#[allow(deprecated)]
+	fn single_pixels_and_uniform_squares_are_leaves() {
+		let palette = DynamicPaletteView::from(colors(5));
+		let all_options = [
+			image::AnalyzeOptions::default(),
+			image::AnalyzeOptions::default().gradient_fit(None).snap(Some(100)),
+			image::AnalyzeOptions::default().gradient_fit(Some(GradientFit::Mesh)).max_depth(Some(0)),
+			image::AnalyzeOptions::default().sensitivity(16384),
+		];
+		let leaf = |color| QuadtreeNode::<DynamicPaletteView> { color, ..Default::default() };
+		for options in &all_options {
+			for image in [vec![4], vec![3; 4], vec![2; 64 * 64]] {
+				let mut tree = QuadtreeNode::default();
+				tree.mount_with(&image, &palette, options).unwrap();
+				assert_eq!(tree, leaf(image[0]));
+			}
+		}
+		// Each pixel of a square on its own, and the square
+		let image = [0, 1, 2, 3];
+		for (ind, &color) in image.iter().enumerate() {
+			let mut tree = QuadtreeNode::default();
+			tree.mount(&image, &palette, Some(1), Some((ind % 2, ind / 2)), 16128, true).unwrap();
+			assert_eq!(tree, leaf(color));
+		}
+		let mut tree = QuadtreeNode::default();
+		tree.mount_with(&image, &palette, &Default::default()).unwrap();
+		assert_eq!(tree, QuadtreeNode { color: 0, sections: Some(Box::new(image.map(leaf))), ..Default::default() });
+
+		// Nothing to mount
+		let mut tree = QuadtreeNode::default();
+		assert!(matches!(tree.mount_with(&[], &palette, &Default::default()), Err(error::MountError::InvalidSize)));
+		assert!(matches!(tree.mount(&image, &palette, Some(0), None, 16128, true), Err(error::MountError::InvalidSize)));
+	}
+
+	/// Colors (below 8) of the square of `size` at `pos` in `image` (of rows
+	/// of `row_len`), by count, most common first, each counted afresh.
+	fn reference_counts(image: &[u32], row_len: usize, size: usize, pos: (usize, usize)) -> Vec<(usize, u32)> {
+		let mut counts = (0..8).map(|color| (0, color)).collect::<Vec<_>>();
+		for row in pos.1..pos.1 + size {
+			for &color in image[row * row_len + pos.0..row * row_len + pos.0 + size].iter() {
+				counts[color as usize].0 += 1;
+			}
+		}
+		counts.sort_by_key(|&(count, color)| (std::cmp::Reverse(count), color));
+		counts
+	}
+
+	/// A plain recursive `mount` of the square of `size` at `pos`, with
+	/// `gradient` if `corners` (and without normalizing).
+	fn reference_mount(
+		image: &[u32],
+		row_len: usize,
+		size: usize,
+		pos: (usize, usize),
+		sensitivity: usize,
+		corners: bool
+	) -> QuadtreeNode<DynamicPaletteView> {
+		let counts = reference_counts(image, row_len, size, pos);
+		let mut node = QuadtreeNode { color: counts[0].1, ..Default::default() };
+		let threshold = sensitivity * size * size / 16384;
+		if size == 1 || counts[0].0 >= threshold {
+			return node;
+		}
+		let top_four = counts.iter().take(4).map(|c| c.0);
+		let fit_corners = corners && size > 2 &&
+			top_four.filter(|&count| count > sensitivity * size * size / 65536).sum::<usize>() > threshold;
+		let half = size / 2;
+		let sections = [0, 1, 2, 3].map(|ind: usize| if fit_corners {
+			let off = size / 4;
+			let corner = (pos.0 + (ind & 1) * 3 * off, pos.1 + (ind >> 1) * 3 * off);
+			QuadtreeNode { color: reference_counts(image, row_len, off, corner)[0].1, ..Default::default() }
+		} else {
+			let section_pos = (pos.0 + (ind & 1) * half, pos.1 + (ind >> 1) * half);
+			reference_mount(image, row_len, half, section_pos, sensitivity, corners)
+		});
+		node.sections = Some(Box::new(sections));
+		node
+	}
+
+	#[test]
+	fn mount_matches_reference() {
+		let palette = DynamicPaletteView::from(colors(8));
+		for (size, sensitivity, corners) in [(1024usize, 16128, false), (256, 16128, true), (256, 12288, true)] {
+			// Blocks of color, of every size down to single pixels
+			let levels = size.trailing_zeros() as usize;
+			let mut rng = crate::test_util::Lcg(13);
+			let blocks = (0..levels).map(|level| noise(1 << level, 8, level as u64)).collect::<Vec<_>>();
+			let image = (0..size * size).map(|ind| {
+				let level = rng.below(levels as u32) as usize;
+				let (x, y) = ((ind % size) >> (levels - level), (ind / size) >> (levels - level));
+				blocks[level][(y << level) + x]
+			}).collect::<Vec<_>>();
+
+			let mut tree = QuadtreeNode::default();
+			tree.mount_with(&image, &palette, &image::AnalyzeOptions::default().sensitivity(sensitivity).gradient_fit(corners.then_some(GradientFit::Corners))).unwrap();
+			let mut reference = reference_mount(&image, size, size, (0, 0), sensitivity, corners);
+			if corners {
+				reference.normalize_gradients();
+			} else {
+				reference.normalize();
+			}
+			assert!(tree.node_count() > 1000);
+			assert!(
+				tree == reference,
+				"{}x{}, sensitivity {}, corners {}", size, size, sensitivity, corners
+			);
+		}
+	}
+
+	#[test]
+	fn parallel_mounting_matches_serial() {
+		let palette = DynamicPaletteView::from(colors(6));
+		let quantized = noise(128, 6, 6);
+		let flat = image::AnalyzeOptions { gradient_fit: None, ..Default::default() };
+		let gradients = image::AnalyzeOptions { sensitivity: 12000, ..Default::default() };
+		for options in [
+			flat.clone(),
+			image::AnalyzeOptions { snap: Some(2000), ..flat },
+			gradients.clone(),
+			image::AnalyzeOptions { gradient_fit: Some(GradientFit::Mesh), ..gradients }
+		] {
+			// As `mount_with` does, but never splitting squares among threads
+			#[cfg_attr(not(feature = "rayon"), allow(unused_mut))]
+			let mut context = MountContext::new(&quantized, 128, &palette, &options);
+			#[cfg(feature = "rayon")]
+			{
+				context.parallel_min_size = usize::MAX;
+			}
+			let mut serial = QuadtreeNode::<DynamicPaletteView>::default();
+			serial.mount_region(&context, 128, (0, 0), &mut Histogram::new(palette.width())).unwrap();
+			if options.gradient_fit.is_some() {
+				serial.normalize_gradients();
+			} else {
+				serial.normalize();
+			}
+			let mut tree = QuadtreeNode::default();
+			tree.mount_with(&quantized, &palette, &options).unwrap();
+			assert!(tree.depth() > 3);
+			assert_eq!(tree, serial);
+		}
+	}
+
+	#[test]
+	fn sensitivity_by_depth_overrides_sensitivity() {
+		let palette = DynamicPaletteView::from(colors(6));
+		let image = noise(16, 6, 11);
+		let mount = |options: image::AnalyzeOptions| {
+			let mut tree = QuadtreeNode::default();
+			tree.mount_with(&image, &palette, &options).unwrap();
+			tree
+		};
+		let strict = mount(image::AnalyzeOptions::default().sensitivity(16384));
+		assert_eq!(strict.depth(), 4);
+		assert_eq!(mount(image::AnalyzeOptions::default().sensitivity(0)).node_count(), 1);
+		let by_depth = |sensitivities: &[usize]| mount(image::AnalyzeOptions::default()
+			.sensitivity(16384)
+			.sensitivity_by_depth(Some(sensitivities.to_vec())));
+
+		// Strict for the two largest levels of squares and lax below them,
+		// unlike either flat sensitivity
+		let shallow = by_depth(&[16384, 16384, 0]);
+		assert_eq!(shallow, strict.truncated(2));
+		assert_eq!(shallow.node_count(), 1 + 4 + 16);
+		// Deeper squares use the last entry
+		assert_eq!(by_depth(&[16384, 16384, 0, 0, 0]), shallow);
+		assert_eq!(by_depth(&[16384, 0]), strict.truncated(1));
+		assert_eq!(by_depth(&[16384]), strict);
+		// Lax at the top, nothing below is looked at
+		assert_eq!(by_depth(&[0, 16384]).node_count(), 1);
+		// An empty list leaves `sensitivity` in charge
+		assert_eq!(by_depth(&[]), strict);
+	}
+
+	#[test]
+	fn snapping_trims_further() {
+		// Flat blocks, aligned to the quadrants, each with a little noise
+		let mut rng = crate::test_util::Lcg(4);
+		let bases = [[200u8, 40, 40], [40, 160, 60], [30, 60, 190], [220, 210, 90]];
+		let img = ::image::RgbaImage::from_fn(64, 64, |x, y| {
+			let base = bases[(x / 16 % 2 + y / 16 % 2 * 2) as usize];
+			::image::Rgba([0, 1, 2, 3].map(|ch| if ch == 3 { 255 } else { base[ch] + rng.below(9) as u8 - 4 }))
+		});
+		let palette: DynamicPaletteView = quantize::generate_palette_median_cut(&img, 64);
+		let flat = image::AnalyzeOptions { gradient_fit: None, ..Default::default() };
+		let trimmed = |options: &image::AnalyzeOptions| {
+			let mut tree = QuadtreeNode::default();
+			tree.from_image_with(&img, &palette, options).unwrap();
+			(0..3).for_each(|_| tree.trim(0));
+			let mut drawn = ::image::RgbaImage::new(64, 64);
+			tree.to_image_with(&mut drawn, &palette, &Default::default()).unwrap();
+			(tree.node_count(), metrics::mse(&img, &drawn, &Default::default()).unwrap())
+		};
+		let (nodes, error) = trimmed(&flat);
+		let (snapped_nodes, snapped_error) = trimmed(&image::AnalyzeOptions { snap: Some(200), ..flat.clone() });
+		// A leaf for each block; neither tree keeps much of the noise, so the
+		// error is about the same
+		assert_eq!(snapped_nodes, 1 + 4 + 16);
+		assert!(nodes > 10 * snapped_nodes);
+		assert!((snapped_error - error).abs() < 1., "{} against {}", snapped_error, error);
+	}
+}
@@ -1,7 +1,10 @@
 use bitvec::vec::BitVec;
 
+use std::io::Read;
+
 use super::error::*;
 use super::quantize::palette::{DynamicPalette, Palette};
+use super::TreePath;
 
 /// A `BitVec` variant ideal for encoding and decoding quadtrees.
 type QuadtreeEncodeBitVec = BitVec<bitvec::order::Msb0, u8>;
@@ -9,6 +12,331 @@ type QuadtreeEncodeBitVec = BitVec<bitvec::order::Msb0, u8>;
 /// A type for doing things
 type DecodeQueue = Vec<(Vec<(bool, u32)>, usize)>;
 
+/// The bytes that every QTI file starts with, before its format version.
+pub const MAGIC: &[u8; 6] = b"QuTrIm";
+
+/// Version 3 header flag: the palette is stored as one alpha byte per color,
+/// following a shared RGB base color.
+pub const FLAG_ALPHA_PALETTE: u8 = 0x01;
+/// Version 3 header flag: the image dimensions are declared in the header.
+pub const FLAG_DIMENSIONS: u8 = 0x02;
+/// Version 3 header flag: the number of bits in the quadtree content is
+/// given (as a varint) just before it.
+pub const FLAG_TREE_LENGTH: u8 = 0x04;
+/// Version 3 header flag: each quadrant of the root node starts on a byte
+/// boundary, at an offset given in the header.
+pub const FLAG_QUADRANT_OFFSETS: u8 = 0x08;
+/// Version 3 header flag: a small pre-rendered preview image is stored in the
+/// header.
+pub const FLAG_THUMBNAIL: u8 = 0x10;
+/// Version 3 header flag: the tree was built without gradients, so it is
+/// meant to be rendered without them.
+pub const FLAG_FLAT: u8 = 0x20;
+/// Version 3 header flag: there is no palette, and color numbers are 32-bit
+/// RGBA values (as with `IdentityPalette`).
+pub const FLAG_DIRECT_COLOR: u8 = 0x40;
+
+/// Side length of thumbnails written with `EncodeOptions::embed_thumbnail`,
+/// keeping them within 4 KiB.
+pub const THUMBNAIL_SIZE: u32 = 32;
+
+/// Number of whole bytes of quadtree content that `write_qti` gathers
+/// before writing them out.
+const WRITE_CHUNK_BYTES: usize = 4096;
+
+/// Bits of quadtree content on their way to a writer, for `write_qti`.
+struct BitWriter<'a, W: std::io::Write> {
+	out: &'a mut W,
+	/// Bits not yet written, fewer than `WRITE_CHUNK_BYTES` bytes' worth
+	/// after each `flush_chunks`.
+	bits: QuadtreeEncodeBitVec,
+	/// Number of bytes written so far.
+	written: usize,
+}
+
+impl<W: std::io::Write> BitWriter<'_, W> {
+	/// Writes out the whole bytes of `bits`, if there are enough of them.
+	fn flush_chunks(&mut self) -> std::io::Result<()> {
+		if self.bits.len() < WRITE_CHUNK_BYTES * 8 {
+			return Ok(());
+		}
+		let rest = self.bits.split_off(self.bits.len() / 8 * 8);
+		self.out.write_all(self.bits.as_slice())?;
+		self.written += self.bits.as_slice().len();
+		self.bits = rest;
+		Ok(())
+	}
+
+	/// Pads `bits` with zeros to a byte boundary.
+	fn pad(&mut self) {
+		let len = self.bits.len().div_ceil(8) * 8;
+		self.bits.resize(len, false);
+	}
+
+	/// Pads and writes out whatever is left, giving the number of bytes
+	/// written in all.
+	fn finish(mut self) -> std::io::Result<usize> {
+		self.pad();
+		self.out.write_all(self.bits.as_slice())?;
+		Ok(self.written + self.bits.as_slice().len())
+	}
+}
+
+/// Most bytes of quadtree content that `read_qti` reads at once.
+const READ_CHUNK_BYTES: u64 = 4096;
+
+/// Bits of quadtree content read from a reader a chunk at a time, for
+/// `read_qti`.
+struct BitReader<'a, R: Read> {
+	reader: &'a mut R,
+	/// Bytes read but not yet used up.
+	chunk: Vec<u8>,
+	/// Position of the next bit in `chunk`.
+	chunk_pos: usize,
+	/// Number of bits used up so far.
+	pos: u64,
+	/// Number of bits of quadtree content, if known; otherwise it goes on
+	/// to the end of the reader.
+	len: Option<u64>,
+	/// Number of bytes of quadtree content left to read into `chunk`, if
+	/// known.
+	unread_bytes: Option<u64>,
+}
+
+impl<'a, R: Read> BitReader<'a, R> {
+	/// Prepares to read quadtree content of `len` bits (if known), the
+	/// first bytes of which, `start`, have already been read.
+	fn new(reader: &'a mut R, start: Vec<u8>, len: Option<u64>) -> Self {
+		let unread_bytes = len.map(|len| len.div_ceil(8).saturating_sub(start.len() as u64));
+		BitReader { reader, chunk: start, chunk_pos: 0, pos: 0, len, unread_bytes }
+	}
+
+	/// Reads the next chunk into `chunk`, giving whether there was any more.
+	fn fill(&mut self) -> Result<bool, DecodeError> {
+		let limit = self.unread_bytes.map_or(READ_CHUNK_BYTES, |n| std::cmp::min(n, READ_CHUNK_BYTES));
+		self.chunk.clear();
+		self.chunk_pos = 0;
+		self.reader.by_ref().take(limit).read_to_end(&mut self.chunk).map_err(DecodeError::Io)?;
+		if let Some(ref mut unread) = self.unread_bytes {
+			*unread -= self.chunk.len() as u64;
+		}
+		Ok(!self.chunk.is_empty())
+	}
+
+	/// The next bit of the content, or `None` at the end of its bytes
+	/// (including any padding after the last bit).
+	fn next_padded(&mut self) -> Result<Option<bool>, DecodeError> {
+		if self.chunk_pos == self.chunk.len() * 8 && !self.fill()? {
+			return Ok(None);
+		}
+		let bit = self.chunk[self.chunk_pos / 8] & (0x80 >> (self.chunk_pos % 8)) != 0;
+		self.chunk_pos += 1;
+		self.pos += 1;
+		Ok(Some(bit))
+	}
+
+	/// The next bit of the content, or `InsufficientData` past its end.
+	fn bit(&mut self) -> Result<bool, DecodeError> {
+		if self.len.is_some_and(|len| self.pos >= len) {
+			return Err(DecodeError::InsufficientData);
+		}
+		self.next_padded()?.ok_or(DecodeError::InsufficientData)
+	}
+
+	/// The next `width` bits, as a color number.
+	fn color(&mut self, width: u8) -> Result<u32, DecodeError> {
+		let mut n = 0;
+		for _ in 0..width {
+			n = (n << 1) | self.bit()? as u32;
+		}
+		Ok(n)
+	}
+
+	/// Skips to bit `pos`, giving whether the bits skipped were all zero.
+	fn skip_to(&mut self, pos: u64) -> Result<bool, DecodeError> {
+		let mut zero = true;
+		while self.pos < pos {
+			zero &= !self.bit()?;
+		}
+		Ok(zero)
+	}
+
+	/// Reads the rest of the content's bytes, giving whether their bits were
+	/// all zero.
+	fn rest_is_zero(&mut self) -> Result<bool, DecodeError> {
+		let mut zero = true;
+		while let Some(bit) = self.next_padded()? {
+			zero &= !bit;
+		}
+		Ok(zero)
+	}
+}
+
+/// Appends `n` to `buffer` as an unsigned LEB128 varint.
+fn write_varint(buffer: &mut Vec<u8>, mut n: u64) {
+	while n >= 0x80 {
+		buffer.push(n as u8 | 0x80);
+		n >>= 7;
+	}
+	buffer.push(n as u8);
+}
+
+/// Where `parse_header` gets the bytes of a QTI file from: the file in
+/// memory, or a reader that it is read from as needed.
+trait HeaderSource {
+	/// The `len` bytes at `start`, or `InsufficientData` if the file ends
+	/// before them.
+	fn bytes(&mut self, start: usize, len: usize) -> Result<&[u8], DecodeError>;
+}
+
+impl HeaderSource for &[u8] {
+	fn bytes(&mut self, start: usize, len: usize) -> Result<&[u8], DecodeError> {
+		self.get(start..start + len).ok_or(DecodeError::InsufficientData)
+	}
+}
+
+/// A reader, and everything read from it so far, for `read_qti`.
+struct ReadSource<'a, R: Read> {
+	reader: &'a mut R,
+	buffer: Vec<u8>,
+}
+
+impl<R: Read> HeaderSource for ReadSource<'_, R> {
+	fn bytes(&mut self, start: usize, len: usize) -> Result<&[u8], DecodeError> {
+		let end = start + len;
+		if self.buffer.len() < end {
+			// Read just what is needed, and no more than the reader has, so
+			// that a bad length can't make this allocate more than that
+			let missing = (end - self.buffer.len()) as u64;
+			self.reader.by_ref().take(missing).read_to_end(&mut self.buffer).map_err(DecodeError::Io)?;
+		}
+		self.buffer.get(start..end).ok_or(DecodeError::InsufficientData)
+	}
+}
+
+/// Reads a big-endian four-byte unsigned integer from `source` at `offset`.
+fn read_u32<S: HeaderSource>(source: &mut S, offset: usize) -> Result<u32, DecodeError> {
+	source.bytes(offset, 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Reads an unsigned LEB128 varint from `source` at `offset`, returning it
+/// and the offset just after it.
+fn read_varint<S: HeaderSource>(source: &mut S, mut offset: usize) -> Result<(u64, usize), DecodeError> {
+	let mut n = 0;
+	for shift in (0..64).step_by(7) {
+		let byte = source.bytes(offset, 1)?[0];
+		offset += 1;
+		n |= ((byte & 0x7f) as u64) << shift;
+		if byte & 0x80 == 0 {
+			return Ok((n, offset));
+		}
+	}
+	Err(DecodeError::MissingHeader)
+}
+
+/// The number of palette entries that `to_qti_with` writes: those up to the
+/// last one that isn't blank (all zeros, or with zero alpha for alpha
+/// palettes) or is `max_color`, rounded up as the version 3 size byte
+/// requires unless `compact` (version 4).
+///
+/// Blank entries that the tree uses are written, so that the decoder reads
+/// their color numbers as they are rather than as the implied color.
+fn stored_palette_len<P: Palette>(palette: &P, compact: bool, max_color: u32) -> u32 {
+	if palette.is_direct() {
+		return 0;
+	}
+	let alpha_base = palette.alpha_base();
+	let full_len = 1usize << palette.width();
+	// Trailing entries that the decoder will fill in by itself
+	let is_blank = |c: &image::Rgba<u8>| match alpha_base {
+		Some(_) => c.0[3] == 0,
+		None => *c == image::Rgba([0; 4])
+	};
+	let used_len = match palette.get_slice() {
+		// Entries past the end of the slice are transparent
+		Some(colors) => {
+			let colors = &colors[..std::cmp::min(colors.len(), full_len)];
+			colors.len() - colors.iter().rev().take_while(|c| is_blank(c)).count()
+		},
+		None => full_len - (0..full_len)
+			.rev()
+			.take_while(|&n| palette.to_rgba(n as u32).is_ok_and(|c| is_blank(&c)))
+			.count()
+	};
+	let used_len = std::cmp::max(used_len, std::cmp::min(max_color as usize + 1, full_len));
+	if compact {
+		used_len as u32
+	} else {
+		// The size byte gives `(n + 9) * 2^(width - 4)` colors for a three-bit
+		// `n`, so the stored length is a multiple of `full_len / 16` from
+		// `9 * full_len / 16` to `full_len`. Below width 4 that step is a
+		// fraction, and any whole length from `ceil(9 * full_len / 16)` up
+		// will do as is: widths 1, 2 and 3 store at least 2, 3 and 5 colors.
+		let palette_len = std::cmp::max(used_len, (9 * full_len).div_ceil(16));
+		((palette_len * 16).div_ceil(full_len) * full_len / 16) as u32
+	}
+}
+
+/// Options for writing QTI data with `to_qti_with`.
+#[derive(Clone, Debug, Default)]
+pub struct EncodeOptions {
+	/// Dimensions of the image, as `(width, height)`, to declare in the header.
+	pub dimensions: Option<(u32, u32)>,
+	/// Whether to start each quadrant of the tree on a byte boundary and
+	/// give their offsets in the header, so that each can be decoded alone
+	/// (with `quadrant_from_qti`).
+	pub quadrant_offsets: bool,
+	/// Whether to store a `THUMBNAIL_SIZE`-pixel square rendering of the
+	/// tree in the header, for `read_embedded_thumbnail`.
+	pub embed_thumbnail: bool,
+	/// Whether to write version 4, which stores exactly the palette colors
+	/// in use (after a count) rather than a rounded number of them.
+	pub compact_palette: bool,
+	/// Whether to mark the tree as built without gradients (with no
+	/// `gradient_fit` in `from_image_with`), so that it is rendered without
+	/// them.
+	pub flat: bool,
+}
+
+/// Options for reading QTI data with `from_qti_with`.
+#[derive(Clone, Debug)]
+pub struct DecodeOptions {
+	/// Whether to reject trees deeper than needed for the image dimensions
+	/// declared in the header, if there are any.
+	pub check_depth: bool,
+	/// Whether to reject files with nonzero padding after the quadtree
+	/// content, with `NonZeroPadding`, rather than ignoring it. Callers that
+	/// would warn of it can decode strictly first, then again without.
+	pub strict: bool,
+	/// Whether to `normalize` the decoded tree, collapsing redundant
+	/// branches (which may change how it renders with gradients).
+	pub normalize: bool,
+	/// If given, trees with nodes more than this many levels below the root
+	/// are rejected as they are read, whatever the dimensions in the header.
+	/// Defaults to `DEFAULT_MAX_DEPTH`.
+	pub max_depth: Option<usize>,
+}
+
+/// Default `DecodeOptions::max_depth`: deeper trees could only be drawn
+/// with every node at least a pixel at over 2^32 pixels a side, which
+/// dimensions can't be, and take that many levels of recursion to draw.
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+impl Default for DecodeOptions {
+	fn default() -> Self {
+		DecodeOptions { check_depth: true, strict: false, normalize: false, max_depth: Some(DEFAULT_MAX_DEPTH) }
+	}
+}
+
+/// The depth limit for the root's quadrants, given that for the root;
+/// `TooDeep` if the root can't have subsections at all.
+fn quadrant_depth_limit(max_depth: Option<usize>) -> Result<Option<usize>, DecodeError> {
+	match max_depth {
+		Some(0) => Err(DecodeError::TooDeep),
+		max_depth => Ok(max_depth.map(|max_depth| max_depth - 1))
+	}
+}
+
 impl<P: Palette + Default> super::QuadtreeNode<P> {
 	/// Converts the `QuadtreeNode` into a binary data format.
 	///
@@ -23,67 +351,154 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 		buffer: &mut QuadtreeEncodeBitVec,
 		palette: &P
 	) -> Result<(), EncodeError> {
-		// Validate color value
-		if self.color >= 1 << palette.width() {
-			return Err(EncodeError::ColorOutOfRange);
-		}
-		// Bit to indicate subsections
-		buffer.push(self.sections.is_some());
-		// Color number
-		for bit_ind in 0..palette.width() {
-			buffer.push(self.color & (1 << (palette.width() - bit_ind - 1)) != 0);
-		}
-		// Recursion
+		// Validate color values, so that nothing is appended on failure
+		self.check_colors(palette, &mut Vec::new())?;
+		self.push_v1(buffer, palette.width());
+		Ok(())
+	}
+
+	/// Helper for `encode_v1`, for trees that have passed `check_colors`.
+	fn push_v1(&self, buffer: &mut QuadtreeEncodeBitVec, width: u8) {
+		self.encode_node(buffer, width);
 		if let Some(ref sects) = self.sections {
 			for section in sects.iter() {
-				section.encode_v1(buffer, palette)?;
+				section.push_v1(buffer, width);
 			}
 		}
-		Ok(())
+	}
+
+	/// The number of bits that `encode_v1` appends for this tree with
+	/// `palette`, found without encoding it: one subsection bit and a color
+	/// number for each node. See `encoded_size_estimate` for whole QTI files,
+	/// palette and header included.
+	pub fn encoded_bit_len(&self, palette: &P) -> usize {
+		self.node_count() * (1 + palette.width() as usize)
 	}
 
 	/// Reads a `BitVec` of the sort that would be output from `.encode_v1()`
 	/// and parses a quadtree from it.
 	///
 	/// Successful return value is the index to which the parser has progressed,
-	/// to assist with the recursive algorithm.
+	/// for reading anything that follows the tree.
 	///
 	/// 0 should be passed for `curr_ind` by outside callers, unless they
 	/// know what they're doing and have a good reason otherwise.
+	///
+	/// Nodes are read with a stack of their own rather than by recursion,
+	/// so no tree is too deep to decode. Most everything else done with
+	/// trees (dropping them included) does recurse, though, so the depth of
+	/// trees from untrusted files should be limited with `from_qti_with` and
+	/// `DecodeOptions::max_depth`, as it is by default.
 	pub fn decode_v1(
 		&mut self,
 		buffer: &QuadtreeEncodeBitVec,
 		palette: &P,
-		mut curr_ind: usize
+		curr_ind: usize
 	) -> Result<usize, DecodeError> {
-		// Validate data quantity
-		if buffer.len() - curr_ind < (palette.width()) as usize {
-			return Err(DecodeError::InsufficientData);
-		}
-		// Extract current node
-		let mut n = 0;
-		for bit_ind in 0..(palette.width()) {
-			n |= (buffer[curr_ind + bit_ind as usize + 1] as u32) << (palette.width() - bit_ind - 1);
-		}
-		self.color = n;
-		// Recursion
-		let should_recurse = buffer[curr_ind];
-		curr_ind += 1 + palette.width() as usize;
-		if should_recurse {
-			self.sections = Some(Default::default());
-			for sect_ind in 0..4 {
-				curr_ind = self.sections.as_mut().unwrap()[sect_ind]
-					.decode_v1(buffer, palette, curr_ind)?;
+		self.decode_v1_limited(buffer, palette, curr_ind, None, u32::MAX)
+	}
+
+	/// `decode_v1`, giving `TooDeep` for trees more than `max_depth` levels
+	/// deep (if given), and reading color numbers over `last_color` as it
+	/// (see `Header::palette_colors`).
+	fn decode_v1_limited(
+		&mut self,
+		buffer: &QuadtreeEncodeBitVec,
+		palette: &P,
+		mut curr_ind: usize,
+		max_depth: Option<usize>,
+		last_color: u32
+	) -> Result<usize, DecodeError> {
+		let width = palette.width() as usize;
+		self.decode_nodes(|| {
+			// Validate data quantity: the subsection bit and the color number
+			// (without adding to `curr_ind`, which may be anything)
+			if buffer.len().saturating_sub(curr_ind) < 1 + width {
+				return Err(DecodeError::InsufficientData);
 			}
-		}
+			let color = buffer[curr_ind + 1..curr_ind + 1 + width].iter()
+				.fold(0, |n, b| (n << 1) | *b as u32);
+			let should_recurse = buffer[curr_ind];
+			curr_ind += 1 + width;
+			Ok((should_recurse, std::cmp::min(color, last_color)))
+		}, max_depth)?;
 		Ok(curr_ind)
 	}
 
+	/// Like `decode_v1_limited`, but reading the bits from `reader` as they
+	/// are needed.
+	fn read_v1<R: Read>(
+		&mut self,
+		reader: &mut BitReader<R>,
+		width: u8,
+		max_depth: Option<usize>,
+		last_color: u32
+	) -> Result<(), DecodeError> {
+		self.decode_nodes(|| Ok((reader.bit()?, std::cmp::min(reader.color(width)?, last_color))), max_depth)
+	}
+
+	/// Helper for `decode_v1_limited` and `read_v1`; builds this node and
+	/// those below it from their subsection bits and color numbers, which
+	/// `next_node` gives in the order `encode_v1` writes them.
+	///
+	/// The nodes are listed first, and then put together from the last up,
+	/// so that neither step needs recursion.
+	fn decode_nodes<F>(&mut self, mut next_node: F, max_depth: Option<usize>) -> Result<(), DecodeError>
+	where F: FnMut() -> Result<(bool, u32), DecodeError> {
+		let mut nodes = Vec::new();
+		// Number of subsections yet to be read of each branch being read
+		let mut unread: Vec<u8> = Vec::new();
+		loop {
+			if max_depth.is_some_and(|max_depth| unread.len() > max_depth) {
+				return Err(DecodeError::TooDeep);
+			}
+			let (should_recurse, color) = next_node()?;
+			nodes.push((should_recurse, color));
+			if should_recurse {
+				unread.push(4);
+				continue;
+			}
+			// Finish the branches that this was the last subsection of
+			loop {
+				match unread.last_mut() {
+					None => break,
+					Some(1) => {
+						unread.pop();
+					},
+					Some(count) => {
+						*count -= 1;
+						break;
+					}
+				}
+			}
+			if unread.is_empty() {
+				break;
+			}
+		}
+		// Going backwards, each branch's subsections are the last four
+		// nodes made, the first on top
+		let mut made: Vec<super::QuadtreeNode<P>> = Vec::new();
+		for (should_recurse, color) in nodes.into_iter().rev() {
+			let sections = if should_recurse {
+				let mut sections: Box<[super::QuadtreeNode<P>; 4]> = Default::default();
+				for section in sections.iter_mut() {
+					*section = made.pop().expect("branch with fewer than four subsections");
+				}
+				Some(sections)
+			} else {
+				None
+			};
+			made.push(super::QuadtreeNode { color, sections, _pal: std::marker::PhantomData });
+		}
+		*self = made.pop().expect("no nodes decoded");
+		Ok(())
+	}
+
 	/// Reads a `BitVec` of the sort that would be output from `.encode_v2()`
 	/// and parses a quadtree from it.
 	///
 	/// Not yet implemented. I have no idea what I'm doing.
-	/// Big TODO.
+	/// Big TODO. Until then, this gives `UnsupportedVersion(2)`.
 	pub fn decode_v2(
 		&mut self,
 		buffer: &QuadtreeEncodeBitVec,
@@ -91,81 +506,1428 @@ impl<P: Palette + Default> super::QuadtreeNode<P> {
 		queue: Option<&mut DecodeQueue>,
 	) -> Result<DecodeQueue, DecodeError> {
 		// To get rid of unused variable warnings
-		dbg!(buffer, queue, palette.width());
-		Err(DecodeError::InsufficientData)
+		let _ = (buffer, palette, queue);
+		Err(DecodeError::UnsupportedVersion(2))
 	}
 
 	/// Encodes the quadtree and a palette into QTI data.
 	pub fn to_qti(&self, palette: &P) -> Result<Vec<u8>, EncodeError> {
+		self.to_qti_with(palette, &Default::default())
+	}
+
+	/// Encodes the quadtree and a palette into QTI data (version 3, or 4 with
+	/// `EncodeOptions::compact_palette`), with optional header fields as set
+	/// in `options`.
+	///
+	/// The quadtree content starts on a byte boundary, after its length in
+	/// bits; the padding bits in its last byte are zero.
+	///
+	/// `from_qti` of the output gives back an equal tree and palette (by
+	/// `==`), for palettes of any width and however many trailing
+	/// transparent colors they have.
+	pub fn to_qti_with(&self, palette: &P, options: &EncodeOptions) -> Result<Vec<u8>, EncodeError> {
+		self.validate(palette)?;
 		let mut ret = Vec::new();
-		// Header (version 1)
-		ret.extend_from_slice(b"QuTrIm\x01");
-		let mut palette_vec = palette.get_slice()
-			.map(|x| x.to_owned())
-			.unwrap_or_else(|| (0..palette.width() << 1)
-				.map(|n| palette.to_rgba(n as u32).unwrap())
-				.collect::<Vec<_>>());
-		palette_vec.resize(1 << palette.width(), image::Rgba([0; 4]));
-		let palette_len = std::cmp::max((1 << palette.width()) - palette_vec.iter()
-			.rev()
-			.take_while(|c| **c == image::Rgba([0; 4]))
-			.count(),
-			(9 * (1 << palette.width()) + 15) / 16);
-		let approx_len = (palette_len as f64 * 16. / (1 << palette.width()) as f64)
-			.ceil() as u32 * (1 << palette.width()) / 16;
-		// Length indicator
-		ret.push((((approx_len * 16) / (1 << palette.width()) - 9) << 5) as u8 |
-			(palette.width() - 1));
+		// Writing to a `Vec` can't fail, and every color has been checked, so
+		// the thumbnail can be drawn
+		self.write_checked(palette, options, &mut ret).expect("failure to write checked quadtree");
+		Ok(ret)
+	}
+
+	/// Writes the QTI data that `to_qti` gives to `out`, a chunk at a time
+	/// rather than all at once, so that large trees needn't be held in
+	/// memory twice. Gives the number of bytes written.
+	///
+	/// Trees that `to_qti` can't encode give `QtiWriteError::Encode` before
+	/// anything is written; `QtiWriteError::Io` errors may come after some
+	/// of the data has been.
+	pub fn write_qti<W: std::io::Write>(&self, palette: &P, out: &mut W) -> Result<usize, QtiWriteError> {
+		self.write_qti_with(palette, &Default::default(), out)
+	}
+
+	/// Like `write_qti`, for `to_qti_with` and `options`.
+	pub fn write_qti_with<W: std::io::Write>(
+		&self,
+		palette: &P,
+		options: &EncodeOptions,
+		out: &mut W
+	) -> Result<usize, QtiWriteError> {
+		self.validate(palette).map_err(QtiWriteError::Encode)?;
+		self.write_checked(palette, options, out).map_err(QtiWriteError::Io)
+	}
+
+	/// Checks that the tree and palette can be encoded (or drawn): that the
+	/// palette's width is from 1 to 32, and that it has a color for every
+	/// color number of the tree, without encoding or drawing anything.
+	///
+	/// The first node out of range, in the order nodes are encoded, gives
+	/// `ColorOutOfRange` with its path; this catches trees built by hand or
+	/// moved onto another palette that `to_qti` or `to_image` would fail on.
+	pub fn validate(&self, palette: &P) -> Result<(), EncodeError> {
+		if !(1..=32).contains(&palette.width()) {
+			return Err(EncodeError::InvalidWidth);
+		}
+		self.check_colors(palette, &mut Vec::new())
+	}
+
+	/// Helper for `validate` and `encode_v1`; checks the colors of
+	/// this node and those below it, in the order they are encoded. `path`
+	/// is where this node is.
+	fn check_colors(&self, palette: &P, path: &mut TreePath) -> Result<(), EncodeError> {
+		palette.to_rgba(self.color)
+			.map_err(|e| EncodeError::ColorOutOfRange { index: e.index(), path: path.clone() })?;
+		if let Some(ref sects) = self.sections {
+			for (ind, section) in sects.iter().enumerate() {
+				path.push(ind as u8);
+				section.check_colors(palette, path)?;
+				path.pop();
+			}
+		}
+		Ok(())
+	}
+
+	/// The largest color number in the tree.
+	fn max_color(&self) -> u32 {
+		self.sections.iter()
+			.flat_map(|sects| sects.iter())
+			.map(|s| s.max_color())
+			.fold(self.color, std::cmp::max)
+	}
+
+	/// The number of bits of quadtree content that `to_qti_with` writes
+	/// with `quadrant_offsets`, and the offsets themselves, if any.
+	fn tree_layout(&self, node_bits: usize, quadrant_offsets: bool) -> (usize, Option<[u32; 4]>) {
+		match (&self.sections, quadrant_offsets) {
+			// Each quadrant starts on a byte boundary
+			(Some(sects), true) => {
+				let mut offsets = [0u32; 4];
+				let mut bits = node_bits;
+				for (section, offset) in sects.iter().zip(offsets.iter_mut()) {
+					*offset = bits.div_ceil(8) as u32;
+					bits = bits.div_ceil(8) * 8 + section.node_count() * node_bits;
+				}
+				(bits, Some(offsets))
+			},
+			(None, true) => (node_bits, Some([0; 4])),
+			(_, false) => (self.node_count() * node_bits, None)
+		}
+	}
+
+	/// Helper for `to_qti_with` and `write_qti_with`, for trees that have
+	/// passed `validate`; gives the number of bytes written.
+	fn write_checked<W: std::io::Write>(
+		&self,
+		palette: &P,
+		options: &EncodeOptions,
+		out: &mut W
+	) -> std::io::Result<usize> {
+		let mut header = Vec::new();
+		let alpha_base = palette.alpha_base();
+		let flags = FLAG_TREE_LENGTH |
+			if alpha_base.is_some() { FLAG_ALPHA_PALETTE } else { 0 } |
+			if options.dimensions.is_some() { FLAG_DIMENSIONS } else { 0 } |
+			if options.quadrant_offsets { FLAG_QUADRANT_OFFSETS } else { 0 } |
+			if options.embed_thumbnail { FLAG_THUMBNAIL } else { 0 } |
+			if options.flat { FLAG_FLAT } else { 0 } |
+			if palette.is_direct() { FLAG_DIRECT_COLOR } else { 0 };
+		// The layout of the quadtree is known from the node counts, so the
+		// header can be written before it
+		let (tree_bits, quadrant_offsets) = self.tree_layout(palette.width() as usize + 1, options.quadrant_offsets);
+		header.extend_from_slice(MAGIC);
+		header.push(if options.compact_palette { 4 } else { 3 });
+		let palette_len = stored_palette_len(palette, options.compact_palette, self.max_color());
+		if options.compact_palette || palette.is_direct() {
+			// Width alone; the count, if any, is given before the palette
+			header.push(palette.width() - 1);
+		} else {
+			// Length indicator; exact, since `stored_palette_len` rounds to a
+			// multiple of `2^(width - 4)`
+			header.push((((palette_len as u64 * 16) / (1 << palette.width()) - 9) << 5) as u8 |
+				(palette.width() - 1));
+		}
+		header.push(flags);
+		if let Some(base) = alpha_base {
+			header.extend_from_slice(&base);
+		}
+		if let Some((width, height)) = options.dimensions {
+			header.extend_from_slice(&height.to_be_bytes());
+			header.extend_from_slice(&width.to_be_bytes());
+		}
+		if let Some(offsets) = quadrant_offsets {
+			for offset in offsets.iter() {
+				header.extend_from_slice(&offset.to_be_bytes());
+			}
+		}
+		if options.embed_thumbnail {
+			let mut thumbnail = image::RgbaImage::new(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+			self.to_image(&mut thumbnail, palette, None, None, false, None)
+				.map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "quadtree could not be drawn"))?;
+			header.push(THUMBNAIL_SIZE as u8);
+			header.push(THUMBNAIL_SIZE as u8);
+			header.extend_from_slice(&thumbnail);
+		}
 		// Palette
-		for c in 0..approx_len {
-			ret.extend_from_slice(&palette.to_rgba(c).unwrap().0);
+		if options.compact_palette && !palette.is_direct() {
+			write_varint(&mut header, palette_len as u64);
+		}
+		// Version 3 lengths are rounded up, perhaps past the palette's colors;
+		// the entries there are blank, as the decoder would take them to be
+		match alpha_base {
+			Some(_) => {
+				for c in 0..palette_len {
+					header.push(palette.to_rgba(c).map_or(0, |c| c.0[3]));
+				}
+			},
+			None => for c in 0..palette_len {
+				header.extend_from_slice(&palette.to_rgba(c).map_or([0; 4], |c| c.0));
+			}
 		}
+		write_varint(&mut header, tree_bits as u64);
+		out.write_all(&header)?;
 		// Quadtree
-		let mut bit_buf = QuadtreeEncodeBitVec::new();
-		self.encode_v1(&mut bit_buf, palette)?;
-		ret.extend_from_slice(bit_buf.as_slice());
-		Ok(ret)
+		let mut writer = BitWriter { out, bits: QuadtreeEncodeBitVec::new(), written: header.len() };
+		if options.quadrant_offsets {
+			// Root node alone, then each quadrant padded to a byte boundary
+			self.encode_node(&mut writer.bits, palette.width());
+			if let Some(ref sects) = self.sections {
+				for section in sects.iter() {
+					writer.pad();
+					section.write_v1(&mut writer, palette.width())?;
+				}
+			}
+		} else {
+			self.write_v1(&mut writer, palette.width())?;
+		}
+		writer.finish()
+	}
+
+	/// Pushes the bits of this node alone (without its subsections) to
+	/// `buffer`, as `encode_v1` does.
+	fn encode_node(&self, buffer: &mut QuadtreeEncodeBitVec, width: u8) {
+		// Bit to indicate subsections
+		buffer.push(self.sections.is_some());
+		// Color number
+		for bit_ind in 0..width {
+			buffer.push(self.color & (1 << (width - bit_ind - 1)) != 0);
+		}
+	}
+
+	/// Like `encode_v1`, but passing the bits on to `writer` as they fill up
+	/// whole chunks.
+	fn write_v1<W: std::io::Write>(&self, writer: &mut BitWriter<W>, width: u8) -> std::io::Result<()> {
+		self.encode_node(&mut writer.bits, width);
+		writer.flush_chunks()?;
+		if let Some(ref sects) = self.sections {
+			for section in sects.iter() {
+				section.write_v1(writer, width)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// The number of bytes that `to_qti` gives for this tree and palette,
+	/// worked out without encoding it: the header, the palette and
+	/// `node_count() * (width + 1)` bits of quadtree content.
+	///
+	/// Exact for trees that encode successfully.
+	pub fn encoded_size_estimate(&self, palette: &P) -> usize {
+		self.encoded_size_estimate_with(palette, &Default::default())
+	}
+
+	/// Like `encoded_size_estimate`, for `to_qti_with` and `options`.
+	pub fn encoded_size_estimate_with(&self, palette: &P, options: &EncodeOptions) -> usize {
+		let (tree_bits, _) = self.tree_layout(palette.width() as usize + 1, options.quadrant_offsets);
+		let palette_len = stored_palette_len(palette, options.compact_palette, self.max_color()) as usize;
+		let varint_len = |n: usize| std::cmp::max(1, (usize::BITS - n.leading_zeros()).div_ceil(7) as usize);
+		// Magic number, version, size byte and flags
+		9 +
+			if palette.alpha_base().is_some() { 3 + palette_len } else { 4 * palette_len } +
+			if options.dimensions.is_some() { 8 } else { 0 } +
+			if options.quadrant_offsets { 16 } else { 0 } +
+			if options.embed_thumbnail { 2 + 4 * (THUMBNAIL_SIZE * THUMBNAIL_SIZE) as usize } else { 0 } +
+			if options.compact_palette && !palette.is_direct() { varint_len(palette_len) } else { 0 } +
+			varint_len(tree_bits) +
+			tree_bits.div_ceil(8)
+	}
+}
+
+/// The parts of a QTI file before the quadtree content, from `read_header`.
+#[derive(Clone, Debug)]
+pub struct Header {
+	/// Format version.
+	pub version: u8,
+	/// Palette colors, as many as the file stores; color numbers past them
+	/// stand for `implied_color`. Empty if `direct`.
+	pub palette: Vec<image::Rgba<u8>>,
+	/// The bit width of color numbers in the file; 32 if `direct`.
+	pub width: u8,
+	/// The color of every palette entry but for its opacity, for alpha
+	/// palettes (see `FLAG_ALPHA_PALETTE`).
+	pub alpha_base: Option<[u8; 3]>,
+	/// Whether color numbers are RGBA values, with no palette (see
+	/// `FLAG_DIRECT_COLOR`).
+	pub direct: bool,
+	/// Image dimensions as `(width, height)`, if declared.
+	pub dimensions: Option<(u32, u32)>,
+	/// Byte offsets of the root's quadrants in the quadtree content, if the
+	/// file was written with `EncodeOptions::quadrant_offsets`.
+	pub quadrant_offsets: Option<[u32; 4]>,
+	/// Embedded preview image, if any.
+	pub thumbnail: Option<image::RgbaImage>,
+	/// Whether the tree is meant to be rendered without gradients.
+	pub flat: bool,
+	/// Number of bits of quadtree content, if declared.
+	pub tree_length: Option<u64>,
+	/// Position of the quadtree content in the file.
+	pub tree_offset: usize,
+}
+
+/// Whether `bytes` (the start of a file, or all of it) look like QTI data:
+/// whether they start with `MAGIC`. The rest of the header may still be
+/// invalid, or of an unsupported version.
+pub fn is_qti(bytes: &[u8]) -> bool {
+	bytes.starts_with(MAGIC)
+}
+
+/// Reads the header and palette of a QTI file, without decoding the quadtree.
+///
+/// Versions other than 1 to 4 give `UnsupportedVersion`; version 2 headers
+/// are read, but their quadtrees can't yet be decoded.
+pub fn read_header(mut source: &[u8]) -> Result<Header, DecodeError> {
+	parse_header(&mut source)
+}
+
+/// Helper for `read_header` and `read_qti`.
+fn parse_header<S: HeaderSource>(source: &mut S) -> Result<Header, DecodeError> {
+	// Verify header (version 1 is required for compatibility)
+	match source.bytes(0, 6) {
+		Ok(magic) if magic == MAGIC => (),
+		Err(DecodeError::Io(e)) => return Err(DecodeError::Io(e)),
+		_ => return Err(DecodeError::MissingHeader)
 	}
+	let header = source.bytes(6, 2)?;
+	let (version, size_byte) = (header[0], header[1]);
+	// Version 2 has a header that can be read, though not a tree that can be
+	if !(1..=4).contains(&version) {
+		return Err(DecodeError::UnsupportedVersion(version));
+	}
+	let pal_size = (size_byte & 0x1f) + 1;
+	// `(n + 9) * 2^(pal_size - 4)`; for widths under 4 a length that isn't a
+	// whole number (such as `n = 0` at width 1) can't have been written
+	let pal_len = if pal_size >= 4 {
+		((size_byte as u64 >> 5) + 9) << (pal_size - 4)
+	} else {
+		let scaled = (size_byte as u64 >> 5) + 9;
+		let shift = 4 - pal_size;
+		if version < 4 && scaled & ((1 << shift) - 1) != 0 {
+			return Err(DecodeError::MissingHeader);
+		}
+		scaled >> shift
+	};
+	let pal_len = std::cmp::min(pal_len, u32::MAX as u64) as u32;
+	if version < 4 && pal_len.count_ones() > 4 {
+		return Err(DecodeError::MissingHeader);
+	}
+	let (flags, mut offset) = if version >= 3 { (source.bytes(8, 1)?[0], 9) } else { (0, 8) };
+	let direct = flags & FLAG_DIRECT_COLOR != 0;
+	if direct && pal_size != 32 {
+		return Err(DecodeError::MissingHeader);
+	}
+	let base = if flags & FLAG_ALPHA_PALETTE != 0 {
+		offset += 3;
+		let base = source.bytes(offset - 3, 3)?;
+		Some([base[0], base[1], base[2]])
+	} else {
+		None
+	};
+	let dimensions = if flags & FLAG_DIMENSIONS != 0 {
+		offset += 8;
+		Some((read_u32(source, offset - 4)?, read_u32(source, offset - 8)?))
+	} else {
+		None
+	};
+	let quadrant_offsets = if flags & FLAG_QUADRANT_OFFSETS != 0 {
+		offset += 16;
+		Some([
+			read_u32(source, offset - 16)?,
+			read_u32(source, offset - 12)?,
+			read_u32(source, offset - 8)?,
+			read_u32(source, offset - 4)?,
+		])
+	} else {
+		None
+	};
+	let thumbnail = if flags & FLAG_THUMBNAIL != 0 {
+		let size = source.bytes(offset, 2)?;
+		let (width, height) = (size[0] as u32, size[1] as u32);
+		let pixels = source.bytes(offset + 2, (width * height * 4) as usize)?;
+		offset += 2 + pixels.len();
+		image::RgbaImage::from_raw(width, height, pixels.to_vec())
+	} else {
+		None
+	};
+	// Version 4 gives the exact number of palette colors
+	let pal_len = if direct {
+		0
+	} else if version >= 4 {
+		let (count, pal_offset) = read_varint(source, offset)?;
+		if count > 1 << pal_size {
+			return Err(DecodeError::MissingHeader);
+		}
+		offset = pal_offset;
+		count as u32
+	} else {
+		pal_len
+	};
+	// Extract palette; it is only as long as the data holding it, and isn't
+	// padded out to the full width (up to 2^32 colors)
+	let mut pal = vec![];
+	if direct {
+		// Nothing to extract
+	} else if let Some(base) = base {
+		for alpha in source.bytes(offset, pal_len as usize)? {
+			pal.push(image::Rgba([base[0], base[1], base[2], *alpha]));
+		}
+		offset += pal_len as usize;
+	} else {
+		for c in source.bytes(offset, 4 * pal_len as usize)?.chunks(4) {
+			pal.push(image::Rgba([c[0], c[1], c[2], c[3]]));
+		}
+		offset += 4 * pal_len as usize;
+	}
+	// Find tree
+	let tree_length = if flags & FLAG_TREE_LENGTH != 0 {
+		let (bit_len, tree_offset) = read_varint(source, offset)?;
+		offset = tree_offset;
+		Some(bit_len)
+	} else {
+		None
+	};
+	Ok(Header {
+		version,
+		palette: pal,
+		width: pal_size,
+		alpha_base: base,
+		direct,
+		dimensions,
+		quadrant_offsets,
+		thumbnail,
+		flat: flags & FLAG_FLAT != 0,
+		tree_length,
+		tree_offset: offset,
+	})
+}
+
+/// Gets the preview image embedded in a QTI file with
+/// `EncodeOptions::embed_thumbnail`, if it has one, without decoding the
+/// quadtree.
+pub fn read_embedded_thumbnail(source: &[u8]) -> Option<image::RgbaImage> {
+	read_header(source).ok()?.thumbnail
 }
 
-impl<'a, P: DynamicPalette + Default + std::fmt::Debug> super::QuadtreeNode<P> {
+/// Adds the area (as a fraction of `1 << 64`) covered by each color in the
+/// tree to `usage`, including that of branch nodes; those more than 32
+/// levels down count as much as those at 32.
+fn color_usage<P: Palette + Default>(
+	node: &super::QuadtreeNode<P>,
+	palette: &P,
+	depth: u32,
+	usage: &mut std::collections::HashMap<image::Rgba<u8>, u128>
+) {
+	// Each level adds up to at most `1 << 64` per tree, so this can't
+	// overflow for any tree that fits in memory
+	if let Ok(c) = palette.to_rgba(node.color) {
+		*usage.entry(c).or_insert(0) += 1 << (2 * (32 - std::cmp::min(depth, 32)));
+	}
+	if let Some(ref sects) = node.sections {
+		for section in sects.iter() {
+			color_usage(section, palette, depth + 1, usage);
+		}
+	}
+}
+
+/// Rewrites a set of QTI files onto one shared palette, so that they can be
+/// used together (e.g. in an atlas). The palette has every color used by any
+/// of the files, most widely used first, so each renders just as before.
+///
+/// Other header contents (dimensions, quadrant offsets, thumbnails and
+/// flatness) are kept, but the files are otherwise written as by `to_qti`.
+pub fn unify_palettes(sources: &[&[u8]]) -> Result<Vec<Vec<u8>>, DecodeError> {
+	use super::quantize::palette::DynamicPaletteView;
+	let mut decoded = Vec::new();
+	let mut usage = std::collections::HashMap::new();
+	for source in sources.iter() {
+		let header = read_header(source)?;
+		let (tree, palette) = super::QuadtreeNode::<DynamicPaletteView>::from_qti(source)?;
+		color_usage(&tree, &palette, 0, &mut usage);
+		decoded.push((header, tree, palette));
+	}
+	let mut colors = usage.into_iter().collect::<Vec<_>>();
+	colors.sort_by_key(|cu| (std::cmp::Reverse(cu.1), cu.0 .0));
+	let shared = DynamicPaletteView::from(colors.into_iter().map(|cu| cu.0).collect::<Vec<_>>());
+	Ok(decoded.iter().map(|(header, tree, palette)| {
+		// Every color of every tree is in the shared palette, and decoded
+		// color numbers are always in range, so neither step can fail
+		tree.reindex_to_palette(palette, &shared)
+			.expect("failure to reindex decoded quadtree")
+			.to_qti_with(&shared, &EncodeOptions {
+				dimensions: header.dimensions,
+				quadrant_offsets: header.quadrant_offsets.is_some(),
+				embed_thumbnail: header.thumbnail.is_some(),
+				compact_palette: header.version == 4,
+				flat: header.flat,
+			})
+			.expect("failure to serialize to QTI")
+	}).collect())
+}
+
+/// Helper for `qti_fingerprint`; hashes the node at `ind` alone, returning
+/// whether it is a branch and the index after it.
+fn hash_node<H: std::hash::Hasher>(
+	bits: &QuadtreeEncodeBitVec,
+	header: &Header,
+	ind: usize,
+	hasher: &mut H
+) -> Result<(bool, usize), DecodeError> {
+	use std::hash::Hash;
+	let width = header.palette_width() as usize;
+	let node = bits.get(ind..ind + 1 + width).ok_or(DecodeError::InsufficientData)?;
+	let color = node[1..].iter().fold(0, |n, b| (n << 1) | *b as u32);
+	// The same as `QuadtreeNode::visual_hash`
+	Some(header.color(color).0).hash(hasher);
+	node[0].hash(hasher);
+	Ok((node[0], ind + 1 + width))
+}
+
+/// Helper for `qti_fingerprint`; hashes the node at `ind` and its subnodes,
+/// returning the index after them, or `TooDeep` if they go more than
+/// `max_depth` levels below it (if given).
+///
+/// Like `decode_nodes`, this keeps count of the subsections yet to be read
+/// of each branch rather than recursing, as the file may not be trusted.
+fn hash_nodes<H: std::hash::Hasher>(
+	bits: &QuadtreeEncodeBitVec,
+	header: &Header,
+	mut ind: usize,
+	max_depth: Option<usize>,
+	hasher: &mut H
+) -> Result<usize, DecodeError> {
+	let mut unread: Vec<u8> = Vec::new();
+	loop {
+		if max_depth.is_some_and(|max_depth| unread.len() > max_depth) {
+			return Err(DecodeError::TooDeep);
+		}
+		let (is_branch, next) = hash_node(bits, header, ind, hasher)?;
+		ind = next;
+		if is_branch {
+			unread.push(4);
+			continue;
+		}
+		// Finish the branches that this was the last subsection of
+		loop {
+			match unread.last_mut() {
+				None => return Ok(ind),
+				Some(1) => {
+					unread.pop();
+				},
+				Some(count) => {
+					*count -= 1;
+					break;
+				}
+			}
+		}
+	}
+}
+
+/// Hashes the image content of a QTI file: the tree structure and the colors
+/// its nodes resolve to, but not the palette order, padding or other header
+/// contents, so that files showing the same image give the same hash.
+///
+/// The tree is read as it is hashed, without being built in memory. The
+/// result is the same as `visual_hash` of the decoded tree and palette (as a
+/// `DynamicPaletteView`). Trees too deep for `from_qti` with the default
+/// `DecodeOptions` give `TooDeep` here too.
+pub fn qti_fingerprint(source: &[u8]) -> Result<u64, DecodeError> {
+	use std::hash::Hasher;
+	let header = read_header(source)?;
+	if header.version == 2 {
+		return Err(DecodeError::UnsupportedVersion(2));
+	}
+	let (_, bits) = header.tree_bits(source)?;
+	let max_depth = header.depth_limit(&Default::default());
+	let mut hasher = std::collections::hash_map::DefaultHasher::new();
+	match header.quadrant_offsets {
+		Some(offsets) => {
+			// Root node, then each quadrant from its own byte
+			if hash_node(&bits, &header, 0, &mut hasher)?.0 {
+				let max_depth = quadrant_depth_limit(max_depth)?;
+				for offset in offsets.iter() {
+					hash_nodes(&bits, &header, *offset as usize * 8, max_depth, &mut hasher)?;
+				}
+			}
+		},
+		None => {
+			hash_nodes(&bits, &header, 0, max_depth, &mut hasher)?;
+		}
+	}
+	Ok(hasher.finish())
+}
+
+impl Header {
+	/// The bit width of color numbers in the file.
+	pub fn palette_width(&self) -> u8 {
+		self.width
+	}
+
+	/// The color that color numbers past the end of the stored palette stand
+	/// for: transparent black, or the base color of an alpha palette at no
+	/// opacity.
+	pub fn implied_color(&self) -> image::Rgba<u8> {
+		let [r, g, b] = self.alpha_base.unwrap_or([0; 3]);
+		image::Rgba([r, g, b, 0])
+	}
+
+	/// The colors of the palette that `from_qti` gives: the stored ones, then
+	/// `implied_color` if there is room for it. Every color number past the
+	/// stored ones is read as that last one, so a file of a wide palette
+	/// with few colors stored doesn't need them all in memory.
+	pub fn palette_colors(&self) -> Vec<image::Rgba<u8>> {
+		let mut colors = self.palette.clone();
+		if !self.direct && (colors.len() as u64) < 1 << self.width {
+			colors.push(self.implied_color());
+		}
+		colors
+	}
+
+	/// The largest color number that trees of the file are decoded with;
+	/// larger ones are read as this (see `palette_colors`).
+	fn last_color(&self) -> u32 {
+		if self.direct {
+			u32::MAX
+		} else {
+			std::cmp::min(self.palette.len() as u64, (1 << self.width) - 1) as u32
+		}
+	}
+
+	/// The color that a color number of the file stands for.
+	fn color(&self, n: u32) -> image::Rgba<u8> {
+		if self.direct {
+			image::Rgba(n.to_be_bytes())
+		} else {
+			self.palette.get(n as usize).copied().unwrap_or_else(|| self.implied_color())
+		}
+	}
+
+	/// Extracts the bytes holding the quadtree content, including padding,
+	/// and just the content.
+	fn tree_bits(
+		&self,
+		source: &[u8]
+	) -> Result<(QuadtreeEncodeBitVec, QuadtreeEncodeBitVec), DecodeError> {
+		match self.tree_length {
+			Some(bit_len) => {
+				let section_bits = QuadtreeEncodeBitVec::from(source
+					.get(self.tree_offset..self.tree_offset + bit_len.div_ceil(8) as usize)
+					.ok_or(DecodeError::InsufficientData)?);
+				let mut tree_bits = section_bits.clone();
+				tree_bits.truncate(bit_len as usize);
+				Ok((section_bits, tree_bits))
+			},
+			None => {
+				let section_bits = QuadtreeEncodeBitVec::from(&source[self.tree_offset..]);
+				Ok((section_bits.clone(), section_bits))
+			}
+		}
+	}
+
+	/// The most levels below the root that a tree of this file may have,
+	/// with `options`: `max_depth`, or with `check_depth`, no more than its
+	/// dimensions allow, if fewer.
+	fn depth_limit(&self, options: &DecodeOptions) -> Option<usize> {
+		// Deeper levels would have nodes smaller than a pixel; the next power
+		// of two over 2^31 is 2^32
+		let by_dimensions = self.dimensions
+			.filter(|_| options.check_depth)
+			.map(|(width, height)| std::cmp::max(width, height)
+				.checked_next_power_of_two()
+				.map_or(32, |size| size.trailing_zeros() as usize));
+		match (options.max_depth, by_dimensions) {
+			(Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+			(a, b) => a.or(b)
+		}
+	}
+
+	/// Makes the palette as a `P`, checking that its width (which the tree's
+	/// color numbers are read with) is that of the file's palette, and that
+	/// it is direct if and only if the file is.
+	fn palette<P: DynamicPalette>(&self) -> Result<P, DecodeError> {
+		let palette = if self.direct {
+			P::direct().unwrap_or_else(|| P::from(Vec::new()))
+		} else {
+			P::with_width(self.palette_colors(), self.width)
+		};
+		if palette.width() != self.palette_width() || palette.is_direct() != self.direct {
+			return Err(DecodeError::WidthMismatch);
+		}
+		Ok(palette)
+	}
+}
+
+impl<P: DynamicPalette + Default + std::fmt::Debug> super::QuadtreeNode<P> {
 	/// Derives a palette and quadtree from the data of a QTI file.
+	///
+	/// The quadtree content is found at `Header::tree_offset`, just after
+	/// the header fields and palette that `read_header` reads, for every
+	/// version; see `to_qti_with` for round trips. Version 2 files give
+	/// `UnsupportedVersion(2)`.
 	pub fn from_qti(source: &[u8]) -> Result<(super::QuadtreeNode<P>, P), DecodeError> {
-		// Verify header (version 1 is required for compatibility)
-		if &source[..6] != b"QuTrIm" {
-			return Err(DecodeError::MissingHeader);
+		Self::from_qti_with(source, &Default::default())
+	}
+
+	/// Derives a palette and quadtree from the data of a QTI file, with
+	/// additional validation as set in `options`.
+	pub fn from_qti_with(
+		source: &[u8],
+		options: &DecodeOptions
+	) -> Result<(super::QuadtreeNode<P>, P), DecodeError> {
+		Self::decode_tree(source, &read_header(source)?, options)
+	}
+
+	/// Like `from_qti`, but reading the data from `reader`, as far as the end
+	/// of the quadtree content and no further, rather than from memory.
+	///
+	/// The header and palette are read first, and then the quadtree a chunk
+	/// at a time as it is decoded, so the data is never all in memory at
+	/// once.
+	///
+	/// Data that ends too soon gives `InsufficientData`, and errors from
+	/// `reader` are given as `Io`. Files without the tree length flag
+	/// (version 1, for one) are read to the end of `reader`.
+	pub fn read_qti<R: Read>(reader: &mut R) -> Result<(super::QuadtreeNode<P>, P), DecodeError> {
+		Self::read_qti_with(reader, &Default::default())
+	}
+
+	/// Like `read_qti`, with the validation of `from_qti_with`.
+	pub fn read_qti_with<R: Read>(
+		reader: &mut R,
+		options: &DecodeOptions
+	) -> Result<(super::QuadtreeNode<P>, P), DecodeError> {
+		Self::read_qti_with_header(reader, options).map(|(tree, palette, _)| (tree, palette))
+	}
+
+	/// Like `read_qti_with`, but also giving the header, as `read_header`
+	/// would read it, for the dimensions and such.
+	pub fn read_qti_with_header<R: Read>(
+		reader: &mut R,
+		options: &DecodeOptions
+	) -> Result<(super::QuadtreeNode<P>, P, Header), DecodeError> {
+		let mut source = ReadSource { reader, buffer: Vec::new() };
+		let header = parse_header(&mut source)?;
+		let palette = header.palette::<P>()?;
+		// Anything read past the header is the start of the quadtree
+		source.bytes(header.tree_offset, 0)?;
+		let start = source.buffer.split_off(header.tree_offset);
+		let mut reader = BitReader::new(source.reader, start, header.tree_length);
+		let width = palette.width();
+		let max_depth = header.depth_limit(options);
+		let last_color = header.last_color();
+		let mut tree: super::QuadtreeNode<P> = Default::default();
+		let mut padding_ok = true;
+		match (header.version, header.quadrant_offsets) {
+			(3, Some(offsets)) | (4, Some(offsets)) => {
+				// Root node, then each quadrant from its own byte
+				let should_recurse = reader.bit()?;
+				tree.color = std::cmp::min(reader.color(width)?, last_color);
+				if should_recurse {
+					let max_depth = quadrant_depth_limit(max_depth)?;
+					let mut sections: Box<[super::QuadtreeNode<P>; 4]> = Default::default();
+					for (section, offset) in sections.iter_mut().zip(offsets.iter()) {
+						let start = *offset as u64 * 8;
+						if start < reader.pos {
+							return Err(DecodeError::InsufficientData);
+						}
+						padding_ok &= reader.skip_to(start)?;
+						section.read_v1(&mut reader, width, max_depth, last_color)?;
+					}
+					tree.sections = Some(sections);
+				}
+			},
+			(1, _) | (3, _) | (4, _) => tree.read_v1(&mut reader, width, max_depth, last_color)?,
+			(version, _) => return Err(DecodeError::UnsupportedVersion(version))
 		}
-		let pal_size = (source[7] & 0x1f) + 1;
-		let pal_len = (
-			((source[7] >> 5) as f64 + 9.) *
-			(pal_size as f64 - 4.).exp2()
-		) as u32;
-		assert!(pal_len.count_ones() <= 4);
-		// Extract palette
-		let mut pal = vec![];
-		for offset in (0..pal_len).map(|n| n as usize * 4 + 8) {
-			pal.push(image::Rgba([
-				source[offset],
-				source[offset + 1],
-				source[offset + 2],
-				source[offset + 3],
-			]));
-		}
-		pal.resize(1 << pal_size, image::Rgba([0; 4]));
-		let palette = P::from(pal);
+		padding_ok &= reader.rest_is_zero()?;
+		tree.check_decoded(options, padding_ok)?;
+		Ok((tree, palette, header))
+	}
+
+	/// Helper for `from_qti_with` and `read_qti_with`; decodes the quadtree
+	/// of the QTI data in `source`, whose header is `header`.
+	fn decode_tree(
+		source: &[u8],
+		header: &Header,
+		options: &DecodeOptions
+	) -> Result<(super::QuadtreeNode<P>, P), DecodeError> {
+		let (section_bits, tree_bits) = header.tree_bits(source)?;
+		let tree_bits = &tree_bits;
+		let last_color = header.last_color();
+		let palette = header.palette::<P>()?;
+		let max_depth = header.depth_limit(options);
 		// Decode tree
-		let tree_bits = QuadtreeEncodeBitVec::from(&source[8 + 4 * pal_len as usize..]);
 		let mut tree: super::QuadtreeNode<P> = Default::default();
-		match source[6] {
-			1 => { // Version one, documented in older versions of qti_spec
-				tree.decode_v1(&tree_bits, &palette, 0)?;
-				Ok((tree, palette))
+		let mut padding_ok = true;
+		let tree_end = match (header.version, header.quadrant_offsets) {
+			(3, Some(offsets)) | (4, Some(offsets)) => {
+				// Root node, then each quadrant from its own byte
+				let root_end = 1 + palette.width() as usize;
+				if tree_bits.len() < root_end {
+					return Err(DecodeError::InsufficientData);
+				}
+				tree.color = std::cmp::min(tree_bits[1..root_end].iter()
+					.fold(0, |n, b| (n << 1) | *b as u32), last_color);
+				let mut curr_ind = root_end;
+				if tree_bits[0] {
+					let max_depth = quadrant_depth_limit(max_depth)?;
+					let mut sections: Box<[super::QuadtreeNode<P>; 4]> = Default::default();
+					for (section, offset) in sections.iter_mut().zip(offsets.iter()) {
+						let start = *offset as usize * 8;
+						if start < curr_ind || start >= tree_bits.len() {
+							return Err(DecodeError::InsufficientData);
+						}
+						padding_ok &= tree_bits[curr_ind..start].not_any();
+						curr_ind = section.decode_v1_limited(tree_bits, &palette, start, max_depth, last_color)?;
+					}
+					tree.sections = Some(sections);
+				}
+				curr_ind
 			},
-			2 => { // Version two (current) -- DOES NOT WORK; TODO
-				tree.decode_v2(&tree_bits, &palette, None)?;
-				Ok((tree, palette))
+			// Version one, documented in older versions of qti_spec;
+			// versions three and four only extend its header
+			(1, _) | (3, _) | (4, _) => tree.decode_v1_limited(tree_bits, &palette, 0, max_depth, last_color)?,
+			(2, _) => { // Version two (current) -- DOES NOT WORK; TODO
+				tree.decode_v2(tree_bits, &palette, None)?;
+				tree_bits.len()
 			},
-			_ => Err(DecodeError::MissingHeader)
+			(version, _) => return Err(DecodeError::UnsupportedVersion(version))
+		};
+		// Everything after the last node must be zero padding
+		tree.check_decoded(options, padding_ok && section_bits[tree_end..].not_any())?;
+		Ok((tree, palette))
+	}
+
+	/// Helper for `decode_tree` and `read_qti_with_header`; checks the
+	/// padding of the decoded tree's content as set in `options`, given
+	/// whether it was all zero, and normalizes the tree if asked to.
+	fn check_decoded(&mut self, options: &DecodeOptions, padding_ok: bool) -> Result<(), DecodeError> {
+		if !padding_ok && options.strict {
+			return Err(DecodeError::NonZeroPadding);
+		}
+		if options.normalize {
+			self.normalize();
+		}
+		Ok(())
+	}
+
+	/// Decodes just one quadrant (0 to 3, in the same order as `sections`)
+	/// of a QTI file written with `EncodeOptions::quadrant_offsets`, without
+	/// decoding any of the others.
+	///
+	/// Returns `Ok(None)` if the root node has no subsections.
+	pub fn quadrant_from_qti(
+		source: &[u8],
+		quadrant: usize
+	) -> Result<Option<(super::QuadtreeNode<P>, P)>, DecodeError> {
+		let header = read_header(source)?;
+		let offsets = header.quadrant_offsets.ok_or(DecodeError::MissingHeader)?;
+		let (_, tree_bits) = header.tree_bits(source)?;
+		if !*tree_bits.first().ok_or(DecodeError::InsufficientData)? {
+			return Ok(None);
+		}
+		let start = *offsets.get(quadrant).ok_or(DecodeError::InsufficientData)? as usize * 8;
+		if start >= tree_bits.len() {
+			return Err(DecodeError::InsufficientData);
+		}
+		let palette = header.palette::<P>()?;
+		let mut tree: super::QuadtreeNode<P> = Default::default();
+		let max_depth = quadrant_depth_limit(header.depth_limit(&Default::default()))?;
+		tree.decode_v1_limited(&tree_bits, &palette, start, max_depth, header.last_color())?;
+		Ok(Some((tree, palette)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::image::{AnalyzeOptions, RenderOptions};
+	use crate::quantize::palette::{AlphaPalette, Color, DynamicPaletteView};
+	use crate::test_util::{noise, Lcg};
+	use crate::QuadtreeNode;
+
+	/// `n` distinct opaque colors.
+	fn colors(n: usize) -> Vec<Color> {
+		(0..n).map(|c| image::Rgba([c as u8, (c >> 8) as u8, 255 - c as u8, 255])).collect()
+	}
+
+	/// A tree of `image` (color numbers) on `palette`, mounted without
+	/// gradients.
+	fn mounted<P: Palette + Default>(image: &[u32], palette: &P) -> QuadtreeNode<P> {
+		let mut tree = QuadtreeNode::default();
+		tree.mount_with(image, palette, &AnalyzeOptions::default().gradient_fit(None)).unwrap();
+		tree
+	}
+
+	#[test]
+	fn alpha_palette_round_trip() {
+		let alphas = (0..16).map(|a| a * 17).collect::<Vec<u8>>();
+		let palette = AlphaPalette { base: [10, 20, 30], alphas: alphas.clone().into_boxed_slice() };
+		let image = noise(16, 16, 1);
+		let tree = mounted(&image, &palette);
+		let data = tree.to_qti(&palette).unwrap();
+
+		// The same colors stored in full take four bytes each rather than one,
+		// less the three of the base color
+		let colors = (0..16).map(|c| palette.to_rgba(c).unwrap()).collect::<Vec<Color>>();
+		let rgba_palette = DynamicPaletteView::from(colors);
+		let rgba_data = mounted(&image, &rgba_palette).to_qti(&rgba_palette).unwrap();
+		assert_eq!(rgba_data.len() - data.len(), 3 * 16 - 3);
+
+		let (decoded, decoded_palette) = QuadtreeNode::<AlphaPalette>::from_qti(&data).unwrap();
+		assert_eq!(decoded_palette.base, [10, 20, 30]);
+		assert_eq!(&decoded_palette.alphas[..16], &alphas[..]);
+		assert_eq!(decoded.to_qti(&decoded_palette).unwrap(), data);
+	}
+
+	#[test]
+	fn rejects_trees_deeper_than_dimensions() {
+		let palette = DynamicPaletteView::from(colors(4));
+		let tree = mounted(&noise(8, 4, 2), &palette);
+		assert_eq!(tree.depth(), 3);
+		let with_dimensions = |dimensions| tree.to_qti_with(&palette, &EncodeOptions {
+			dimensions: Some(dimensions),
+			..Default::default()
+		}).unwrap();
+		let plain = tree.to_qti(&palette).unwrap();
+		// Dimensions that aren't powers of two are drawn at the next one up
+		for dimensions in [(8, 8), (5, 5), (8, 1)] {
+			let (decoded, _) = QuadtreeNode::<DynamicPaletteView>::from_qti(&with_dimensions(dimensions)).unwrap();
+			assert_eq!(decoded.to_qti(&palette).unwrap(), plain);
+		}
+		let unchecked = DecodeOptions { check_depth: false, ..Default::default() };
+		for dimensions in [(4, 4), (3, 3), (1, 1), (0, 0)] {
+			let data = with_dimensions(dimensions);
+			assert!(matches!(QuadtreeNode::<DynamicPaletteView>::from_qti(&data), Err(DecodeError::TooDeep)));
+			let (decoded, _) = QuadtreeNode::<DynamicPaletteView>::from_qti_with(&data, &unchecked).unwrap();
+			assert_eq!(decoded.to_qti(&palette).unwrap(), plain);
+		}
+	}
+
+	#[test]
+	fn malformed_input_gives_errors() {
+		let palette = DynamicPaletteView::from(colors(5));
+		let tree = mounted(&noise(8, 5, 3), &palette);
+		let data = tree.to_qti_with(&palette, &EncodeOptions {
+			dimensions: Some((8, 8)),
+			quadrant_offsets: true,
+			..Default::default()
+		}).unwrap();
+		for len in 0..data.len() {
+			assert!(matches!(
+				QuadtreeNode::<DynamicPaletteView>::from_qti(&data[..len]),
+				Err(DecodeError::InsufficientData) | Err(DecodeError::MissingHeader)
+			), "{} bytes", len);
+		}
+		// Nor may dimensions too large for their next power of two
+		let huge = tree.to_qti_with(&palette, &EncodeOptions { dimensions: Some((u32::MAX, 1)), ..Default::default() }).unwrap();
+		assert!(QuadtreeNode::<DynamicPaletteView>::from_qti(&huge).is_ok());
+		// Corrupt data may happen to make another valid file, but mustn't panic
+		let mut rng = Lcg(4);
+		for _ in 0..2000 {
+			let mut corrupt = data.clone();
+			for _ in 0..3 {
+				let ind = rng.below(corrupt.len() as u32) as usize;
+				corrupt[ind] = rng.next_u32() as u8;
+			}
+			let _ = read_header(&corrupt);
+			let _ = QuadtreeNode::<DynamicPaletteView>::from_qti(&corrupt);
+			let _ = QuadtreeNode::<DynamicPaletteView>::quadrant_from_qti(&corrupt, 3);
+		}
+	}
+
+	/// Checks that `data` decodes to `tree` from a slice and from a reader,
+	/// but gives `NonZeroPadding` for both with `DecodeOptions::strict`.
+	fn check_nonzero_padding(data: &[u8], tree: &QuadtreeNode<DynamicPaletteView>) {
+		let strict = DecodeOptions { strict: true, ..Default::default() };
+		assert_eq!(&QuadtreeNode::<DynamicPaletteView>::from_qti(data).unwrap().0, tree);
+		assert_eq!(&QuadtreeNode::<DynamicPaletteView>::read_qti(&mut &data[..]).unwrap().0, tree);
+		assert!(matches!(
+			QuadtreeNode::<DynamicPaletteView>::from_qti_with(data, &strict),
+			Err(DecodeError::NonZeroPadding)
+		));
+		assert!(matches!(
+			QuadtreeNode::<DynamicPaletteView>::read_qti_with(&mut &data[..], &strict),
+			Err(DecodeError::NonZeroPadding)
+		));
+	}
+
+	/// A writer that takes at most a few bytes a call, and fails once it has
+	/// taken `limit` bytes.
+	struct FailingWriter {
+		written: Vec<u8>,
+		limit: usize,
+	}
+
+	impl std::io::Write for FailingWriter {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			let len = std::cmp::min(std::cmp::min(buf.len(), 7), self.limit - self.written.len());
+			if len == 0 && !buf.is_empty() {
+				return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "limit reached"));
+			}
+			self.written.extend_from_slice(&buf[..len]);
+			Ok(len)
+		}
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn writing_streams_and_propagates_errors() {
+		let palette = DynamicPaletteView::from(colors(16));
+		// Several chunks of quadtree content
+		let tree = mounted(&noise(128, 16, 27), &palette);
+		for options in [Default::default(), EncodeOptions { quadrant_offsets: true, ..Default::default() }] {
+			let data = tree.to_qti_with(&palette, &options).unwrap();
+			assert!(data.len() > 2 * WRITE_CHUNK_BYTES);
+			let mut out = Vec::new();
+			assert_eq!(tree.write_qti_with(&palette, &options, &mut out).unwrap(), data.len());
+			assert_eq!(out, data);
+
+			for limit in [0, 5, 100, WRITE_CHUNK_BYTES, data.len() / 2, data.len() - 1, data.len()] {
+				let mut out = FailingWriter { written: Vec::new(), limit };
+				let result = tree.write_qti_with(&palette, &options, &mut out);
+				if limit == data.len() {
+					assert_eq!(result.unwrap(), data.len());
+				} else {
+					assert!(matches!(result, Err(QtiWriteError::Io(ref e)) if e.kind() == std::io::ErrorKind::WriteZero));
+				}
+				assert_eq!(out.written, &data[..limit]);
+			}
+		}
+
+		// Trees that can't be encoded fail before anything is written
+		let bad = QuadtreeNode::<DynamicPaletteView> { color: 16, ..Default::default() };
+		let mut out = FailingWriter { written: Vec::new(), limit: usize::MAX };
+		assert!(matches!(
+			bad.write_qti(&palette, &mut out),
+			Err(QtiWriteError::Encode(EncodeError::ColorOutOfRange { index: 16, .. }))
+		));
+		assert!(out.written.is_empty());
+	}
+
+	/// A reader that gives one byte a call.
+	struct OneByteReader<'a>(&'a [u8]);
+
+	impl Read for OneByteReader<'_> {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			match (self.0.split_first(), buf.first_mut()) {
+				(Some((byte, rest)), Some(first)) => {
+					*first = *byte;
+					self.0 = rest;
+					Ok(1)
+				},
+				_ => Ok(0)
+			}
+		}
+	}
+
+	#[test]
+	fn reading_a_byte_at_a_time() {
+		let palette = DynamicPaletteView::from(colors(16));
+		let tree = mounted(&noise(64, 16, 29), &palette);
+		let all_options = [
+			Default::default(),
+			EncodeOptions { quadrant_offsets: true, dimensions: Some((60, 50)), ..Default::default() },
+			EncodeOptions { compact_palette: true, embed_thumbnail: true, ..Default::default() },
+		];
+		for options in all_options.iter() {
+			let data = tree.to_qti_with(&palette, options).unwrap();
+			// The reader is left just past the quadtree content
+			let mut with_more = data.clone();
+			with_more.extend_from_slice(b"more");
+			let mut reader = OneByteReader(&with_more);
+			let (decoded, decoded_palette, header) =
+				QuadtreeNode::<DynamicPaletteView>::read_qti_with_header(&mut reader, &Default::default()).unwrap();
+			assert_eq!((&decoded, &decoded_palette), (&tree, &palette));
+			assert_eq!(header.dimensions, options.dimensions);
+			assert_eq!(reader.0, b"more");
+
+			// Every byte of the header, and a few hundred lengths past it
+			let lens = (0..header.tree_offset).chain((header.tree_offset..data.len()).step_by(1 + data.len() / 300));
+			for len in lens.chain(std::iter::once(data.len() - 1)) {
+				let result = QuadtreeNode::<DynamicPaletteView>::read_qti(&mut OneByteReader(&data[..len]));
+				match result {
+					Err(DecodeError::InsufficientData) => (),
+					Err(DecodeError::MissingHeader) if len < MAGIC.len() => (),
+					_ => panic!("{} of {} bytes gave {:?}", len, data.len(), result.map(|_| ()))
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn unknown_versions_are_unsupported() {
+		let palette = DynamicPaletteView::from(colors(5));
+		let tree = mounted(&noise(8, 5, 24), &palette);
+		let data = tree.to_qti(&palette).unwrap();
+		for version in [0, 5, 255] {
+			let mut data = data.clone();
+			data[6] = version;
+			assert!(matches!(read_header(&data), Err(DecodeError::UnsupportedVersion(v)) if v == version));
+			assert!(matches!(
+				QuadtreeNode::<DynamicPaletteView>::from_qti(&data),
+				Err(DecodeError::UnsupportedVersion(v)) if v == version
+			));
+			assert!(matches!(
+				QuadtreeNode::<DynamicPaletteView>::read_qti(&mut &data[..]),
+				Err(DecodeError::UnsupportedVersion(v)) if v == version
+			));
+		}
+		// Version 2 has a header that can be read, but not a tree
+		let mut data_v2 = data.clone();
+		data_v2[6] = 2;
+		assert!(read_header(&data_v2).is_ok());
+		assert!(matches!(
+			QuadtreeNode::<DynamicPaletteView>::from_qti(&data_v2),
+			Err(DecodeError::UnsupportedVersion(2))
+		));
+		// Version 3 was once unknown too, but is now what's written
+		assert_eq!(data[6], 3);
+		assert_eq!(QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap(), (tree, palette));
+	}
+
+	#[test]
+	fn strict_decoding_rejects_nonzero_padding() {
+		let strict = DecodeOptions { strict: true, ..Default::default() };
+		// Four bits a node, so an odd number of them ends half way into a byte
+		let palette = DynamicPaletteView::from(colors(8));
+		let tree = mounted(&noise(4, 8, 5), &palette);
+		let mut tree_bits = QuadtreeEncodeBitVec::new();
+		tree.encode_v1(&mut tree_bits, &palette).unwrap();
+		assert_eq!(tree_bits.len() % 8, 4);
+
+		let data = tree.to_qti(&palette).unwrap();
+		assert_eq!(QuadtreeNode::<DynamicPaletteView>::from_qti_with(&data, &strict).unwrap().0, tree);
+		let mut padded = data.clone();
+		*padded.last_mut().unwrap() |= 1;
+		check_nonzero_padding(&padded, &tree);
+
+		// Padding before each quadrant counts as well as that at the end
+		let options = EncodeOptions { quadrant_offsets: true, ..Default::default() };
+		let data = tree.to_qti_with(&palette, &options).unwrap();
+		assert_eq!(QuadtreeNode::<DynamicPaletteView>::from_qti_with(&data, &strict).unwrap().0, tree);
+		let header = read_header(&data).unwrap();
+		for offset in header.quadrant_offsets.unwrap().iter() {
+			let mut padded = data.clone();
+			padded[header.tree_offset + *offset as usize - 1] |= 1;
+			check_nonzero_padding(&padded, &tree);
 		}
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn quadrants_decode_from_their_offsets() {
+		let palette = DynamicPaletteView::from(colors(8));
+		let tree = mounted(&noise(16, 8, 6), &palette);
+		let options = EncodeOptions { quadrant_offsets: true, ..Default::default() };
+		let data = tree.to_qti_with(&palette, &options).unwrap();
+		let plain = tree.to_qti(&palette).unwrap();
+		assert_eq!(QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap().0.to_qti(&palette).unwrap(), plain);
+		for (quadrant, section) in tree.sections.as_ref().unwrap().iter().enumerate() {
+			let (decoded, decoded_palette) = QuadtreeNode::<DynamicPaletteView>::quadrant_from_qti(&data, quadrant)
+				.unwrap()
+				.unwrap();
+			assert_eq!(decoded.to_qti(&palette).unwrap(), section.to_qti(&palette).unwrap());
+			assert_eq!(decoded_palette, palette);
+		}
+
+		let leaf = QuadtreeNode::<DynamicPaletteView> { color: 3, ..Default::default() };
+		let data = leaf.to_qti_with(&palette, &options).unwrap();
+		assert!(QuadtreeNode::<DynamicPaletteView>::quadrant_from_qti(&data, 0).unwrap().is_none());
+		assert!(matches!(
+			QuadtreeNode::<DynamicPaletteView>::quadrant_from_qti(&plain, 0),
+			Err(DecodeError::MissingHeader)
+		));
+	}
+
+	#[test]
+	fn thumbnail_round_trip() {
+		let palette = DynamicPaletteView::from(colors(7));
+		let tree = mounted(&noise(64, 7, 7), &palette);
+		let plain = tree.to_qti(&palette).unwrap();
+		let data = tree.to_qti_with(&palette, &EncodeOptions { embed_thumbnail: true, ..Default::default() }).unwrap();
+		let mut expected = image::RgbaImage::new(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+		tree.to_image(&mut expected, &palette, None, None, false, None).unwrap();
+		assert_eq!(read_embedded_thumbnail(&data), Some(expected));
+		assert_eq!(QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap().0.to_qti(&palette).unwrap(), plain);
+
+		let data = plain.clone();
+		assert_eq!(read_embedded_thumbnail(&data), None);
+		assert_eq!(QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap().0.to_qti(&palette).unwrap(), plain);
+	}
+
+	#[test]
+	fn compact_palettes_store_only_used_colors() {
+		let palette = DynamicPaletteView::from(colors(5));
+		let tree = mounted(&noise(16, 5, 8), &palette);
+		let data = tree.to_qti_with(&palette, &EncodeOptions { compact_palette: true, ..Default::default() }).unwrap();
+		let header = read_header(&data).unwrap();
+		assert_eq!((header.version, header.palette_width(), header.palette.len()), (4, 3, 5));
+		let (decoded, decoded_palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+		assert_eq!(decoded.to_qti(&decoded_palette).unwrap(), tree.to_qti(&palette).unwrap());
+
+		// A few colors of a wide palette, which are read back without padding
+		// the palette out to its full width
+		let palette = DynamicPaletteView::with_width(colors(3), 12);
+		let tree = mounted(&noise(8, 3, 23), &palette);
+		let data = tree.to_qti_with(&palette, &EncodeOptions { compact_palette: true, ..Default::default() }).unwrap();
+		let header = read_header(&data).unwrap();
+		assert_eq!((header.palette_width(), header.palette.len()), (12, 3));
+		let (decoded, decoded_palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+		assert_eq!((decoded_palette.width(), decoded_palette.len()), (12, 4));
+		assert_eq!(decoded.to_qti_with(&decoded_palette, &EncodeOptions { compact_palette: true, ..Default::default() }).unwrap(), data);
+	}
+
+	#[test]
+	fn unified_files_share_a_palette() {
+		// A color on every level of a deep tree
+		let mut deep_image = vec![0; 64 * 64];
+		deep_image[0] = 1;
+		let deep_palette = DynamicPaletteView::from(colors(2));
+		let mut deep_tree = QuadtreeNode::default();
+		deep_tree.mount_with(&deep_image, &deep_palette, &AnalyzeOptions::default().sensitivity(16384).gradient_fit(None)).unwrap();
+		assert_eq!(deep_tree.depth(), 6);
+		let noise_palette = DynamicPaletteView::from(colors(5).into_iter().rev().collect::<Vec<_>>());
+		let sources = [
+			deep_tree.to_qti(&deep_palette).unwrap(),
+			mounted(&noise(16, 5, 7), &noise_palette).to_qti(&noise_palette).unwrap(),
+			std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/ferris_comp.qti")).unwrap(),
+		];
+		let unified = unify_palettes(&sources.iter().map(Vec::as_slice).collect::<Vec<_>>()).unwrap();
+		assert_eq!(unified.len(), sources.len());
+
+		let render = |data: &[u8]| {
+			let (tree, palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(data).unwrap();
+			let mut img = image::RgbaImage::new(256, 256);
+			tree.to_image_with(&mut img, &palette, &RenderOptions { gradient: true, ..Default::default() }).unwrap();
+			img
+		};
+		let shared = read_header(&unified[0]).unwrap().palette;
+		for (source, data) in sources.iter().zip(unified.iter()) {
+			let (header, unified_header) = (read_header(source).unwrap(), read_header(data).unwrap());
+			assert_eq!(unified_header.palette, shared);
+			assert_eq!(unified_header.dimensions, header.dimensions);
+			assert!(header.palette.iter().all(|c| shared.contains(c)));
+			assert!(render(data) == render(source));
+		}
+	}
+
+	#[test]
+	fn same_image_files_share_a_fingerprint() {
+		let palette = DynamicPaletteView::from(colors(5));
+		let tree = mounted(&noise(16, 5, 9), &palette);
+		let data = tree.to_qti(&palette).unwrap();
+		let fingerprint = qti_fingerprint(&data).unwrap();
+		assert_eq!(fingerprint, tree.visual_hash(&palette));
+
+		let mut padded = data.clone();
+		*padded.last_mut().unwrap() |= 1;
+		let reordered = DynamicPaletteView::from(colors(5).into_iter().rev().collect::<Vec<_>>());
+		let mut extended = colors(5);
+		extended.push(image::Rgba([1, 2, 3, 4]));
+		let extended = DynamicPaletteView::from(extended);
+		let variants = [
+			padded,
+			tree.to_qti_with(&palette, &EncodeOptions {
+				quadrant_offsets: true,
+				embed_thumbnail: true,
+				compact_palette: true,
+				..Default::default()
+			}).unwrap(),
+			tree.reindex_to_palette(&palette, &reordered).unwrap().to_qti(&reordered).unwrap(),
+			tree.to_qti(&extended).unwrap(),
+		];
+		for variant in variants.iter() {
+			assert_ne!(variant, &data);
+			assert_eq!(qti_fingerprint(variant).unwrap(), fingerprint);
+		}
+
+		let mut changed = mounted(&noise(16, 5, 9), &palette);
+		let mut leaf = &mut changed;
+		while let Some(sections) = leaf.sections.as_mut() {
+			leaf = &mut sections[0];
+		}
+		leaf.color = (leaf.color + 1) % 5;
+		assert_ne!(qti_fingerprint(&changed.to_qti(&palette).unwrap()).unwrap(), fingerprint);
+	}
+
+	#[test]
+	fn truncated_bits_give_insufficient_data() {
+		let palette = DynamicPaletteView::from(colors(5));
+		let tree = mounted(&noise(8, 5, 10), &palette);
+		let mut bits = QuadtreeEncodeBitVec::new();
+		tree.encode_v1(&mut bits, &palette).unwrap();
+		let mut decoded = QuadtreeNode::default();
+		assert_eq!(decoded.decode_v1(&bits, &palette, 0).unwrap(), bits.len());
+		assert_eq!(decoded, tree);
+
+		for len in 0..bits.len() {
+			let mut truncated = bits.clone();
+			truncated.truncate(len);
+			let result = QuadtreeNode::default().decode_v1(&truncated, &palette, 0);
+			assert!(matches!(result, Err(DecodeError::InsufficientData)), "{} bits", len);
+		}
+		// Starting at or past the end, however far
+		for start in [bits.len(), bits.len() + 1, bits.len() + 100, usize::MAX - 1, usize::MAX] {
+			let result = QuadtreeNode::default().decode_v1(&bits, &palette, start);
+			assert!(matches!(result, Err(DecodeError::InsufficientData)), "from {}", start);
+		}
+	}
+
+	#[test]
+	fn narrow_palettes_round_trip() {
+		for width in 1..=8u8 {
+			let extent = 1usize << width;
+			// Palettes full, with room to spare, and ending in transparent
+			// entries that the file may drop
+			for (color_count, transparent) in [(extent, 0), (extent / 2 + 1, 0), (1, extent - 1), (extent / 2, extent / 2 - 1)] {
+				let mut palette_colors = colors(color_count);
+				palette_colors.resize(color_count + transparent, image::Rgba([0; 4]));
+				let palette = DynamicPaletteView::with_width(palette_colors, width);
+				let tree = mounted(&noise(16, color_count as u32, width as u64), &palette);
+				for options in [
+					EncodeOptions::default(),
+					// Odd dimensions that need the whole 16 by 16 tree
+					EncodeOptions { dimensions: Some((15, 9)), ..Default::default() },
+					EncodeOptions { compact_palette: true, ..Default::default() },
+				] {
+					let data = tree.to_qti_with(&palette, &options).unwrap();
+					let header = read_header(&data).unwrap();
+					assert_eq!((header.palette_width(), header.dimensions), (width, options.dimensions));
+					if options.compact_palette {
+						assert_eq!(header.palette.len(), color_count);
+					} else {
+						assert!((color_count..=extent).contains(&header.palette.len()));
+					}
+					let (decoded, decoded_palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+					assert_eq!(decoded_palette, palette, "width {}, {} + {} colors", width, color_count, transparent);
+					assert_eq!(decoded, tree);
+					assert_eq!(decoded.to_qti_with(&decoded_palette, &options).unwrap(), data);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn used_transparent_entries_round_trip() {
+		// Sprite colors, then a clear white, then transparent black entries,
+		// all of them used
+		let mut palette_colors = colors(4);
+		palette_colors.push(image::Rgba([255, 255, 255, 0]));
+		palette_colors.resize(16, image::Rgba([0; 4]));
+		let palette = DynamicPaletteView::from(palette_colors);
+		let image = noise(32, 16, 21);
+		let tree = mounted(&image, &palette);
+		assert!(tree.colors_used().contains(&15));
+		let mut expected = image::RgbaImage::new(32, 32);
+		tree.to_image_with(&mut expected, &palette, &Default::default()).unwrap();
+		for compact_palette in [false, true] {
+			let data = tree.to_qti_with(&palette, &EncodeOptions { compact_palette, ..Default::default() }).unwrap();
+			// All of them, as the last is used
+			assert_eq!(read_header(&data).unwrap().palette.len(), 16);
+			let (decoded, decoded_palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+			assert_eq!((&decoded, &decoded_palette), (&tree, &palette));
+			assert_eq!(decoded_palette.to_rgba(4), Ok(image::Rgba([255, 255, 255, 0])));
+			assert_eq!(decoded_palette.to_rgba(15), Ok(image::Rgba([0; 4])));
+			let mut drawn = image::RgbaImage::new(32, 32);
+			decoded.to_image_with(&mut drawn, &decoded_palette, &Default::default()).unwrap();
+			assert!(drawn == expected);
+		}
+	}
+
+	#[test]
+	fn palette_lengths_fit_the_size_byte() {
+		for width in 1..=8u8 {
+			let extent = 1usize << width;
+			for color_count in [1, 2, 3, extent].iter().copied().filter(|&n| n <= extent) {
+				let palette = DynamicPaletteView::with_width(colors(color_count), width);
+				let tree = mounted(&noise(8, color_count as u32, 22), &palette);
+				let data = tree.to_qti(&palette).unwrap();
+				// `(n + 9) * 2^(width - 4)` for the three bits of `n`
+				let stored = ((data[7] >> 5) as usize + 9) * extent;
+				assert_eq!(stored % 16, 0, "width {}, {} colors", width, color_count);
+				let least = std::cmp::max(color_count, (9 * extent).div_ceil(16));
+				assert_eq!(stored / 16, least.next_multiple_of(extent.div_ceil(16)));
+				assert_eq!(data[7] & 0x1f, width - 1);
+				let header = read_header(&data).unwrap();
+				assert_eq!(header.palette.len(), stored / 16);
+				assert_eq!(QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap(), (tree, palette));
+			}
+		}
+
+		// A few colors of the widest palette, which only a compact palette
+		// can store
+		let palette = DynamicPaletteView::with_width(colors(3), 32);
+		let tree = mounted(&noise(8, 3, 23), &palette);
+		let data = tree.to_qti_with(&palette, &EncodeOptions { compact_palette: true, ..Default::default() }).unwrap();
+		assert_eq!(read_header(&data).unwrap().palette.len(), 3);
+		assert_eq!(QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap(), (tree, palette));
+	}
+
+	#[test]
+	fn wide_palettes_round_trip() {
+		use crate::quantize::palette::{PaletteView12, PaletteView16};
+
+		let palette = PaletteView12::from(colors(3000));
+		let tree = mounted(&noise(32, 3000, 18), &palette);
+		let data = tree.to_qti(&palette).unwrap();
+		assert_eq!(read_header(&data).unwrap().palette_width(), 12);
+		assert_eq!(QuadtreeNode::<PaletteView12>::from_qti(&data).unwrap(), (tree, palette));
+
+		// Color numbers with the top bit of each byte set
+		let image = noise(64, 1 << 16, 19).iter().map(|c| c | 0x8080).collect::<Vec<_>>();
+		let palette = PaletteView16::from(colors(1 << 16));
+		let tree = mounted(&image, &palette);
+		let data = tree.to_qti(&palette).unwrap();
+		let header = read_header(&data).unwrap();
+		assert_eq!((header.palette_width(), header.palette.len()), (16, 1 << 16));
+		let (decoded, decoded_palette) = QuadtreeNode::<PaletteView16>::from_qti(&data).unwrap();
+		assert_eq!(decoded_palette, palette);
+		assert_eq!(decoded, tree);
+		let (decoded, decoded_palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+		assert_eq!(decoded_palette.width(), 16);
+		assert_eq!(decoded.to_qti(&decoded_palette).unwrap(), data);
+	}
+
+	#[test]
+	fn size_estimates_are_exact() {
+		let option_sets = [
+			EncodeOptions::default(),
+			EncodeOptions { dimensions: Some((100, 60)), flat: true, ..Default::default() },
+			EncodeOptions { quadrant_offsets: true, compact_palette: true, ..Default::default() },
+			EncodeOptions { embed_thumbnail: true, dimensions: Some((1, 1)), ..Default::default() },
+		];
+		let mut rng = crate::test_util::Lcg(17);
+		for (size, color_count) in [(1, 1), (2, 2), (8, 3), (16, 16), (32, 200), (64, 5)] {
+			let palette = DynamicPaletteView::from(colors(color_count));
+			// Sparse trees as well as full ones
+			let image = noise(size, color_count as u32, rng.next_u32() as u64);
+			let sparse = image.iter().enumerate().map(|(i, c)| if i % size < size / 2 { 0 } else { *c }).collect::<Vec<_>>();
+			for image in [image, sparse] {
+				let tree = mounted(&image, &palette);
+				for options in &option_sets {
+					let estimate = tree.encoded_size_estimate_with(&palette, options);
+					assert_eq!(estimate, tree.to_qti_with(&palette, options).unwrap().len(), "{:?}", options);
+				}
+				assert_eq!(tree.encoded_size_estimate(&palette), tree.to_qti(&palette).unwrap().len());
+			}
+		}
+	}
+}
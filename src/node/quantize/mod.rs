@@ -5,7 +5,7 @@ use std::collections::HashMap;
 type BigColor = image::Rgba<isize>;
 
 fn abs_sub(a: u8, b: u8) -> u8 {
-	(a as i16 - b as i16).abs() as u8
+	(a as i16 - b as i16).unsigned_abs() as u8
 }
 
 fn vec4_len_squared(a: u8, b: u8, c: u8, d: u8) -> u32 {
@@ -33,6 +33,100 @@ fn dedup_distance(a: &palette::Color, b: &palette::Color) -> u32 {
 	)
 }
 
+/// How the difference between two colors is measured for palette generation
+/// and quantization.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DistanceMetric {
+	/// Squared Euclidean distance in RGBA.
+	#[default]
+	Euclidean,
+	/// Squared Euclidean distance in RGBA, with the color channels weighted
+	/// by their contribution to luma (0.299, 0.587 and 0.114, scaled to sum
+	/// to 3).
+	WeightedRgb,
+	/// Squared Euclidean distance in CIELAB (CIE76 delta E), scaled so that
+	/// lightness runs from 0 to 255, plus squared alpha difference.
+	Lab,
+}
+
+/// Converts an sRGB channel value to linear light, from 0 to 1.
+pub fn srgb_to_linear(v: u8) -> f64 {
+	let v = v as f64 / 255.;
+	if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Converts a linear light value (clamped to 0 to 1) back to an sRGB channel
+/// value, rounding to the nearest; the inverse of `srgb_to_linear`.
+pub fn linear_to_srgb(v: f64) -> u8 {
+	let v = v.clamp(0., 1.);
+	let v = if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1. / 2.4) - 0.055 };
+	(v * 255.).round() as u8
+}
+
+/// Converts an sRGB color to CIELAB (D65 white point).
+fn srgb_to_lab(c: &palette::Color) -> [f64; 3] {
+	let (r, g, b) = (srgb_to_linear(c.0[0]), srgb_to_linear(c.0[1]), srgb_to_linear(c.0[2]));
+	let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+	let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+	let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+	let f = |t: f64| if t > 216. / 24389. { t.cbrt() } else { (24389. / 27. * t + 16.) / 116. };
+	let (fx, fy, fz) = (f(x), f(y), f(z));
+	[116. * fy - 16., 500. * (fx - fy), 200. * (fy - fz)]
+}
+
+impl DistanceMetric {
+	/// Position of a color in the space that the metric measures straight-line
+	/// distance in, as three color coordinates and alpha.
+	fn coordinates(&self, c: &palette::Color) -> [f64; 4] {
+		let [r, g, b, a] = c.0.map(|v| v as f64);
+		match self {
+			DistanceMetric::Euclidean => [r, g, b, a],
+			DistanceMetric::WeightedRgb => [
+				r * (3. * 0.299f64).sqrt(),
+				g * (3. * 0.587f64).sqrt(),
+				b * (3. * 0.114f64).sqrt(),
+				a,
+			],
+			DistanceMetric::Lab => {
+				let lab = srgb_to_lab(c);
+				[2.55 * lab[0], 2.55 * lab[1], 2.55 * lab[2], a]
+			}
+		}
+	}
+
+	/// Squared distance between two points from `coordinates`, with alpha
+	/// scaled by `alpha_scale`.
+	fn coordinate_distance(a: &[f64; 4], b: &[f64; 4], alpha_scale: f64) -> u32 {
+		((a[0] - b[0]).powi(2) +
+			(a[1] - b[1]).powi(2) +
+			(a[2] - b[2]).powi(2) +
+			((a[3] - b[3]) * alpha_scale).powi(2)).round() as u32
+	}
+
+	/// Squared distance between two colors, with alpha scaled by
+	/// `alpha_scale`.
+	fn distance_scaled(&self, a: &palette::Color, b: &palette::Color, alpha_scale: f64) -> u32 {
+		Self::coordinate_distance(&self.coordinates(a), &self.coordinates(b), alpha_scale)
+	}
+
+	/// Squared distance between two colors, as used for quantization.
+	pub fn distance(&self, a: &palette::Color, b: &palette::Color) -> u32 {
+		match self {
+			DistanceMetric::Euclidean => color_distance(a, b),
+			_ => self.distance_scaled(a, b, 1.)
+		}
+	}
+
+	/// Squared distance between two colors, as used for palette
+	/// deduplication; alpha is less important.
+	pub fn dedup_distance(&self, a: &palette::Color, b: &palette::Color) -> u32 {
+		match self {
+			DistanceMetric::Euclidean => dedup_distance(a, b),
+			_ => self.distance_scaled(a, b, 0.25)
+		}
+	}
+}
+
 fn color_add_big(a: BigColor, b: BigColor) -> BigColor {
 	image::Rgba::<isize>([
 		a.0[0] + b.0[0],
@@ -60,76 +154,1120 @@ fn color_div(a: BigColor, b: isize) -> palette::Color {
 	])
 }
 
-/// Selects a palette of a given size and type through a process similar to
-/// (but not quite the same as) finding the most commonly used colors in the image.
+/// Lists the colors in a palette, in order, up to its `len` (but at least
+/// one, even if that is just a placeholder), or up to the first color number
+/// that `to_rgba` gives an error for. Palettes with no colors (and no
+/// placeholder) give none.
 ///
-/// `dedup_thresh` indicates the (squared) limit for how "distant" colors can be
-/// while still being quantized as one color.
-pub fn generate_palette<P: palette::DynamicPalette>(
-	img: &image::RgbaImage,
-	dedup_thresh: u32
-) -> P {
+/// Direct palettes (see `Palette::is_direct`) have billions of colors, so
+/// they should be searched with `NearestColor::for_palette` instead.
+pub fn palette_colors<P: palette::Palette>(palette: &P) -> Vec<palette::Color> {
+	let len = std::cmp::max(palette.len(), 1);
+	palette.get_slice().map(|x| x[..std::cmp::min(len, x.len())].to_owned())
+		.unwrap_or_else(|| (0..std::cmp::min(len as u64, 1 << palette.width()))
+			.map_while(|n| palette.to_rgba(n as u32).ok())
+			.collect::<Vec<_>>())
+}
+
+/// The color number of a palette's most transparent color (the first, if
+/// there are several), which `AnalyzeOptions::transparent_below` gives to
+/// transparent pixels; 0 for direct palettes, which is transparent black.
+pub fn transparent_index<P: palette::Palette>(palette: &P) -> u32 {
+	if palette.is_direct() {
+		return 0;
+	}
+	palette_colors(palette).iter()
+		.enumerate()
+		.min_by_key(|(ind, c)| (c.0[3], *ind))
+		.map_or(0, |(ind, _)| ind as u32)
+}
+
+/// A copy of `img` with every pixel with alpha below `alpha` made transparent
+/// black, so that palette generation sees them as one color.
+pub fn clear_transparent(img: &image::RgbaImage, alpha: u8) -> image::RgbaImage {
+	let mut img = img.clone();
+	img.pixels_mut().filter(|p| p.0[3] < alpha).for_each(|p| *p = image::Rgba([0; 4]));
+	img
+}
+
+/// Makes palette colors generated from `clear_transparent(img, alpha)` fit
+/// for `AnalyzeOptions::transparent_below`: colors with alpha below `alpha`
+/// (which come mostly from the transparent pixels) are removed, and
+/// transparent black is put first, in their place.
+///
+/// Alpha counts for less than color in dedup distances, so this is what
+/// keeps faint opaque colors and transparent pixels from sharing an entry.
+pub fn with_transparent_entry(colors: Vec<palette::Color>, alpha: u8) -> Vec<palette::Color> {
+	std::iter::once(image::Rgba([0; 4]))
+		.chain(colors.into_iter().filter(|c| c.0[3] >= alpha))
+		.collect()
+}
+
+/// Finds the index of the color in `colors` closest to `color` by `metric`
+/// (the first, if there are several); `None` if `colors` is empty.
+pub fn nearest_color(
+	colors: &[palette::Color],
+	color: &palette::Color,
+	metric: DistanceMetric
+) -> Option<u32> {
+	colors.iter()
+		.enumerate()
+		.map(|(ind, col)| (metric.distance(color, col), ind as u32))
+		.min()
+		.map(|(_, ind)| ind)
+}
+
+/// How `NearestColor` searches its palette.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchStrategy {
+	/// Every palette color is compared.
+	Linear,
+	/// The palette is partitioned into 4×4×4 cells of RGB space, and only
+	/// cells that could hold a closer color than the best found so far are
+	/// searched. Not for `Lab`, whose distances aren't sums over RGB
+	/// channels.
+	Cells,
+	/// The palette is arranged into a k-d tree in the metric's space, and
+	/// only branches that could hold a closer color than the best found so
+	/// far are searched.
+	KdTree,
+	/// The palette is direct, so no search is needed.
+	Direct,
+}
+
+/// Palettes with at least this many colors are searched with
+/// `SearchStrategy::Cells`, or `SearchStrategy::KdTree` for `Lab`.
+pub const CELLS_MIN_COLORS: usize = 32;
+
+/// Palettes with at least this many colors are searched with
+/// `SearchStrategy::KdTree`, whose searches grow more slowly with the
+/// palette than those of cells, which hold more colors each.
+pub const KD_TREE_MIN_COLORS: usize = 1024;
+
+/// Cells along each of the red, green and blue axes for `SearchStrategy::Cells`.
+const CELLS_PER_AXIS: usize = 4;
+
+/// A palette partitioned into cells of RGB space, for `SearchStrategy::Cells`.
+#[derive(Clone, Debug)]
+struct CellGrid {
+	/// Indices of the colors in each cell.
+	members: Vec<Vec<u32>>,
+	/// For each cell, the cells with colors in them, nearest first, so that
+	/// a close color is found early and the cells past it can be skipped.
+	order: Vec<Vec<u8>>,
+}
+
+impl CellGrid {
+	/// Partitions `colors` into cells.
+	fn new(colors: &[palette::Color]) -> Self {
+		let mut members = vec![Vec::new(); CELLS_PER_AXIS.pow(3)];
+		for (ind, c) in colors.iter().enumerate() {
+			members[Self::cell_of(c)].push(ind as u32);
+		}
+		let position = |cell: usize| [
+			cell / (CELLS_PER_AXIS * CELLS_PER_AXIS),
+			cell / CELLS_PER_AXIS % CELLS_PER_AXIS,
+			cell % CELLS_PER_AXIS,
+		];
+		let order = (0..members.len()).map(|from| {
+			let mut cells = (0..members.len()).filter(|cell| !members[*cell].is_empty()).collect::<Vec<_>>();
+			cells.sort_by_key(|to| {
+				let (a, b) = (position(from), position(*to));
+				(0..3).map(|ch| a[ch].abs_diff(b[ch]).pow(2)).sum::<usize>()
+			});
+			cells.into_iter().map(|cell| cell as u8).collect()
+		}).collect();
+		CellGrid { members, order }
+	}
+
+	/// Index of the cell that `c` is in.
+	fn cell_of(c: &palette::Color) -> usize {
+		let axis = |v: u8| v as usize * CELLS_PER_AXIS / 256;
+		(axis(c.0[0]) * CELLS_PER_AXIS + axis(c.0[1])) * CELLS_PER_AXIS + axis(c.0[2])
+	}
+}
+
+/// A color in `NearestColor`'s k-d tree: its coordinates in the metric's
+/// space, its index in the palette, and the axis that it splits its subtree
+/// on.
+#[derive(Clone, Debug)]
+struct KdPoint {
+	coordinates: [f64; 4],
+	index: u32,
+	axis: usize,
+}
+
+/// Arranges `points` into a k-d tree, in place: the middle point splits the
+/// rest, with the points before it as one subtree and those after it as
+/// the other.
+fn build_kd_tree(points: &mut [KdPoint]) {
+	if points.len() <= 1 {
+		return;
+	}
+	// Split on the axis with the widest spread
+	let axis = (0..4).map(|ax| {
+		let (min, max) = points.iter().fold((f64::MAX, f64::MIN), |(min, max), p|
+			(min.min(p.coordinates[ax]), max.max(p.coordinates[ax])));
+		(max - min, ax)
+	}).max_by(|a, b| a.0.total_cmp(&b.0)).unwrap().1;
+	points.sort_by(|a, b| a.coordinates[axis].total_cmp(&b.coordinates[axis]));
+	let mid = points.len() / 2;
+	points[mid].axis = axis;
+	let (before, after) = points.split_at_mut(mid);
+	build_kd_tree(before);
+	build_kd_tree(&mut after[1..]);
+}
+
+/// Searches a k-d tree from `build_kd_tree` for the point closest to
+/// `target`, updating `best` (as `(distance, index)`).
+fn search_kd_tree(points: &[KdPoint], target: &[f64; 4], best: &mut (u32, u32)) {
+	if points.is_empty() {
+		return;
+	}
+	let mid = points.len() / 2;
+	let point = &points[mid];
+	*best = std::cmp::min(*best, (
+		DistanceMetric::coordinate_distance(&point.coordinates, target, 1.),
+		point.index
+	));
+	let diff = target[point.axis] - point.coordinates[point.axis];
+	let (near, far) = if diff < 0. {
+		(&points[..mid], &points[mid + 1..])
+	} else {
+		(&points[mid + 1..], &points[..mid])
+	};
+	search_kd_tree(near, target, best);
+	// Distances are rounded, so allow for points just past the bound
+	if diff * diff <= best.0 as f64 + 0.5 {
+		search_kd_tree(far, target, best);
+	}
+}
+
+/// Finds the closest palette colors to colors, with the same results as
+/// `nearest_color`, using a strategy chosen by palette size and metric (see
+/// `new`) or given (see `with_strategy`).
+#[derive(Clone, Debug)]
+pub struct NearestColor {
+	colors: Vec<palette::Color>,
+	metric: DistanceMetric,
+	/// The colors as a k-d tree, for `SearchStrategy::KdTree`.
+	kd_tree: Option<Vec<KdPoint>>,
+	/// The colors by cell, for `SearchStrategy::Cells`.
+	cells: Option<CellGrid>,
+	/// Whether every color is its own number, for direct palettes.
+	direct: bool,
+}
+
+impl NearestColor {
+	/// Prepares to search `colors`, with the strategy that is fastest for
+	/// their number (see `benches/nearest_color.rs`); with none, `find`
+	/// gives 0.
+	pub fn new(colors: Vec<palette::Color>, metric: DistanceMetric) -> Self {
+		let strategy = match colors.len() {
+			len if len < CELLS_MIN_COLORS => SearchStrategy::Linear,
+			len if len < KD_TREE_MIN_COLORS && metric != DistanceMetric::Lab => SearchStrategy::Cells,
+			_ => SearchStrategy::KdTree
+		};
+		Self::with_strategy(colors, metric, strategy).expect("failure to prepare default search strategy")
+	}
+
+	/// Like `new`, but searching with `strategy` whatever the number of
+	/// colors; for comparing strategies, or palettes whose colors suit one
+	/// better. `None` if the strategy can't be used: `Cells` with `Lab`, or
+	/// `Direct` (see `for_palette`).
+	pub fn with_strategy(
+		colors: Vec<palette::Color>,
+		metric: DistanceMetric,
+		strategy: SearchStrategy
+	) -> Option<Self> {
+		let (mut kd_tree, mut cells) = (None, None);
+		match strategy {
+			SearchStrategy::Linear => (),
+			SearchStrategy::KdTree => {
+				let mut points = colors.iter()
+					.enumerate()
+					.map(|(ind, c)| KdPoint { coordinates: metric.coordinates(c), index: ind as u32, axis: 0 })
+					.collect::<Vec<_>>();
+				build_kd_tree(&mut points);
+				kd_tree = Some(points);
+			},
+			// Cell bounds only hold for metrics that are sums over RGB channels
+			SearchStrategy::Cells if metric != DistanceMetric::Lab => {
+				cells = Some(CellGrid::new(&colors));
+			},
+			SearchStrategy::Cells | SearchStrategy::Direct => return None
+		}
+		Some(NearestColor { colors, metric, kd_tree, cells, direct: false })
+	}
+
+	/// Prepares to search the colors of `palette`; for direct palettes (see
+	/// `Palette::is_direct`), each color is found as its own number.
+	pub fn for_palette<P: palette::Palette>(palette: &P, metric: DistanceMetric) -> Self {
+		if palette.is_direct() {
+			NearestColor { colors: Vec::new(), metric, kd_tree: None, cells: None, direct: true }
+		} else {
+			Self::new(palette_colors(palette), metric)
+		}
+	}
+
+	/// The strategy that `find` uses.
+	pub fn strategy(&self) -> SearchStrategy {
+		match (self.direct, &self.kd_tree, &self.cells) {
+			(true, _, _) => SearchStrategy::Direct,
+			(false, Some(_), _) => SearchStrategy::KdTree,
+			(false, None, Some(_)) => SearchStrategy::Cells,
+			(false, None, None) => SearchStrategy::Linear
+		}
+	}
+
+	/// The least distances that a color in each slice of cells along each of
+	/// the red, green and blue axes could be from `c`, in that channel; the
+	/// bound for a cell is the sum of those of its three slices.
+	fn slice_bounds(&self, c: &palette::Color) -> [[f64; CELLS_PER_AXIS]; 3] {
+		let span = 256 / CELLS_PER_AXIS;
+		let weights = match self.metric {
+			DistanceMetric::WeightedRgb => [3. * 0.299, 3. * 0.587, 3. * 0.114],
+			_ => [1.; 3]
+		};
+		let mut bounds = [[0.; CELLS_PER_AXIS]; 3];
+		for (ch, channel_bounds) in bounds.iter_mut().enumerate() {
+			for (pos, bound) in channel_bounds.iter_mut().enumerate() {
+				let (low, high) = (pos * span, (pos + 1) * span - 1);
+				let v = c.0[ch] as usize;
+				let d = if v < low { low - v } else { v.saturating_sub(high) };
+				*bound = weights[ch] * (d * d) as f64;
+			}
+		}
+		bounds
+	}
+
+	/// Finds the index of the closest color to `color` (the first, if there
+	/// are several), or 0 if there are no colors, as `Palette::nearest`
+	/// does.
+	pub fn find(&self, color: &palette::Color) -> u32 {
+		if self.direct {
+			return u32::from_be_bytes(color.0);
+		}
+		if self.colors.is_empty() {
+			return 0;
+		}
+		if let Some(ref points) = self.kd_tree {
+			let mut best = (u32::MAX, u32::MAX);
+			search_kd_tree(points, &self.metric.coordinates(color), &mut best);
+			return best.1;
+		}
+		let cells = match self.cells {
+			Some(ref cells) => cells,
+			None => return nearest_color(&self.colors, color, self.metric).unwrap_or(0)
+		};
+		let bounds = self.slice_bounds(color);
+		let mut best = (u32::MAX, u32::MAX);
+		for cell in cells.order[CellGrid::cell_of(color)].iter().map(|cell| *cell as usize) {
+			let bound = bounds[0][cell / (CELLS_PER_AXIS * CELLS_PER_AXIS)] +
+				bounds[1][cell / CELLS_PER_AXIS % CELLS_PER_AXIS] +
+				bounds[2][cell % CELLS_PER_AXIS];
+			// Distances are rounded, so allow for colors just past the bound
+			if bound > best.0 as f64 + 0.5 {
+				continue;
+			}
+			for ind in cells.members[cell].iter() {
+				best = std::cmp::min(best, (self.metric.distance(color, &self.colors[*ind as usize]), *ind));
+			}
+		}
+		best.1
+	}
+}
+
+/// Averages a group of colors weighted by their pixel counts, giving the
+/// average and the total count.
+fn weighted_average(group: &[(palette::Color, isize)]) -> (palette::Color, isize) {
+	let total = group.iter().map(|cc| cc.1).sum();
+	let col = color_div(
+		group.iter()
+			.map(|cc| color_mul(&cc.0, &cc.1))
+			.fold(image::Rgba::<isize>([0; 4]), color_add_big),
+		total
+	);
+	(col, total)
+}
+
+/// Counts the pixels of each distinct color in an image, most common first
+/// (ties broken by color value), so that nothing that uses the histogram
+/// depends on the order of a `HashMap`.
+fn color_histogram(img: &image::RgbaImage) -> Vec<(palette::Color, isize)> {
 	let mut successes = HashMap::new();
 	for pixel in img.pixels() {
 		*successes.entry(*pixel).or_insert(0isize) += 1;
 	}
+	let mut histogram = successes.into_iter().collect::<Vec<_>>();
+	histogram.sort_by_key(|cc| (-cc.1, cc.0 .0));
+	histogram
+}
+
+/// Groups the colors of a histogram that are within `dedup_thresh` of each
+/// other, giving the average color and pixel count of each group, most
+/// common first.
+///
+/// Gives up, returning `None`, once there are more than `max_clusters` groups.
+///
+/// Colors are grouped in the order of `histogram`, which should be that of
+/// `color_histogram`.
+fn dedup_clusters(
+	histogram: &[(palette::Color, isize)],
+	dedup_thresh: u32,
+	max_clusters: usize,
+	metric: DistanceMetric
+) -> Option<Vec<(palette::Color, isize)>> {
 	let mut similars: Vec<Vec<(palette::Color, isize)>> = Vec::new();
-	for (col, count) in successes.into_iter() {
+	for &(col, count) in histogram.iter() {
 		let mut found = false;
 		for comp in similars.iter_mut() {
-			if dedup_distance(&comp[0].0, &col) < dedup_thresh {
+			if metric.dedup_distance(&comp[0].0, &col) < dedup_thresh {
 				comp.push((col, count));
 				found = true;
 				break;
 			}
 		}
 		if !found {
+			if similars.len() == max_clusters {
+				return None;
+			}
 			similars.push(vec![(col, count)]);
 		}
 	}
 	let mut rank = Vec::new();
-	rank.extend(similars.into_iter().map(|cat| {
-		let total = cat.iter().map(|cc| cc.1).sum();
-		let col = color_div(
-			cat.iter()
-				.map(|cc| color_mul(&cc.0, &cc.1))
-				.fold(image::Rgba::<isize>([0; 4]), color_add_big),
-			total
-		);
-		(col, total)
-	}));
-	rank.sort_by_key(|cc: &(palette::Color, isize)| -cc.1);
+	rank.extend(similars.iter().map(|cat| weighted_average(cat)));
+	rank.sort_by_key(|cc: &(palette::Color, isize)| (-cc.1, cc.0 .0));
+	Some(rank)
+}
+
+/// Selects a palette of a given size and type through a process similar to
+/// (but not quite the same as) finding the most commonly used colors in the image.
+///
+/// `dedup_thresh` indicates the (squared) limit for how "distant" colors can be
+/// while still being quantized as one color.
+///
+/// This is `generate_palette_with` with the other `PaletteOptions` left at
+/// their defaults.
+pub fn generate_palette<P: palette::DynamicPalette>(
+	img: &image::RgbaImage,
+	dedup_thresh: u32
+) -> P {
+	generate_palette_with(img, &PaletteOptions::default().dedup_thresh(dedup_thresh))
+}
+
+/// Settings for `generate_palette_with`.
+///
+/// Each field has a method of the same name that sets it, for building
+/// options up in a chain.
+#[derive(Clone, Debug)]
+pub struct PaletteOptions {
+	/// The (squared) limit for how "distant" colors can be while still being
+	/// quantized as one color; defaults to 256.
+	pub dedup_thresh: u32,
+	/// If greater than 1, the palette is generated from a copy of the image
+	/// scaled down by this factor, simulating the averaging done by the
+	/// quadtree's larger leaves, so that the colors better match what is
+	/// actually displayed. (This is experimental.) Defaults to 1, for the
+	/// image as it is.
+	pub downsample: u32,
+	/// How color distance is measured for `dedup_thresh`; defaults to
+	/// `Euclidean`.
+	pub metric: DistanceMetric,
+}
+
+impl Default for PaletteOptions {
+	fn default() -> Self {
+		PaletteOptions { dedup_thresh: 256, downsample: 1, metric: DistanceMetric::Euclidean }
+	}
+}
+
+impl PaletteOptions {
+	option_setters! {
+		dedup_thresh: u32,
+		downsample: u32,
+		metric: DistanceMetric,
+	}
+}
+
+/// Like `generate_palette`, with the settings in `options`.
+///
+/// The result depends only on the image (and the options): pixels are
+/// counted into a histogram that is then sorted by count and color, so
+/// neither the order pixels are read in nor hashing affects it.
+pub fn generate_palette_with<P: palette::DynamicPalette>(
+	img: &image::RgbaImage,
+	options: &PaletteOptions
+) -> P {
+	// An empty image has nothing to scale (and `resize` can't scale it)
+	let histogram = if options.downsample > 1 && img.width() > 0 && img.height() > 0 {
+		color_histogram(&image::imageops::resize(
+			img,
+			std::cmp::max(img.width() / options.downsample, 1),
+			std::cmp::max(img.height() / options.downsample, 1),
+			image::imageops::FilterType::Triangle
+		))
+	} else {
+		color_histogram(img)
+	};
+	let rank = dedup_clusters(&histogram, options.dedup_thresh, usize::MAX, options.metric)
+		.expect("unlimited clusters");
 	P::from(rank.iter().map(|x| x.0).collect())
 }
 
+/// Selects a palette of at most `max_colors` colors with the median cut
+/// algorithm: the colors of the image are split into boxes in RGBA space,
+/// always splitting the box with the largest range in one channel at the
+/// (pixel-weighted) median of that channel, and each box gives the average of
+/// its colors.
+///
+/// No more colors than fit in the width of `P` are generated; if the image
+/// has fewer unique colors than that, each gets its own palette entry.
+pub fn generate_palette_median_cut<P: palette::DynamicPalette>(
+	img: &image::RgbaImage,
+	max_colors: usize
+) -> P {
+	let histogram = color_histogram(img);
+	let mut max_colors = max_colors;
+	loop {
+		let colors = median_cut(histogram.clone(), max_colors);
+		let count = colors.len();
+		let palette = P::from(colors);
+		let capacity = 1usize.checked_shl(palette.width() as u32).unwrap_or(usize::MAX);
+		if count <= capacity {
+			return palette;
+		}
+		max_colors = capacity;
+	}
+}
+
+/// Helper for `generate_palette_median_cut`; gives the colors of at most
+/// `max_colors` boxes, most common first.
+fn median_cut(histogram: Vec<(palette::Color, isize)>, max_colors: usize) -> Vec<palette::Color> {
+	// The channel with the largest range in a box, and that range
+	let widest = |b: &[(palette::Color, isize)]| (0..4)
+		.map(|ch| (
+			b.iter().map(|cc| cc.0 .0[ch]).max().unwrap_or(0) -
+				b.iter().map(|cc| cc.0 .0[ch]).min().unwrap_or(0),
+			ch
+		))
+		.max()
+		.unwrap();
+	let mut boxes = vec![histogram];
+	while boxes.len() < max_colors {
+		let (ind, (range, ch)) = match boxes.iter()
+			.map(|b| widest(b))
+			.enumerate()
+			.max_by_key(|(_, w)| w.0) {
+			Some(w) => w,
+			None => break
+		};
+		if range == 0 {
+			// Every box has just one color
+			break;
+		}
+		let mut split = boxes.swap_remove(ind);
+		split.sort_by_key(|cc| (cc.0 .0[ch], cc.0 .0));
+		let half = split.iter().map(|cc| cc.1).sum::<isize>() / 2;
+		let mut seen = 0;
+		let median = split.iter()
+			.position(|cc| { seen += cc.1; seen > half })
+			.unwrap();
+		// Both halves must have at least one color
+		let at = median.clamp(1, split.len() - 1);
+		let upper = split.split_off(at);
+		boxes.push(split);
+		boxes.push(upper);
+	}
+	let mut rank = boxes.iter()
+		.filter(|b| !b.is_empty())
+		.map(|b| weighted_average(b))
+		.collect::<Vec<_>>();
+	rank.sort_by_key(|cc: &(palette::Color, isize)| (-cc.1, cc.0 .0));
+	rank.into_iter().map(|cc| cc.0).collect()
+}
+
+/// When `refine_palette_kmeans_with` stops.
+#[derive(Clone, Debug)]
+pub struct RefineOptions {
+	/// Most rounds of refinement to do.
+	pub max_iterations: usize,
+	/// Stop once no palette color moves (in RGBA space) more than this in a
+	/// round.
+	pub convergence: f64,
+}
+
+impl Default for RefineOptions {
+	fn default() -> Self {
+		RefineOptions { max_iterations: 16, convergence: 0.5 }
+	}
+}
+
+/// Improves a palette with `iterations` rounds of k-means clustering: each
+/// pixel is assigned to its closest palette color, and then each palette color
+/// is replaced with the average of its pixels. Colors with no pixels are kept
+/// as they are.
+///
+/// Stops early if the palette stops changing; see `refine_palette_kmeans_with`
+/// for more control.
+pub fn refine_palette_kmeans<P: palette::DynamicPalette>(
+	img: &image::RgbaImage,
+	palette: P,
+	iterations: usize
+) -> P {
+	refine_palette_kmeans_with(img, palette, &RefineOptions {
+		max_iterations: iterations,
+		convergence: 0.
+	}).0
+}
+
+/// Improves a palette with k-means clustering, as `refine_palette_kmeans`
+/// does, stopping as set in `options`. Also gives the number of rounds done.
+pub fn refine_palette_kmeans_with<P: palette::DynamicPalette>(
+	img: &image::RgbaImage,
+	palette: P,
+	options: &RefineOptions
+) -> (P, usize) {
+	// Direct palettes already have every color
+	if palette.is_direct() {
+		return (palette, 0);
+	}
+	let mut colors = palette_colors(&palette);
+	if colors.is_empty() {
+		return (palette, 0);
+	}
+	let histogram = color_histogram(img);
+	for iteration in 0..options.max_iterations {
+		let mut sums = vec![(image::Rgba::<isize>([0; 4]), 0isize); colors.len()];
+		for (col, count) in histogram.iter() {
+			if let Some(ind) = nearest_color(&colors, col, DistanceMetric::Euclidean) {
+				let sum = &mut sums[ind as usize];
+				*sum = (color_add_big(sum.0, color_mul(col, count)), sum.1 + count);
+			}
+		}
+		let mut moved = 0f64;
+		for (col, (sum, count)) in colors.iter_mut().zip(sums) {
+			if count > 0 {
+				let new = color_div(sum, count);
+				moved = moved.max((color_distance(col, &new) as f64).sqrt());
+				*col = new;
+			}
+		}
+		if moved <= options.convergence {
+			return (P::from(colors), iteration + 1);
+		}
+	}
+	(P::from(colors), options.max_iterations)
+}
+
+/// Estimate of how many colors an image "really" has, from `suggest_color_count`.
+#[derive(Clone, Debug)]
+pub struct ColorCountReport {
+	/// Number of clusters found for each tried dedup threshold, as
+	/// `(threshold, clusters)`, by increasing threshold. Thresholds that would
+	/// give more than `MAX_SUGGESTED_COLORS` clusters are left out.
+	pub cluster_counts: Vec<(u32, usize)>,
+	/// The dedup threshold at the knee of the cluster count curve.
+	pub knee_threshold: u32,
+	/// The number of clusters at the knee; the suggested palette size.
+	pub suggested: usize,
+	/// Fraction of pixels covered by the most common 2, 4, 8, ... 256
+	/// clusters at the knee threshold, as `(clusters, fraction)`.
+	pub coverage: Vec<(usize, f64)>,
+}
+
+/// The most colors `suggest_color_count` will consider suggesting.
+pub const MAX_SUGGESTED_COLORS: usize = 4096;
+
+/// Runs the palette deduplication at several thresholds to suggest
+/// how many colors an image has, ignoring slight noise.
+///
+/// The suggestion is the knee of the curve of cluster counts by threshold
+/// (on logarithmic scales); the point furthest from the line between the
+/// curve's ends, or one of the next two if the curve levels off there.
+pub fn suggest_color_count(img: &image::RgbaImage) -> ColorCountReport {
+	let histogram = color_histogram(img);
+	let pixels = (img.width() * img.height()) as f64;
+	let mut clusterings = (0..9).map(|n| 1 << (2 * n))
+		.filter_map(|thresh| dedup_clusters(&histogram, thresh, MAX_SUGGESTED_COLORS, Default::default())
+			.map(|c| (thresh, c)))
+		.collect::<Vec<_>>();
+	let cluster_counts = clusterings.iter()
+		.map(|(t, c)| (*t, c.len()))
+		.collect::<Vec<_>>();
+	let points = cluster_counts.iter()
+		.map(|(t, n)| ((*t as f64).log2(), (*n as f64).max(1.).log2()))
+		.collect::<Vec<_>>();
+	let knee = match (points.first(), points.last()) {
+		(Some(first), Some(last)) => points.iter()
+			.map(|p| ((p.0 - first.0) * (last.1 - first.1) - (p.1 - first.1) * (last.0 - first.0)).abs())
+			.enumerate()
+			// First maximum, so that flat curves give the smallest threshold
+			.fold((0, 0.), |m, (ind, d)| if d > m.1 { (ind, d) } else { m })
+			.0,
+		_ => 0
+	};
+	// Noise that the knee's threshold doesn't quite merge away can leave it
+	// just short of where the curve levels off at the image's real number
+	// of colors; if it holds there over three thresholds, that is the knee
+	let knee = (knee..std::cmp::min(knee + 2, cluster_counts.len().saturating_sub(2)))
+		.find(|&ind| cluster_counts[ind].1 == cluster_counts[ind + 2].1)
+		.unwrap_or(knee);
+	let (knee_threshold, knee_clusters) = if clusterings.is_empty() {
+		(0, Vec::new())
+	} else {
+		clusterings.swap_remove(knee)
+	};
+	let coverage = (1..=8).map(|n| {
+		let covered = knee_clusters.iter().take(1 << n).map(|c| c.1).sum::<isize>();
+		(1 << n, if pixels == 0. { 1. } else { covered as f64 / pixels })
+	}).collect();
+	ColorCountReport {
+		cluster_counts,
+		knee_threshold,
+		suggested: knee_clusters.len(),
+		coverage,
+	}
+}
+
+/// Most colors that a `Quantizer` remembers the quantization of.
+const QUANT_CACHE_LIMIT: usize = 1 << 16;
+
 /// Processes an image given a palette so as to convert it to a "rectangle"
 /// of pixels each represented by a palette-color-number that most closely
 /// matches the original color.
 ///
 /// For the efficiency of the quadtree, the image may be Gaussian-blurred
 /// before quantization; the extent to which this is done is controlled by `blur`.
+///
+/// `metric` is how "closely" is measured.
+///
+/// This is `Quantizer::quantize` with a new `Quantizer`; for quantizing
+/// several images to one palette, keep a `Quantizer` for all of them.
 pub fn quantize_to_palette<P: palette::Palette>(
 	img: &image::RgbaImage,
-	palette: &P
+	palette: &P,
+	metric: DistanceMetric
 ) -> Vec<u32> {
-	let palette_colors = palette.get_slice().map(|x| x.to_owned())
-		.unwrap_or_else(|| (0..1 << palette.width())
-			.map(|n| palette.to_rgba(n as u32).unwrap())
-			.collect::<Vec<_>>());
-	let mut quant_cache = HashMap::new();
-	img.pixels()
-		.map(|pix| {
-			match quant_cache.get(pix) {
-				Some(c) => *c,
-				None => {
-					let c = palette_colors.iter()
-						.enumerate()
-						.map(|(ind, col)| (color_distance(pix, col), ind as u32))
-						.min().unwrap().1;
-					quant_cache.insert(pix, c);
-					c
+	Quantizer::new(palette, metric).quantize(img)
+}
+
+/// Quantizes images to a palette, as `quantize_to_palette` does, remembering
+/// the color numbers of the pixel colors it has seen from one image to the
+/// next. This speeds up quantizing images that share many colors, like the
+/// frames of an animation.
+#[derive(Debug)]
+pub struct Quantizer<P: palette::Palette> {
+	palette: P,
+	nearest: NearestColor,
+	cache: HashMap<palette::Color, u32>,
+}
+
+impl<P: palette::Palette> Quantizer<P> {
+	/// Prepares to quantize to `palette`, with colors matched by `metric`.
+	/// `palette` may be a reference.
+	pub fn new(palette: P, metric: DistanceMetric) -> Self {
+		let nearest = NearestColor::for_palette(&palette, metric);
+		Quantizer { palette, nearest, cache: HashMap::new() }
+	}
+
+	/// The palette being quantized to.
+	pub fn palette(&self) -> &P {
+		&self.palette
+	}
+
+	/// Gives back the palette, dropping the remembered colors.
+	pub fn into_palette(self) -> P {
+		self.palette
+	}
+
+	/// The color number of each pixel of `img`, row by row.
+	pub fn quantize(&mut self, img: &image::RgbaImage) -> Vec<u32> {
+		#[cfg(feature = "rayon")]
+		{
+			use rayon::prelude::*;
+			let (cache, nearest) = (&self.cache, &self.nearest);
+			// Rows on separate threads, each looking up colors not already
+			// remembered in its own cache, and listing them to remember
+			let rows = img.as_raw()
+				.par_chunks(std::cmp::max(img.width() as usize * 4, 4))
+				.map_init(HashMap::new, |local_cache, row| {
+					let mut found = Vec::new();
+					let colors = row.chunks(4)
+						.map(|c| {
+							let pix = image::Rgba([c[0], c[1], c[2], c[3]]);
+							match cache.get(&pix) {
+								Some(c) => *c,
+								None => {
+									let c = quantize_pixel(&pix, nearest, local_cache);
+									found.push((pix, c));
+									c
+								}
+							}
+						})
+						.collect::<Vec<_>>();
+					(colors, found)
+				})
+				.collect::<Vec<_>>();
+			let mut ret = Vec::with_capacity(img.len() / 4);
+			for (colors, found) in rows {
+				ret.extend(colors);
+				for (pix, c) in found {
+					remember(&mut self.cache, pix, c);
 				}
 			}
+			ret
+		}
+		#[cfg(not(feature = "rayon"))]
+		{
+			img.pixels()
+				.map(|pix| quantize_pixel(pix, &self.nearest, &mut self.cache))
+				.collect::<Vec<_>>()
+		}
+	}
+}
+
+/// Like `quantize_to_palette`, but also gives the squared distance (by
+/// `metric`) from each pixel to the palette color it was matched to, in the
+/// same order.
+pub fn quantize_to_palette_with_error<P: palette::Palette>(
+	img: &image::RgbaImage,
+	palette: &P,
+	metric: DistanceMetric
+) -> (Vec<u32>, Vec<u32>) {
+	let indices = quantize_to_palette(img, palette, metric);
+	let errors = img.pixels()
+		.zip(indices.iter())
+		.map(|(pix, ind)| metric.distance(pix, &palette.to_rgba(*ind).unwrap_or(image::Rgba([0; 4]))))
+		.collect::<Vec<_>>();
+	(indices, errors)
+}
+
+/// Helper for `quantize_to_palette`; finds the palette color number for one
+/// pixel, remembering it in `quant_cache`.
+fn quantize_pixel(
+	pix: &image::Rgba<u8>,
+	nearest: &NearestColor,
+	quant_cache: &mut HashMap<image::Rgba<u8>, u32>
+) -> u32 {
+	match quant_cache.get(pix) {
+		Some(c) => *c,
+		None => {
+			let c = nearest.find(pix);
+			remember(quant_cache, *pix, c);
+			c
+		}
+	}
+}
+
+/// Adds a pixel color and its color number to `quant_cache`.
+fn remember(quant_cache: &mut HashMap<image::Rgba<u8>, u32>, pix: image::Rgba<u8>, c: u32) {
+	// Images with very many colors would otherwise fill the cache with
+	// colors that are never seen again
+	if quant_cache.len() >= QUANT_CACHE_LIMIT {
+		quant_cache.clear();
+	}
+	quant_cache.insert(pix, c);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::image::AnalyzeOptions;
+	use crate::test_util::{test_image, Lcg};
+	use palette::{DynamicPaletteView, Palette, PaletteView4, PaletteView8};
+
+	/// Twelve well-separated colors.
+	const TWELVE: [[u8; 3]; 12] = [
+		[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0], [0, 255, 255], [255, 0, 255],
+		[0, 0, 0], [255, 255, 255], [128, 128, 128], [128, 0, 0], [0, 128, 0], [0, 0, 128],
+	];
+
+	/// An image of `TWELVE` scattered about, each pixel off its color by a
+	/// little noise.
+	fn twelve_color_image(size: u32, seed: u64) -> image::RgbaImage {
+		let mut rng = Lcg(seed);
+		image::RgbaImage::from_fn(size, size, |_, _| {
+			let base = TWELVE[rng.below(12) as usize];
+			let mut noisy = |v: u8| (v as i32 + rng.below(5) as i32 - 2).clamp(0, 255) as u8;
+			image::Rgba([noisy(base[0]), noisy(base[1]), noisy(base[2]), 255])
+		})
+	}
+
+	/// A smooth `size` by `size` radial gradient, from light at a point off
+	/// center to dark at the far corner, as of a lit surface in a photo.
+	fn gradient(size: u32) -> image::RgbaImage {
+		let (light, dark) = ([255., 240., 200.], [20., 10., 60.]);
+		image::RgbaImage::from_fn(size, size, |x, y| {
+			let (dx, dy) = (x as f64 - size as f64 / 3., y as f64 - size as f64 / 2.);
+			let t = ((dx * dx + dy * dy).sqrt() / size as f64).min(1.);
+			let channel = |i: usize| (light[i] + (dark[i] - light[i]) * t).round() as u8;
+			image::Rgba([channel(0), channel(1), channel(2), 255])
 		})
-		.collect::<Vec<_>>()
+	}
+
+	/// The sum of the squared distances of the pixels of `img` from their
+	/// colors in `palette`.
+	fn total_error<P: Palette>(img: &image::RgbaImage, palette: &P) -> u64 {
+		let colors = palette.get_slice().unwrap();
+		quantize_to_palette(img, palette, DistanceMetric::Euclidean).iter().zip(img.pixels())
+			.map(|(c, pix)| color_distance(pix, &colors[*c as usize]) as u64)
+			.sum()
+	}
+
+	/// The number of colors in `palette`.
+	fn len<P: Palette>(palette: &P) -> usize {
+		palette.get_slice().unwrap().len()
+	}
+
+	#[test]
+	fn suggests_twelve_colors() {
+		let report = suggest_color_count(&twelve_color_image(64, 1));
+		assert!((11..=13).contains(&report.suggested), "suggested {}", report.suggested);
+		let (_, at_16) = report.coverage.iter().find(|(n, _)| *n == 16).unwrap();
+		assert_eq!(*at_16, 1.);
+	}
+
+	#[test]
+	fn generated_palettes_are_repeatable() {
+		let img = test_image(64, 10);
+		let palette: DynamicPaletteView = generate_palette(&img, 64);
+		for _ in 0..4 {
+			let again: DynamicPaletteView = generate_palette(&img, 64);
+			assert_eq!(again, palette);
+		}
+	}
+
+	#[test]
+	fn median_cut_beats_dedup_on_gradients() {
+		let img = gradient(64);
+		for dedup_thresh in [256, 1024, 4096] {
+			let dedup: DynamicPaletteView = generate_palette(&img, dedup_thresh);
+			let median_cut: DynamicPaletteView = generate_palette_median_cut(&img, len(&dedup));
+			assert!(len(&median_cut) <= len(&dedup));
+			assert!(total_error(&img, &median_cut) < total_error(&img, &dedup));
+		}
+
+		// No more colors than asked for, than the palette type holds, or than
+		// the image has
+		assert_eq!(len(&generate_palette_median_cut::<DynamicPaletteView>(&img, 16)), 16);
+		assert_eq!(len(&generate_palette_median_cut::<PaletteView4>(&img, 256)), 16);
+		let few = twelve_color_image(16, 2);
+		let few = image::RgbaImage::from_fn(16, 16, |x, y| image::Rgba([few.get_pixel(x, y).0[0], 0, 0, 255]));
+		let distinct = few.pixels().map(|p| p.0).collect::<std::collections::HashSet<_>>().len();
+		assert_eq!(len(&generate_palette_median_cut::<DynamicPaletteView>(&few, 256)), distinct);
+	}
+
+	#[test]
+	fn downsampled_palettes_suit_coarse_leaves() {
+		// Leaves limited to 8x8 pixels, by drawing at an eighth of the size;
+		// the colors that survive scaling by 8 are the ones the leaves show
+		let img = gradient(64);
+		let rendered_error = |dedup, downsample| {
+			let options = PaletteOptions { dedup_thresh: dedup, downsample, ..Default::default() };
+			let palette: DynamicPaletteView = generate_palette_with(&img, &options);
+			let mut tree = crate::QuadtreeNode::default();
+			tree.from_image_with(&img, &palette, &AnalyzeOptions::default().sensitivity(16384).gradient_fit(None)).unwrap();
+			let mut out = image::RgbaImage::new(8, 8);
+			tree.to_image(&mut out, &palette, None, None, false, None).unwrap();
+			img.enumerate_pixels().map(|(x, y, pix)| color_distance(pix, out.get_pixel(x / 8, y / 8)) as u64).sum::<u64>()
+		};
+		for dedup in [1024, 4096] {
+			assert!(rendered_error(dedup, 8) < rendered_error(dedup, 1));
+		}
+	}
+
+	#[test]
+	fn downsampling_an_empty_image() {
+		let options = PaletteOptions { downsample: 4, ..Default::default() };
+		assert!(generate_palette_with::<DynamicPaletteView>(&image::RgbaImage::new(0, 0), &options).is_empty());
+	}
+
+	#[test]
+	fn palettes_are_deterministic() {
+		// Each run hashes with new keys; reading the pixels in another order
+		// gives the same histogram
+		let img = twelve_color_image(64, 3);
+		let reordered = image::imageops::rotate180(&image::imageops::flip_horizontal(&img));
+		let colors = palette_colors(&generate_palette::<DynamicPaletteView>(&img, 256));
+		let median_cut = palette_colors(&generate_palette_median_cut::<DynamicPaletteView>(&img, 16));
+		assert!(colors.len() >= 12);
+		for source in [&img, &reordered].iter().cycle().take(16) {
+			assert_eq!(palette_colors(&generate_palette::<DynamicPaletteView>(source, 256)), colors);
+			assert_eq!(palette_colors(&generate_palette_median_cut::<DynamicPaletteView>(source, 16)), median_cut);
+		}
+	}
+
+	#[test]
+	fn kmeans_error_never_increases() {
+		// Dedup already puts the twelve colors' palette at their means
+		for (img, improves) in [(gradient(64), true), (twelve_color_image(64, 3), false)] {
+			let mut palette: DynamicPaletteView = generate_palette(&img, 1024);
+			let mut errors = vec![total_error(&img, &palette)];
+			for _ in 0..8 {
+				palette = refine_palette_kmeans(&img, palette, 1);
+				errors.push(total_error(&img, &palette));
+			}
+			assert!(errors.windows(2).all(|pair| pair[1] <= pair[0]), "errors {:?}", errors);
+			assert_eq!(errors[8] < errors[0], improves, "errors {:?}", errors);
+		}
+	}
+
+	#[test]
+	fn kmeans_stops_on_convergence_or_the_cap() {
+		let img = gradient(64);
+		let start = || generate_palette::<DynamicPaletteView>(&img, 4096);
+		let refine = |max_iterations, convergence| refine_palette_kmeans_with(&img, start(), &RefineOptions {
+			max_iterations,
+			convergence
+		});
+		// Colors move in every round before the palette settles
+		assert_eq!(refine(3, 0.).1, 3);
+		assert_eq!(refine(0, 0.).1, 0);
+		assert_eq!(refine(3, 0.).0, refine_palette_kmeans(&img, start(), 3));
+		assert_eq!(refine(10, 1000.).1, 1);
+
+		let (converged, rounds) = refine(1000, 0.5);
+		assert!(rounds > 1 && rounds < 1000, "{} rounds", rounds);
+		assert_eq!(converged, refine(rounds, 0.).0);
+		// Once settled, one round is enough to tell
+		assert_eq!(refine_palette_kmeans_with(&img, converged, &Default::default()).1, 1);
+		let twelve = twelve_color_image(32, 5);
+		assert_eq!(refine_palette_kmeans_with(&twelve, generate_palette::<DynamicPaletteView>(&twelve, 1024), &Default::default()).1, 1);
+	}
+
+	#[test]
+	fn short_palettes_never_give_black() {
+		let mut rng = Lcg(4);
+		let img = image::RgbaImage::from_fn(32, 32, |_, _| {
+			image::Rgba([128 + rng.below(128) as u8, 128 + rng.below(128) as u8, 128 + rng.below(128) as u8, 255])
+		});
+		let bright = (0..10u8).map(|i| image::Rgba([155 + i * 10, 250 - i * 10, 128 + i * 5, 255])).collect::<Vec<_>>();
+		let palette = PaletteView8::from(bright.clone());
+		for metric in [DistanceMetric::Euclidean, DistanceMetric::WeightedRgb, DistanceMetric::Lab] {
+			assert!(quantize_to_palette(&img, &palette, metric).iter().all(|c| *c < 10));
+			let mut tree = crate::QuadtreeNode::default();
+			tree.from_image_with(&img, &palette, &AnalyzeOptions::default().metric(metric)).unwrap();
+			assert!(tree.render(&palette, false).unwrap().pixels().all(|p| bright.contains(p)));
+		}
+	}
+
+	#[test]
+	fn linear_light_round_trips() {
+		for v in 0..=255 {
+			assert_eq!(linear_to_srgb(srgb_to_linear(v)), v);
+		}
+		assert!((1..=255).all(|v| srgb_to_linear(v) > srgb_to_linear(v - 1)));
+		assert_eq!((srgb_to_linear(0), srgb_to_linear(255)), (0., 1.));
+		assert_eq!((linear_to_srgb(-0.5), linear_to_srgb(1.5)), (0, 255));
+	}
+
+	#[test]
+	fn metrics_rank_hue_and_luma_shifts() {
+		let gray = image::Rgba([128, 128, 128, 255]);
+		// Shifts of the same length in RGB; the first keeps luma, moving only
+		// in hue and saturation
+		let hue_shift = image::Rgba([158, 118, 101, 255]);
+		let luma_shift = image::Rgba([152, 152, 152, 255]);
+		let distances = |metric: DistanceMetric| (metric.distance(&gray, &hue_shift), metric.distance(&gray, &luma_shift));
+
+		let (hue, luma) = distances(DistanceMetric::Euclidean);
+		assert!(hue.abs_diff(luma) <= 1);
+		let (hue, luma) = distances(DistanceMetric::WeightedRgb);
+		assert!(hue < luma);
+		// Lab counts the lightness change alone, and finds the change of hue
+		// the more visible of the two
+		assert!((srgb_to_lab(&hue_shift)[0] - srgb_to_lab(&gray)[0]).abs() < 1.);
+		let lightness = 2.55 * (srgb_to_lab(&luma_shift)[0] - srgb_to_lab(&gray)[0]);
+		let (hue, luma) = distances(DistanceMetric::Lab);
+		assert_eq!(luma, lightness.powi(2).round() as u32);
+		assert!(hue > luma);
+	}
+	/// `n` random colors from `rng`, of random alpha.
+	fn random_colors(rng: &mut Lcg, n: usize) -> Vec<palette::Color> {
+		(0..n).map(|_| image::Rgba(rng.next_u32().to_be_bytes())).collect()
+	}
+
+	#[test]
+	fn search_strategies_match_brute_force() {
+		let mut rng = Lcg(11);
+		let mut colors = random_colors(&mut rng, 4096);
+		// Repeated colors, so that ties must go to the first
+		colors.copy_within(..96, 4000);
+		let queries = random_colors(&mut rng, 100).into_iter().chain(colors[..20].iter().copied()).collect::<Vec<_>>();
+		for metric in [DistanceMetric::Euclidean, DistanceMetric::WeightedRgb, DistanceMetric::Lab] {
+			let expected = queries.iter().map(|q| nearest_color(&colors, q, metric).unwrap()).collect::<Vec<_>>();
+			for strategy in [SearchStrategy::Linear, SearchStrategy::Cells, SearchStrategy::KdTree] {
+				let nearest = match NearestColor::with_strategy(colors.clone(), metric, strategy) {
+					Some(nearest) => nearest,
+					None => {
+						assert_eq!((metric, strategy), (DistanceMetric::Lab, SearchStrategy::Cells));
+						continue;
+					}
+				};
+				assert_eq!(nearest.strategy(), strategy);
+				let found = queries.iter().map(|q| nearest.find(q)).collect::<Vec<_>>();
+				assert!(found == expected, "{:?} with {:?}", strategy, metric);
+			}
+		}
+
+		let chosen = |n, metric| NearestColor::new(colors[..n].to_vec(), metric).strategy();
+		assert_eq!(chosen(CELLS_MIN_COLORS - 1, DistanceMetric::Euclidean), SearchStrategy::Linear);
+		assert_eq!(chosen(CELLS_MIN_COLORS, DistanceMetric::Euclidean), SearchStrategy::Cells);
+		assert_eq!(chosen(KD_TREE_MIN_COLORS - 1, DistanceMetric::WeightedRgb), SearchStrategy::Cells);
+		assert_eq!(chosen(KD_TREE_MIN_COLORS, DistanceMetric::Euclidean), SearchStrategy::KdTree);
+		assert_eq!(chosen(CELLS_MIN_COLORS - 1, DistanceMetric::Lab), SearchStrategy::Linear);
+		assert_eq!(chosen(CELLS_MIN_COLORS, DistanceMetric::Lab), SearchStrategy::KdTree);
+		let direct = NearestColor::for_palette(&palette::IdentityPalette, DistanceMetric::Euclidean);
+		assert_eq!(direct.strategy(), SearchStrategy::Direct);
+		assert_eq!(direct.find(&image::Rgba([1, 2, 3, 4])), 0x01020304);
+		assert!(NearestColor::with_strategy(colors, DistanceMetric::Euclidean, SearchStrategy::Direct).is_none());
+	}
+
+	#[test]
+	fn quantizing_matches_brute_force() {
+		let mut rng = Lcg(12);
+		// Half the pixels from a few colors, so that remembered ones are reused
+		let common = random_colors(&mut rng, 16);
+		let random_image = |rng: &mut Lcg| image::RgbaImage::from_fn(32, 32, |_, _| match rng.below(2) {
+			0 => common[rng.below(16) as usize],
+			_ => image::Rgba(rng.next_u32().to_be_bytes()),
+		});
+		let images = [random_image(&mut rng), random_image(&mut rng)];
+		for n in [8, 256] {
+			let palette = DynamicPaletteView::from(random_colors(&mut rng, n));
+			for metric in [DistanceMetric::Euclidean, DistanceMetric::WeightedRgb, DistanceMetric::Lab] {
+				let brute_force = |img: &image::RgbaImage| img.pixels()
+					.map(|p| nearest_color(palette.get_slice().unwrap(), p, metric).unwrap())
+					.collect::<Vec<_>>();
+				let mut quantizer = Quantizer::new(&palette, metric);
+				for img in images.iter() {
+					let expected = brute_force(img);
+					assert!(quantize_to_palette(img, &palette, metric) == expected, "{} colors, {:?}", n, metric);
+					assert!(quantizer.quantize(img) == expected, "{} colors, {:?}", n, metric);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn exact_colors_have_no_error() {
+		let palette = DynamicPaletteView::from(TWELVE.iter().map(|c| image::Rgba([c[0], c[1], c[2], 255])).collect::<Vec<_>>());
+		let img = twelve_color_image(16, 6);
+		let exact = |x: u32, y: u32| x % 2 == y % 2;
+		let img = image::RgbaImage::from_fn(16, 16, |x, y| {
+			let base = TWELVE[((x + 16 * y) % 12) as usize];
+			if exact(x, y) { image::Rgba([base[0], base[1], base[2], 255]) } else { *img.get_pixel(x, y) }
+		});
+		for metric in [DistanceMetric::Euclidean, DistanceMetric::WeightedRgb, DistanceMetric::Lab] {
+			let (indices, errors) = quantize_to_palette_with_error(&img, &palette, metric);
+			assert_eq!(indices, quantize_to_palette(&img, &palette, metric));
+			for ((ind, (x, y, pixel)), error) in indices.iter().zip(img.enumerate_pixels()).zip(errors) {
+				assert_eq!(error, metric.distance(pixel, &palette.to_rgba(*ind).unwrap()));
+				if exact(x, y) {
+					assert_eq!((*ind, error), (((x + 16 * y) % 12), 0));
+				}
+			}
+		}
+	}
 }
@@ -1,149 +1,795 @@
+use super::super::error::PaletteError;
+
 pub type Color = image::Rgba<u8>;
 
 /// Trait for types that describe how to convert from an arbitrary number
 /// of a fixed size to four bytes of RGBA.
-pub trait Palette: Default {
+///
+/// The trait is object-safe, so palettes can be chosen at runtime as
+/// `Box<dyn Palette>`, which is itself a `DynamicPalette` (and `Default`) for
+/// trees to be built on; `QuadtreeNode` needs `Default` palettes.
+pub trait Palette {
 	/// The bit width of each palette color's number.
 	///
 	/// Must be `1 <= WIDTH <= 32`, because 0 bits wouldn't really be a palette
 	/// and more than 32 bits would be more efficiently represented
-	/// as direct RGBA.
+	/// as direct RGBA (see `IdentityPalette`).
 	fn width(&self) -> u8;
 	/// Uses an instance of the implementing type to convert a number
 	/// representing a palette entry into an RGBA value.
 	///
 	/// If `c` is outside the range of the palette, an `Err` should
-	/// be returned.
-	fn to_rgba(&self, c: u32) -> Result<Color, ()>;
+	/// be returned (see `PaletteError::out_of_range`).
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError>;
 	/// Returns a reference to the slice listing the colors in the palette,
 	/// only if that is applicable and possible given the way the colors
 	/// are stored.
+	///
+	/// The slice may be shorter than `1 << width`, in which case the
+	/// remaining colors are transparent.
 	fn get_slice(&self) -> Option<&[Color]>;
+	/// The number of colors actually in the palette; color numbers from this
+	/// up to `1 << width` are only placeholders (transparent, typically).
+	fn len(&self) -> usize {
+		1 << self.width()
+	}
+	/// Whether `len` is zero.
+	fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+	/// If every color in the palette is the same RGB color at differing
+	/// opacities, returns that color, so that the palette may be stored
+	/// as alpha values alone.
+	fn alpha_base(&self) -> Option<[u8; 3]> {
+		None
+	}
+	/// Whether color numbers are RGBA values themselves, as with
+	/// `IdentityPalette`, so that the palette needn't be stored or searched.
+	fn is_direct(&self) -> bool {
+		false
+	}
+	/// The number of the color up to `len` closest to `c`, by
+	/// `DistanceMetric::Euclidean` (the first, if there are several), as
+	/// `NearestColor::for_palette` would find it; 0 if there are no colors.
+	///
+	/// This searches every color each time; to search with another metric,
+	/// or many times over a palette that doesn't override this, use a
+	/// `NearestColor`.
+	fn nearest(&self, c: Color) -> u32 {
+		if self.is_direct() {
+			return u32::from_be_bytes(c.0);
+		}
+		let metric = super::DistanceMetric::Euclidean;
+		let len = std::cmp::min(self.len() as u64, 1 << self.width()) as usize;
+		let best = match self.get_slice() {
+			Some(colors) => colors.iter()
+				.take(len)
+				.enumerate()
+				.map(|(ind, p)| (metric.distance(&c, p), ind))
+				.min(),
+			None => (0..len)
+				.filter_map(|ind| self.to_rgba(ind as u32).ok().map(|p| (metric.distance(&c, &p), ind)))
+				.min()
+		};
+		best.map_or(0, |(_, ind)| ind as u32)
+	}
 }
 
 /// Marker trait for `Palette` implementors that can be made from lists of
 /// dynamic length (`Vec`s, that is).
-pub trait DynamicPalette: Palette + From<Vec<Color>> {}
+pub trait DynamicPalette: Palette + From<Vec<Color>> {
+	/// A palette for QTI files with no palette (see `Palette::is_direct`),
+	/// if this type can be one; `from_qti` of those gives `WidthMismatch`
+	/// otherwise.
+	fn direct() -> Option<Self> {
+		None
+	}
+	/// Makes a palette of `colors` whose color numbers are `width` bits wide,
+	/// for QTI files, which leave trailing transparent colors out of their
+	/// palettes. Types whose width is fixed, or always fits their colors,
+	/// can ignore `width` (the default); `from_qti` gives `WidthMismatch` if
+	/// the palette doesn't have it.
+	fn with_width(colors: Vec<Color>, width: u8) -> Self {
+		let _ = width;
+		Self::from(colors)
+	}
+}
 
-/// Used internally to assist `generic_palette_struct`.
-macro_rules! generic_palette_doc {
-	($e:expr) => { concat!("A simple implementer of `Palette`; ", $e, " bits.") };
+impl<T: Palette + ?Sized> Palette for Box<T> {
+	fn width(&self) -> u8 {
+		(**self).width()
+	}
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError> {
+		(**self).to_rgba(c)
+	}
+	fn get_slice(&self) -> Option<&[Color]> {
+		(**self).get_slice()
+	}
+	fn len(&self) -> usize {
+		(**self).len()
+	}
+	fn is_empty(&self) -> bool {
+		(**self).is_empty()
+	}
+	fn alpha_base(&self) -> Option<[u8; 3]> {
+		(**self).alpha_base()
+	}
+	fn is_direct(&self) -> bool {
+		(**self).is_direct()
+	}
+	fn nearest(&self, c: Color) -> u32 {
+		(**self).nearest(c)
+	}
 }
 
-/// Used internally to generate structs that implement  `Palette`.
-macro_rules! generic_palette_struct {
-	(@inner $i:ident $n:expr, $e:expr) => {
-		#[doc = $e]
-		#[derive(Debug)]
-		pub struct $i {
-			pub colors: [Color; 1 << $n],
-		}
-		impl Palette for $i {
-			fn width(&self) -> u8 { $n }
-			fn to_rgba(&self, c: u32) -> Result<Color, ()> {
-				self.colors.get(c as usize).ok_or(()).map(|x| *x)
-			}
-			fn get_slice(&self) -> Option<&[Color]> {
-				Some(&self.colors)
-			}
-		}
-		impl Default for $i {
-			fn default() -> Self {
-				Self { colors: [image::Rgba([0; 4]); 1 << $n] }
-			}
-		}
-	};
-	($i:ident $n:expr, $e:expr) => {
-		generic_palette_struct!(@inner $i $n, generic_palette_doc![$e]);
+/// So that borrowed palettes can be used where palettes are taken by value,
+/// as by `Quantizer`.
+impl<T: Palette + ?Sized> Palette for &T {
+	fn width(&self) -> u8 {
+		(**self).width()
+	}
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError> {
+		(**self).to_rgba(c)
+	}
+	fn get_slice(&self) -> Option<&[Color]> {
+		(**self).get_slice()
+	}
+	fn len(&self) -> usize {
+		(**self).len()
+	}
+	fn is_empty(&self) -> bool {
+		(**self).is_empty()
+	}
+	fn alpha_base(&self) -> Option<[u8; 3]> {
+		(**self).alpha_base()
+	}
+	fn is_direct(&self) -> bool {
+		(**self).is_direct()
+	}
+	fn nearest(&self, c: Color) -> u32 {
+		(**self).nearest(c)
+	}
+}
+
+/// An empty `DynamicPaletteView`.
+impl Default for Box<dyn Palette> {
+	fn default() -> Self {
+		Box::new(DynamicPaletteView::default())
+	}
+}
+
+/// A `DynamicPaletteView` of the colors.
+impl From<Vec<Color>> for Box<dyn Palette> {
+	fn from(v: Vec<Color>) -> Self {
+		Box::new(DynamicPaletteView::from(v))
+	}
+}
+
+/// An `IdentityPalette` for files without a palette.
+impl DynamicPalette for Box<dyn Palette> {
+	fn with_width(colors: Vec<Color>, width: u8) -> Self {
+		Box::new(DynamicPaletteView::with_width(colors, width))
+	}
+	fn direct() -> Option<Self> {
+		Some(Box::new(IdentityPalette))
+	}
+}
+
+impl std::fmt::Debug for dyn Palette {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Palette")
+			.field("width", &self.width())
+			.field("len", &self.len())
+			.field("direct", &self.is_direct())
+			.finish_non_exhaustive()
+	}
+}
+
+/// The colors of a list that a palette of `width` gives: at most `1 << width`
+/// of them, without trailing transparent ones (which are given anyway).
+fn effective_colors(colors: &[Color], width: u8) -> &[Color] {
+	let colors = &colors[..std::cmp::min(colors.len(), 1usize.checked_shl(width as u32).unwrap_or(usize::MAX))];
+	let blank = colors.iter().rev().take_while(|c| **c == image::Rgba([0; 4])).count();
+	&colors[..colors.len() - blank]
+}
+
+/// A simple implementer of `Palette`, holding exactly `LEN` colors inline,
+/// for a width of `log2(LEN)` bits.
+///
+/// The width can't be the parameter, since array lengths can't be computed
+/// from generic parameters; `LEN` must be a power of two from 2 up, which is
+/// checked at compile time. The aliases `GenericPalette1` to
+/// `GenericPalette8` give it by width.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GenericPalette<const LEN: usize> {
+	pub colors: [Color; LEN],
+}
+
+impl<const LEN: usize> GenericPalette<LEN> {
+	const WIDTH: u8 = {
+		assert!(LEN.is_power_of_two() && LEN >= 2, "GenericPalette length must be a power of two from 2 up");
+		LEN.trailing_zeros() as u8
 	};
 }
 
-generic_palette_struct!(GenericPalette1 1, "one");
-generic_palette_struct!(GenericPalette2 2, "two");
-generic_palette_struct!(GenericPalette3 3, "three");
-generic_palette_struct!(GenericPalette4 4, "four");
-generic_palette_struct!(GenericPalette5 5, "five");
+impl<const LEN: usize> Palette for GenericPalette<LEN> {
+	fn width(&self) -> u8 { Self::WIDTH }
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError> {
+		self.colors.get(c as usize).copied().ok_or_else(|| PaletteError::out_of_range(c, Self::WIDTH))
+	}
+	fn get_slice(&self) -> Option<&[Color]> {
+		Some(&self.colors)
+	}
+}
 
-/// Used internally to generate a different sort of struct that implements `Palette`.
-macro_rules! palette_view_struct {
-	(@inner $i:ident $n:expr, $e:expr) => {
-		#[doc = $e]
-		#[derive(Debug)]
-		pub struct $i {
-			pub colors: Box<[Color]>,
-		}
-		impl Palette for $i {
-			fn width(&self) -> u8 { $n }
-			fn to_rgba(&self, c: u32) -> Result<Color, ()> {
-				if c > 1 << $n {
-					Err(())
-				} else {
-					Ok(*(self.colors.get(c as usize).unwrap_or(&image::Rgba([0; 4]))))
-				}
-			}
-			fn get_slice(&self) -> Option<&[Color]> {
-				if self.colors.len() >= 1 << $n {
-					Some(&self.colors[..1 << $n])
-				} else {
-					None
-				}
-			}
-		}
-		impl Default for $i {
-			fn default() -> Self {
-				Self { colors: Box::new([image::Rgba([0; 4]); 0]) }
-			}
+impl<const LEN: usize> Default for GenericPalette<LEN> {
+	fn default() -> Self {
+		Self { colors: [image::Rgba([0; 4]); LEN] }
+	}
+}
+
+/// Colors past the `LEN` that fit are dropped, and missing ones are
+/// transparent black.
+impl<const LEN: usize> From<Vec<Color>> for GenericPalette<LEN> {
+	fn from(inp: Vec<Color>) -> Self {
+		let mut palette = Self::default();
+		palette.colors.iter_mut().zip(inp).for_each(|(c, inp)| *c = inp);
+		palette
+	}
+}
+
+impl<const LEN: usize> DynamicPalette for GenericPalette<LEN> {}
+
+pub type GenericPalette1 = GenericPalette<2>;
+pub type GenericPalette2 = GenericPalette<4>;
+pub type GenericPalette3 = GenericPalette<8>;
+pub type GenericPalette4 = GenericPalette<16>;
+pub type GenericPalette5 = GenericPalette<32>;
+pub type GenericPalette6 = GenericPalette<64>;
+pub type GenericPalette7 = GenericPalette<128>;
+pub type GenericPalette8 = GenericPalette<256>;
+
+/// A view into a slice for implementing `Palette` with a width of `W` bits
+/// (from 1 to 32, checked at compile time).
+///
+/// The slice may be shorter than `1 << W`, with color numbers past its end
+/// being transparent black, or longer, with the colors past that ignored.
+#[derive(Debug)]
+pub struct PaletteView<const W: u8> {
+	pub colors: Box<[Color]>,
+}
+
+impl<const W: u8> PaletteView<W> {
+	/// The number of colors that the width allows.
+	const EXTENT: u64 = {
+		assert!(W >= 1 && W <= 32, "PaletteView width must be from 1 to 32");
+		1 << W
+	};
+}
+
+impl<const W: u8> Palette for PaletteView<W> {
+	fn width(&self) -> u8 { W }
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError> {
+		if c as u64 >= Self::EXTENT {
+			Err(PaletteError::out_of_range(c, W))
+		} else {
+			Ok(*(self.colors.get(c as usize).unwrap_or(&image::Rgba([0; 4]))))
 		}
-		impl From<Vec<Color>> for $i {
-			fn from(inp: Vec<Color>) -> Self {
-				Self { colors: inp.into_boxed_slice() }
-			}
+	}
+	fn len(&self) -> usize {
+		std::cmp::min(self.colors.len() as u64, Self::EXTENT) as usize
+	}
+	fn get_slice(&self) -> Option<&[Color]> {
+		if self.colors.len() as u64 >= Self::EXTENT {
+			Some(&self.colors[..Self::EXTENT as usize])
+		} else {
+			None
 		}
-	};
-	($i:ident $n:expr, $e:expr) => {
-		palette_view_struct!(@inner $i $n, concat!(
-			"A view into a slice for implementing `Palette` with a width of ",
-		$e));
-	};
+	}
+}
+
+impl<const W: u8> Default for PaletteView<W> {
+	fn default() -> Self {
+		Self { colors: Box::new([image::Rgba([0; 4]); 0]) }
+	}
+}
+
+impl<const W: u8> From<Vec<Color>> for PaletteView<W> {
+	fn from(inp: Vec<Color>) -> Self {
+		Self { colors: inp.into_boxed_slice() }
+	}
 }
 
-palette_view_struct!(PaletteView1 1, "one");
-palette_view_struct!(PaletteView2 2, "two");
-palette_view_struct!(PaletteView3 3, "three");
-palette_view_struct!(PaletteView4 4, "four");
-palette_view_struct!(PaletteView5 5, "five");
-palette_view_struct!(PaletteView6 6, "six");
-palette_view_struct!(PaletteView7 7, "seven");
-palette_view_struct!(PaletteView8 8, "eight");
+impl<const W: u8> DynamicPalette for PaletteView<W> {}
+
+/// Compares the colors that the palettes give, so entries past the width or
+/// trailing transparent ones make no difference.
+impl<const W: u8> PartialEq for PaletteView<W> {
+	fn eq(&self, other: &Self) -> bool {
+		effective_colors(&self.colors, W) == effective_colors(&other.colors, W)
+	}
+}
+
+impl<const W: u8> Eq for PaletteView<W> {}
+
+pub type PaletteView1 = PaletteView<1>;
+pub type PaletteView2 = PaletteView<2>;
+pub type PaletteView3 = PaletteView<3>;
+pub type PaletteView4 = PaletteView<4>;
+pub type PaletteView5 = PaletteView<5>;
+pub type PaletteView6 = PaletteView<6>;
+pub type PaletteView7 = PaletteView<7>;
+pub type PaletteView8 = PaletteView<8>;
+pub type PaletteView12 = PaletteView<12>;
+pub type PaletteView16 = PaletteView<16>;
 
 /// A list of colors forming a palette, of a width determined at runtime.
+///
+/// The width is the smallest that fits all of the colors (but at least 1),
+/// unless given with `with_width`; every color of the list is within it.
+/// Color numbers past the end of the list are out of range, even those below
+/// `1 << width`; QTI files read into one get the color that those stand for
+/// in the file added to the list (see `qti::Header::palette_colors`).
 #[derive(Debug)]
 pub struct DynamicPaletteView {
-	pub colors: Box<[Color]>
+	/// The colors; `nearest` searches them as they were when it was first
+	/// called, so a palette whose colors change should be made anew.
+	pub colors: Box<[Color]>,
+	width: u8,
+	/// The searcher for `nearest`, made when it is first needed.
+	nearest: std::sync::OnceLock<super::NearestColor>,
 }
 
 impl Palette for DynamicPaletteView {
 	fn width(&self) -> u8 {
-		(31 - (self.colors.len() as u32).leading_zeros()) as u8
+		self.width
+	}
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError> {
+		// An empty palette has no last color; 0 is as close as `max` can say
+		self.colors.get(c as usize).copied().ok_or(PaletteError::OutOfRange {
+			index: c,
+			max: self.colors.len().saturating_sub(1) as u32
+		})
 	}
-	fn to_rgba(&self, c: u32) -> Result<Color, ()> {
-		Ok(*(self.colors.get(c as usize).unwrap_or(&image::Rgba([0; 4]))))
+	fn len(&self) -> usize {
+		self.colors.len()
 	}
 	fn get_slice(&self) -> Option<&[Color]> {
-		Some(&self.colors[..1 << self.width()])
+		Some(&self.colors)
+	}
+	/// Searches a `NearestColor` made on the first call, rather than every
+	/// color.
+	fn nearest(&self, c: Color) -> u32 {
+		if self.colors.is_empty() {
+			return 0;
+		}
+		self.nearest
+			.get_or_init(|| super::NearestColor::new(self.colors.to_vec(), super::DistanceMetric::Euclidean))
+			.find(&c)
+	}
+}
+
+impl DynamicPaletteView {
+	/// A palette of `colors` with an explicit `width`, rather than the
+	/// smallest that fits them; for leaving room for colors to be added
+	/// later, or matching the width of another palette.
+	///
+	/// Panics if `width` is not from 1 to 32, or is too small for `colors`.
+	pub fn with_width(colors: Vec<Color>, width: u8) -> Self {
+		assert!((1..=32).contains(&width), "palette width must be from 1 to 32");
+		assert!(colors.len() as u64 <= 1 << width, "too many colors for palette width");
+		DynamicPaletteView { colors: colors.into_boxed_slice(), width, nearest: Default::default() }
 	}
 }
 
 impl Default for DynamicPaletteView {
 	fn default() -> Self {
-		DynamicPaletteView { colors: Default::default() }
+		DynamicPaletteView { colors: Default::default(), width: 1, nearest: Default::default() }
 	}
 }
 
 impl From<Vec<Color>> for DynamicPaletteView {
 	fn from(v: Vec<Color>) -> Self {
-		DynamicPaletteView { colors: v.into_boxed_slice() }
+		let width = v.len().next_power_of_two().trailing_zeros().clamp(1, 32) as u8;
+		DynamicPaletteView { colors: v.into_boxed_slice(), width, nearest: Default::default() }
+	}
+}
+
+impl DynamicPalette for DynamicPaletteView {
+	fn with_width(colors: Vec<Color>, width: u8) -> Self {
+		DynamicPaletteView::with_width(colors, width)
+	}
+}
+
+/// Compares widths and the colors that the palettes give, so trailing
+/// transparent entries make no difference.
+impl PartialEq for DynamicPaletteView {
+	fn eq(&self, other: &Self) -> bool {
+		self.width == other.width &&
+			effective_colors(&self.colors, self.width) == effective_colors(&other.colors, other.width)
+	}
+}
+
+impl Eq for DynamicPaletteView {}
+
+/// A palette of one base color at varying levels of opacity, for masks and
+/// coverage maps; stored in QTI files with one byte per entry.
+#[derive(Debug)]
+pub struct AlphaPalette {
+	pub base: [u8; 3],
+	pub alphas: Box<[u8]>,
+}
+
+impl Palette for AlphaPalette {
+	fn width(&self) -> u8 {
+		std::cmp::max(1, self.alphas.len().next_power_of_two().trailing_zeros() as u8)
+	}
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError> {
+		if c as u64 >= 1 << self.width() {
+			Err(PaletteError::out_of_range(c, self.width()))
+		} else {
+			let [r, g, b] = self.base;
+			Ok(image::Rgba([r, g, b, *self.alphas.get(c as usize).unwrap_or(&0)]))
+		}
+	}
+	fn len(&self) -> usize {
+		self.alphas.len()
+	}
+	fn get_slice(&self) -> Option<&[Color]> {
+		None
+	}
+	fn alpha_base(&self) -> Option<[u8; 3]> {
+		Some(self.base)
+	}
+}
+
+impl Default for AlphaPalette {
+	fn default() -> Self {
+		AlphaPalette { base: [255; 3], alphas: Default::default() }
+	}
+}
+
+/// Takes the base color from the first entry; only the alpha channel of the
+/// other entries is kept.
+impl From<Vec<Color>> for AlphaPalette {
+	fn from(v: Vec<Color>) -> Self {
+		let base = v.first().map_or([255; 3], |c| [c.0[0], c.0[1], c.0[2]]);
+		AlphaPalette { base, alphas: v.iter().map(|c| c.0[3]).collect() }
+	}
+}
+
+/// Pads the opacities with zeros to the fewest that need `width`, for widths
+/// up to 16; wider palettes of fewer colors can't be made this way.
+impl DynamicPalette for AlphaPalette {
+	fn with_width(colors: Vec<Color>, width: u8) -> Self {
+		let mut palette = Self::from(colors);
+		if (2..=16).contains(&width) && palette.alphas.len() <= 1 << (width - 1) {
+			let mut alphas = palette.alphas.into_vec();
+			alphas.resize((1 << (width - 1)) + 1, 0);
+			palette.alphas = alphas.into_boxed_slice();
+		}
+		palette
+	}
+}
+
+/// Compares widths, base colors and the opacities that the palettes give,
+/// so trailing fully transparent entries make no difference.
+impl PartialEq for AlphaPalette {
+	fn eq(&self, other: &Self) -> bool {
+		let used = |alphas: &[u8]| alphas.len() - alphas.iter().rev().take_while(|a| **a == 0).count();
+		self.width() == other.width() &&
+			self.base == other.base &&
+			self.alphas[..used(&self.alphas)] == other.alphas[..used(&other.alphas)]
 	}
 }
 
-impl DynamicPalette for DynamicPaletteView {}
\ No newline at end of file
+impl Eq for AlphaPalette {}
+
+/// A palette whose colors are computed by a function of their numbers, for
+/// procedural palettes (such as gradients) that needn't be stored as a list.
+///
+/// `to_qti` still writes out every color (up to the last non-transparent
+/// one), so QTI files of these are no smaller.
+pub struct FnPalette<F> {
+	width: u8,
+	f: Option<F>,
+}
+
+impl<F: Fn(u32) -> Color> FnPalette<F> {
+	/// A palette of `width` bits, giving `f(c)` for each color number `c`.
+	///
+	/// Panics if `width` is not from 1 to 32.
+	pub fn new(width: u8, f: F) -> Self {
+		assert!((1..=32).contains(&width), "palette width must be from 1 to 32");
+		FnPalette { width, f: Some(f) }
+	}
+}
+
+impl<F: Fn(u32) -> Color> Palette for FnPalette<F> {
+	fn width(&self) -> u8 {
+		self.width
+	}
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError> {
+		if c as u64 >= 1 << self.width {
+			Err(PaletteError::out_of_range(c, self.width))
+		} else {
+			Ok(self.f.as_ref().map_or(image::Rgba([0; 4]), |f| f(c)))
+		}
+	}
+	fn get_slice(&self) -> Option<&[Color]> {
+		None
+	}
+}
+
+/// A palette of one bit with no function, whose colors are both transparent.
+impl<F> Default for FnPalette<F> {
+	fn default() -> Self {
+		FnPalette { width: 1, f: None }
+	}
+}
+
+impl<F> std::fmt::Debug for FnPalette<F> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("FnPalette").field("width", &self.width).finish_non_exhaustive()
+	}
+}
+
+/// A 32-bit "palette" whose color numbers are RGBA values (bitwise
+/// big-endian, so `0xff0000ff` is opaque red), for lossless trees: every
+/// color is quantized to itself.
+///
+/// QTI files of these have no palette (see `qti::FLAG_DIRECT_COLOR`), but
+/// each node takes 33 bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IdentityPalette;
+
+impl Palette for IdentityPalette {
+	fn width(&self) -> u8 {
+		32
+	}
+	fn to_rgba(&self, c: u32) -> Result<Color, PaletteError> {
+		Ok(image::Rgba(c.to_be_bytes()))
+	}
+	fn get_slice(&self) -> Option<&[Color]> {
+		None
+	}
+	fn is_direct(&self) -> bool {
+		true
+	}
+}
+
+/// Ignores the colors, which a direct palette has no use for; this is so
+/// that `from_qti` can give one.
+impl From<Vec<Color>> for IdentityPalette {
+	fn from(_: Vec<Color>) -> Self {
+		IdentityPalette
+	}
+}
+
+impl DynamicPalette for IdentityPalette {
+	fn direct() -> Option<Self> {
+		Some(IdentityPalette)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::error::{AnalyzeError, DrawError, EncodeError, MountError};
+	use crate::image::AnalyzeOptions;
+	use crate::QuadtreeNode;
+
+	/// `n` distinct opaque colors.
+	fn colors(n: usize) -> Vec<Color> {
+		(0..n).map(|c| image::Rgba([c as u8, (c >> 8) as u8, 0, 255])).collect()
+	}
+
+	/// A tree of one leaf.
+	fn leaf<P: Palette + Default>(color: u32) -> QuadtreeNode<P> {
+		QuadtreeNode { color, ..Default::default() }
+	}
+
+	/// Checks that color number `last` of `palette` can be looked up, mounted,
+	/// encoded and drawn, and that the next one gives an error for each.
+	fn check_boundary<P: Palette + Default>(palette: &P, last: u32) {
+		let mut bits = Default::default();
+		let mut img = image::RgbaImage::new(1, 1);
+		let options = AnalyzeOptions::default().gradient_fit(None);
+		assert!(palette.to_rgba(last).is_ok());
+		assert!(leaf::<P>(0).mount_with(&[last; 4], palette, &options).is_ok());
+		assert!(leaf::<P>(last).encode_v1(&mut bits, palette).is_ok());
+		assert!(leaf::<P>(last).to_image(&mut img, palette, None, None, false, None).is_ok());
+		if let Some(past) = last.checked_add(1) {
+			assert_eq!(palette.to_rgba(past), Err(PaletteError::OutOfRange { index: past, max: last }));
+			assert!(matches!(leaf::<P>(0).mount_with(&[past; 4], palette, &options), Err(MountError::ColorOutOfRange)));
+			assert!(matches!(
+				leaf::<P>(past).encode_v1(&mut bits, palette),
+				Err(EncodeError::ColorOutOfRange { index, .. }) if index == past
+			));
+			assert!(matches!(
+				leaf::<P>(past).to_image(&mut img, palette, None, None, false, None),
+				Err(DrawError::ColorOutOfRange { index, .. }) if index == past
+			));
+		}
+	}
+
+	#[test]
+	fn color_number_boundaries() {
+		check_boundary(&GenericPalette1::from(colors(2)), 1);
+		check_boundary(&GenericPalette8::from(colors(256)), 255);
+		check_boundary(&PaletteView1::from(colors(2)), 1);
+		// Numbers past a short list are still in range, as transparent black
+		check_boundary(&PaletteView8::from(colors(10)), 255);
+		check_boundary(&PaletteView16::default(), (1 << 16) - 1);
+		check_boundary(&PaletteView::<32>::default(), u32::MAX);
+		check_boundary(&DynamicPaletteView::from(colors(2)), 1);
+		check_boundary(&DynamicPaletteView::from(colors(256)), 255);
+		check_boundary(&Box::<dyn Palette>::from(colors(4)), 3);
+		check_boundary(&AlphaPalette::from(colors(4)), 3);
+		check_boundary(&FnPalette::new(1, |c| image::Rgba([c as u8; 4])), 1);
+		check_boundary(&FnPalette::new(32, |c| image::Rgba(c.to_be_bytes())), u32::MAX);
+		check_boundary(&IdentityPalette, u32::MAX);
+	}
+
+	#[test]
+	fn out_of_range_errors_give_the_node() {
+		let palette = DynamicPaletteView::from(colors(4));
+		let branch = |sections: [QuadtreeNode<DynamicPaletteView>; 4], color| {
+			QuadtreeNode { color, sections: Some(Box::new(sections)), ..Default::default() }
+		};
+		let tree = branch([leaf(0), branch([1, 2, 3, 9].map(leaf), 1), leaf(1), branch([7, 0, 0, 0].map(leaf), 7)], 0);
+		assert_eq!(palette.to_rgba(9), Err(PaletteError::OutOfRange { index: 9, max: 3 }));
+		// The first bad node, in the order nodes are encoded and drawn
+		assert!(matches!(
+			tree.to_qti(&palette),
+			Err(EncodeError::ColorOutOfRange { index: 9, path }) if path == [1, 3]
+		));
+		assert!(matches!(
+			tree.render(&palette, false),
+			Err(DrawError::ColorOutOfRange { index: 9, path }) if path == [1, 3]
+		));
+	}
+
+	#[test]
+	fn dynamic_palette_view_lengths() {
+		let empty = DynamicPaletteView::from(Vec::new());
+		assert_eq!((empty.width(), empty.len()), (1, 0));
+		assert_eq!(empty.get_slice(), Some(&[][..]));
+		assert_eq!(empty.to_rgba(0), Err(PaletteError::OutOfRange { index: 0, max: 0 }));
+		assert_eq!(empty.nearest(image::Rgba([255; 4])), 0);
+		assert!(matches!(leaf(0).to_qti(&empty), Err(EncodeError::ColorOutOfRange { index: 0, .. })));
+		let result = QuadtreeNode::default().from_image_with(&image::RgbaImage::new(4, 4), &empty, &AnalyzeOptions::default().blur(0.).gradient_fit(None));
+		assert!(matches!(result, Err(AnalyzeError::EmptyPalette)));
+
+		for (len, width) in [(1, 1), (3, 2), (127, 7), (128, 7), (129, 8), (200, 8), (257, 9)] {
+			let palette = DynamicPaletteView::from(colors(len));
+			assert_eq!((palette.width(), palette.len()), (width, len));
+			assert_eq!(palette.get_slice().map(<[Color]>::len), Some(len));
+			assert_eq!(palette.to_rgba(len as u32 - 1), Ok(colors(len)[len - 1]));
+			assert_eq!(
+				palette.to_rgba(len as u32),
+				Err(PaletteError::OutOfRange { index: len as u32, max: len as u32 - 1 })
+			);
+			// The last color is used, so none can be left out of the file
+			let mut image = crate::test_util::noise(32, len as u32, 8);
+			image[0] = len as u32 - 1;
+			let mut tree = QuadtreeNode::default();
+			tree.mount_with(&image, &palette, &AnalyzeOptions::default().gradient_fit(None)).unwrap();
+			let (decoded, decoded_palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&tree.to_qti(&palette).unwrap())
+				.unwrap();
+			assert_eq!((decoded, decoded_palette), (tree, palette));
+		}
+	}
+
+	#[test]
+	fn nearest_matches_a_full_search() {
+		let mut rng = crate::test_util::Lcg(25);
+		let mut random_color = || image::Rgba(rng.next_u32().to_be_bytes());
+		for len in [1, 2, 5, 200, 256, 1000] {
+			let colors = (0..len).map(|_| random_color()).collect::<Vec<Color>>();
+			let palette = DynamicPaletteView::from(colors.clone());
+			// `PaletteView` keeps the trait's search of every color
+			let full = PaletteView16::from(colors.clone());
+			for (ind, color) in colors.iter().enumerate() {
+				assert_eq!(palette.to_rgba(palette.nearest(*color)), Ok(*color), "{} colors, {}", len, ind);
+			}
+			for _ in 0..2000 {
+				let color = random_color();
+				assert_eq!(palette.nearest(color), full.nearest(color), "{} colors, {:?}", len, color);
+			}
+		}
+	}
+
+	#[test]
+	fn fixed_palettes_decode_like_dynamic_ones() {
+		let img = crate::test_util::test_image(32, 26);
+		let palette: GenericPalette4 = crate::quantize::generate_palette(&img, 4096);
+		let mut tree = QuadtreeNode::default();
+		tree.from_image_with(&img, &palette, &Default::default()).unwrap();
+		let data = tree.to_qti(&palette).unwrap();
+
+		let (fixed, fixed_palette) = QuadtreeNode::<GenericPalette4>::from_qti(&data).unwrap();
+		let (dynamic, dynamic_palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+		assert_eq!((&fixed, &fixed_palette), (&tree, &palette));
+		assert_eq!(fixed_palette.get_slice(), dynamic_palette.get_slice().map(|c| &c[..16]));
+		assert_eq!(fixed.render(&fixed_palette, false).unwrap(), dynamic.render(&dynamic_palette, false).unwrap());
+
+		// The file's palette is padded out to all sixteen colors
+		let short = DynamicPaletteView::with_width(colors(5), 4);
+		let tree = QuadtreeNode::<DynamicPaletteView>::combine([0, 1, 2, 4].map(leaf), 0);
+		let (fixed, fixed_palette) = QuadtreeNode::<GenericPalette4>::from_qti(&tree.to_qti(&short).unwrap()).unwrap();
+		assert_eq!(fixed_palette, GenericPalette4::from(colors(5)));
+		assert_eq!(fixed.render(&fixed_palette, false).unwrap(), tree.render(&short, false).unwrap());
+		// and can't be read into a palette of another width
+		assert!(matches!(
+			QuadtreeNode::<GenericPalette3>::from_qti(&tree.to_qti(&short).unwrap()),
+			Err(crate::error::DecodeError::WidthMismatch)
+		));
+	}
+
+	/// Checks that a tree of `color_count` colors on `palette` comes back
+	/// from QTI data as it was, palette and all.
+	fn check_round_trip<P: DynamicPalette + Default + Eq + std::fmt::Debug>(palette: P, color_count: u32) {
+		let mut tree = QuadtreeNode::default();
+		tree.mount_with(&crate::test_util::noise(16, color_count, 11), &palette, &Default::default()).unwrap();
+		let data = tree.to_qti(&palette).unwrap();
+		assert_eq!(QuadtreeNode::<P>::from_qti(&data).unwrap(), (tree, palette));
+	}
+
+	#[test]
+	fn palettes_round_trip() {
+		// Each alias, full
+		check_round_trip(GenericPalette1::from(colors(2)), 2);
+		check_round_trip(GenericPalette2::from(colors(4)), 4);
+		check_round_trip(GenericPalette3::from(colors(8)), 8);
+		check_round_trip(GenericPalette4::from(colors(16)), 16);
+		check_round_trip(GenericPalette5::from(colors(32)), 32);
+		check_round_trip(GenericPalette6::from(colors(64)), 64);
+		check_round_trip(GenericPalette7::from(colors(128)), 128);
+		check_round_trip(GenericPalette8::from(colors(256)), 256);
+		check_round_trip(PaletteView1::from(colors(2)), 2);
+		check_round_trip(PaletteView2::from(colors(4)), 4);
+		check_round_trip(PaletteView3::from(colors(8)), 8);
+		check_round_trip(PaletteView4::from(colors(16)), 16);
+		check_round_trip(PaletteView5::from(colors(32)), 32);
+		check_round_trip(PaletteView6::from(colors(64)), 64);
+		check_round_trip(PaletteView7::from(colors(128)), 128);
+		check_round_trip(PaletteView8::from(colors(256)), 256);
+		check_round_trip(PaletteView12::from(colors(4096)), 4096);
+		check_round_trip(PaletteView16::from(colors(1 << 16)), 1 << 16);
+		// and part full
+		check_round_trip(GenericPalette8::from(colors(200)), 200);
+		check_round_trip(PaletteView8::from(colors(10)), 10);
+		check_round_trip(PaletteView12::from(colors(300)), 300);
+		check_round_trip(DynamicPaletteView::from(colors(5)), 5);
+		check_round_trip(AlphaPalette::from(colors(3)), 3);
+
+		// Equal palettes give the same colors, however they are stored
+		let mut padded = colors(10);
+		padded.resize(40, image::Rgba([0; 4]));
+		assert_eq!(PaletteView8::from(padded.clone()), PaletteView8::from(colors(10)));
+		assert_eq!(PaletteView4::from(colors(20)), PaletteView4::from(colors(16)));
+		assert_eq!(DynamicPaletteView::with_width(padded, 8), DynamicPaletteView::with_width(colors(10), 8));
+		assert_ne!(DynamicPaletteView::with_width(colors(10), 8), DynamicPaletteView::from(colors(10)));
+		let mut changed = colors(10);
+		changed[9].0[2] = 1;
+		assert_ne!(PaletteView8::from(changed), PaletteView8::from(colors(10)));
+	}
+
+	#[test]
+	fn approximate_equality() {
+		// Neighboring colors of `colors` are one step apart
+		let palette = DynamicPaletteView::from(colors(8));
+		let tree = |colors: [u32; 4]| QuadtreeNode::<DynamicPaletteView> {
+			color: colors[0],
+			sections: Some(Box::new(colors.map(leaf))),
+			..Default::default()
+		};
+		let (a, b) = (tree([0, 2, 4, 6]), tree([1, 2, 3, 6]));
+		assert!(a.approx_eq(&a, &palette, 0));
+		assert!(!a.approx_eq(&b, &palette, 0));
+		assert!(a.approx_eq(&b, &palette, 1));
+		assert!(!a.approx_eq(&leaf(0), &palette, u32::MAX));
+		assert!(!a.approx_eq(&tree([0, 2, 4, 8]), &palette, u32::MAX));
+	}
+}
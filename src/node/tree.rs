@@ -0,0 +1,782 @@
+use super::quantize::{self, palette::Palette};
+use super::TreePath;
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+impl<P: Palette + Default> super::QuadtreeNode<P> {
+
+	/// "Trims" the tree by removing leaf nodes.
+	///
+	/// Only leaf nodes past a depth of `depth` and with color repetition
+	/// will be removed.
+	///
+	/// Each call removes at most one level of leaves from each branch, so
+	/// calling this `n` times with the same `depth` can flatten the tree
+	/// by up to `n` levels (but never above `depth`).
+	pub fn trim(&mut self, depth: isize) {
+		if let Some(sections) = &mut self.sections {
+			if depth <= 0 && sections.iter().all(|s| s.sections.is_none()) {
+				// Count unique colors
+				let col_f = sections.iter().fold(HashMap::new(),
+					|mut m, e| { *m.entry(e.color).or_insert(0) += 1; m });
+				let freq = col_f.values().collect::<Vec<_>>();
+				if freq.len() == 3 || (freq.len() == 2 && **freq.iter().max().unwrap() == 3) {
+					self.sections = None;
+				}
+			} else {
+				sections.iter_mut().for_each(|s| s.trim(depth - 1));
+			}
+		}
+	}
+
+	/// Like `trim`, but removes leaf nodes whenever their most common color
+	/// is used by at least a fraction `dominance` of the four of them, and
+	/// sets their parent to that color (`trim` leaves the parent's color as
+	/// it was).
+	///
+	/// No `dominance` removes the same leaves as `trim`: 0.75 removes leaves
+	/// split 3:1, as `trim` does, but also uniform leaves, which `trim`
+	/// keeps, and not those split 2:1:1; 0.5 removes those too, but also
+	/// leaves split 2:2.
+	pub fn trim_lossy(&mut self, depth: isize, dominance: f64) {
+		if let Some(sections) = &mut self.sections {
+			if depth <= 0 && sections.iter().all(|s| s.sections.is_none()) {
+				let col_f = sections.iter().fold(HashMap::new(),
+					|mut m, e| { *m.entry(e.color).or_insert(0) += 1; m });
+				// Prefer keeping the current color in case of ties
+				let (color, count) = col_f.into_iter()
+					.max_by_key(|(c, n)| (*n, *c == self.color, std::cmp::Reverse(*c)))
+					.unwrap();
+				if count as f64 >= dominance * 4. {
+					self.color = color;
+					self.sections = None;
+				}
+			} else {
+				sections.iter_mut().for_each(|s| s.trim_lossy(depth - 1, dominance));
+			}
+		}
+	}
+
+	/// Like `trim`, but removes leaf nodes (at any depth) whenever they are
+	/// all close to their average color: each branch whose subsections are
+	/// all leaves becomes a leaf of the palette color closest to their
+	/// average, if no subsection's color is more than `max_error` (squared
+	/// distance) from it.
+	///
+	/// Works from the bottom up, so the new leaves may be merged in turn.
+	/// Branches with colors outside the palette are left as they are.
+	pub fn trim_by_error(&mut self, palette: &P, max_error: u32) {
+		let nearest = quantize::NearestColor::for_palette(palette, quantize::DistanceMetric::Euclidean);
+		self.trim_by_error_with(palette, max_error, &nearest);
+	}
+
+	/// Helper for `trim_by_error`.
+	fn trim_by_error_with(&mut self, palette: &P, max_error: u32, nearest: &quantize::NearestColor) {
+		let sections = match &mut self.sections {
+			Some(sections) => sections,
+			None => return
+		};
+		sections.iter_mut().for_each(|s| s.trim_by_error_with(palette, max_error, nearest));
+		if sections.iter().any(|s| s.sections.is_some()) {
+			return;
+		}
+		let merged = match self.average_color(palette) {
+			Ok(avg) => nearest.find(&avg),
+			Err(_) => return
+		};
+		let merged_rgba = match palette.to_rgba(merged) {
+			Ok(c) => c,
+			Err(_) => return
+		};
+		let within_budget = self.sections.iter().flat_map(|s| s.iter()).all(|s| palette.to_rgba(s.color)
+			.is_ok_and(|c| quantize::DistanceMetric::Euclidean.distance(&c, &merged_rgba) <= max_error));
+		if within_budget {
+			self.color = merged;
+			self.sections = None;
+		}
+	}
+
+	/// Removes leaf nodes until the tree has at most `target_nodes` nodes
+	/// (see `node_count`), for a predictable encoded size.
+	///
+	/// Branches whose subsections are all leaves are merged one at a time,
+	/// cheapest first, into a leaf of the palette color closest to their
+	/// average (as with `trim_by_error`). The cost of a merge is the squared
+	/// distance of each subsection's color from the new one, weighted by the
+	/// area the subsection covers. Merging can make the parent branch a
+	/// candidate in turn.
+	///
+	/// Stops early if there is nothing left to merge: the tree is a single
+	/// leaf, or the remaining branches have colors outside the palette.
+	pub fn trim_to_node_count(&mut self, target_nodes: usize, palette: &P) {
+		let mut count = self.node_count();
+		if count <= target_nodes {
+			return;
+		}
+		let nearest = quantize::NearestColor::for_palette(palette, quantize::DistanceMetric::Euclidean);
+		let depth = self.depth();
+		let mut candidates = BinaryHeap::new();
+		self.merge_candidates(palette, &nearest, depth, &mut Vec::new(), &mut candidates);
+		while count > target_nodes {
+			let (_, path, color) = match candidates.pop() {
+				Some(c) => c,
+				None => return
+			};
+			let node = self.subtree_mut(&path).expect("candidates are branches of the tree");
+			node.color = color;
+			node.sections = None;
+			count -= 4;
+			if let Some((_, parent_path)) = path.split_last() {
+				let parent = self.subtree(parent_path).expect("candidates are branches of the tree");
+				if let Some((cost, color)) = parent.merge_cost(palette, &nearest, depth - parent_path.len() - 1) {
+					candidates.push((Reverse(cost), parent_path.to_vec(), color));
+				}
+			}
+		}
+	}
+
+	/// Helper for `trim_to_node_count`; adds each branch below this one whose
+	/// subsections are all leaves to `candidates`, with its path, merged
+	/// color and the cost of merging it. `levels` is the depth of the
+	/// whole tree less the length of `path`.
+	fn merge_candidates(
+		&self,
+		palette: &P,
+		nearest: &quantize::NearestColor,
+		levels: usize,
+		path: &mut Vec<u8>,
+		candidates: &mut BinaryHeap<(Reverse<u128>, Vec<u8>, u32)>
+	) {
+		let sections = match &self.sections {
+			Some(sections) => sections,
+			None => return
+		};
+		if sections.iter().all(|s| s.sections.is_none()) {
+			if let Some((cost, color)) = self.merge_cost(palette, nearest, levels - 1) {
+				candidates.push((Reverse(cost), path.clone(), color));
+			}
+			return;
+		}
+		for (ind, section) in sections.iter().enumerate() {
+			path.push(ind as u8);
+			section.merge_candidates(palette, nearest, levels - 1, path, candidates);
+			path.pop();
+		}
+	}
+
+	/// Helper for `trim_to_node_count`; the cost of merging this branch's
+	/// subsections (all leaves) and the color they would be merged into, or
+	/// `None` if it isn't such a branch or its colors are out of range.
+	/// Each subsection covers `4^levels` of the deepest nodes of the tree.
+	fn merge_cost(&self, palette: &P, nearest: &quantize::NearestColor, levels: usize) -> Option<(u128, u32)> {
+		let sections = self.sections.as_ref()?;
+		if sections.iter().any(|s| s.sections.is_some()) {
+			return None;
+		}
+		let merged = nearest.find(&self.average_color(palette).ok()?);
+		let merged_rgba = palette.to_rgba(merged).ok()?;
+		let error = sections.iter().try_fold(0u128, |sum, s| palette.to_rgba(s.color).ok()
+			.map(|c| sum + quantize::DistanceMetric::Euclidean.distance(&c, &merged_rgba) as u128))?;
+		Some((error.saturating_mul(1u128.checked_shl(2 * levels as u32).unwrap_or(u128::MAX)), merged))
+	}
+
+	/// Replaces each branch whose subsections are all leaves of the same
+	/// color with a leaf of that color, from the bottom up, so that the tree
+	/// takes fewer bits to encode.
+	///
+	/// The tree renders the same as before without gradients; with them
+	/// (or with `UpscaleMode::Smooth`), the new leaves may be blended with
+	/// their siblings.
+	pub fn normalize(&mut self) {
+		if let Some(sections) = &mut self.sections {
+			sections.iter_mut().for_each(|s| s.normalize());
+			let color = sections[0].color;
+			if sections.iter().all(|s| s.sections.is_none() && s.color == color) {
+				self.color = color;
+				self.sections = None;
+			}
+		}
+	}
+
+	/// Like `normalize`, but only where the tree still renders the same with
+	/// `gradient`.
+	///
+	/// A branch of four leaves of one color is drawn as a gradient of that
+	/// color alone, like a leaf of it, but replacing it with a leaf may
+	/// leave its parent's subsections all leaves, to be drawn as a gradient
+	/// between them. So where all of a branch's subsections would become
+	/// leaves, of more than one color, one of them is left as it is.
+	pub fn normalize_gradients(&mut self) {
+		self.normalize_gradient_sections();
+		// The root has no siblings to be blended with
+		if let Some(color) = self.uniform_color() {
+			self.color = color;
+			self.sections = None;
+		}
+	}
+
+	/// Helper for `normalize_gradients`; normalizes the subsections of this
+	/// node, leaving this node itself as it is.
+	fn normalize_gradient_sections(&mut self) {
+		let sections = match self.sections {
+			Some(ref mut sections) => sections,
+			None => return
+		};
+		sections.iter_mut().for_each(|s| s.normalize_gradient_sections());
+		// The color each subsection would be as a leaf, if it would be one
+		let leaf_colors = sections.iter()
+			.map(|s| if s.sections.is_none() { Some(s.color) } else { s.uniform_color() })
+			.collect::<Vec<_>>();
+		let keep = if leaf_colors.iter().all(|c| c.is_some()) && leaf_colors.iter().any(|c| *c != leaf_colors[0]) {
+			sections.iter().rposition(|s| s.sections.is_some())
+		} else {
+			None
+		};
+		for (ind, (section, color)) in sections.iter_mut().zip(leaf_colors).enumerate() {
+			if let (Some(color), true, false) = (color, section.sections.is_some(), keep == Some(ind)) {
+				section.color = color;
+				section.sections = None;
+			}
+		}
+	}
+
+	/// The color of this node's subsections, if they are all leaves of one
+	/// color.
+	fn uniform_color(&self) -> Option<u32> {
+		let sections = self.sections.as_ref()?;
+		let color = sections[0].color;
+		if sections.iter().all(|s| s.sections.is_none() && s.color == color) {
+			Some(color)
+		} else {
+			None
+		}
+	}
+
+	/// The number of distinct subtrees in the tree: nodes count as the same
+	/// if they and everything below them are equal (by `==`). This is at
+	/// most `node_count`; the fewer there are, the more repetitive the image,
+	/// and the less a format storing each distinct subtree once would take.
+	///
+	/// Only nodes equal all the way down are counted once, so this is best
+	/// measured after `canonicalize`.
+	pub fn count_unique_subtrees(&self) -> usize {
+		let mut ids = HashMap::new();
+		self.subtree_id(&mut ids);
+		ids.len()
+	}
+
+	/// Helper for `count_unique_subtrees`; numbers each distinct subtree by
+	/// its color and the numbers of its subsections, in `ids`, giving the
+	/// number of this one.
+	fn subtree_id(&self, ids: &mut HashMap<(u32, Option<[usize; 4]>), usize>) -> usize {
+		let sections = self.sections.as_ref().map(|sects| {
+			let mut sect_ids = [0; 4];
+			for (id, section) in sect_ids.iter_mut().zip(sects.iter()) {
+				*id = section.subtree_id(ids);
+			}
+			sect_ids
+		});
+		let next = ids.len();
+		*ids.entry((self.color, sections)).or_insert(next)
+	}
+
+	/// Puts the tree in the form that `count_unique_subtrees` measures:
+	/// branches whose subsections are all leaves of one color become leaves
+	/// (as with `normalize`), so that subtrees showing the same thing are
+	/// equal, and a format storing each distinct subtree once would store
+	/// each of them once.
+	pub fn canonicalize(&mut self) {
+		self.normalize();
+	}
+
+	/// Mirrors the image left to right, by swapping the left and right
+	/// subsections of every node.
+	///
+	/// Gradients keep their colors at the mirrored corners, but since they
+	/// are drawn from the top-left corner's color to just short of the
+	/// others', they are not drawn exactly mirrored.
+	pub fn flip_horizontal(&mut self) {
+		if let Some(sections) = &mut self.sections {
+			sections.swap(0, 1);
+			sections.swap(2, 3);
+			sections.iter_mut().for_each(|s| s.flip_horizontal());
+		}
+	}
+
+	/// Mirrors the image top to bottom, by swapping the upper and lower
+	/// subsections of every node.
+	///
+	/// As with `flip_horizontal`, gradients are not drawn exactly mirrored.
+	pub fn flip_vertical(&mut self) {
+		if let Some(sections) = &mut self.sections {
+			sections.swap(0, 2);
+			sections.swap(1, 3);
+			sections.iter_mut().for_each(|s| s.flip_vertical());
+		}
+	}
+
+	/// Rotates the image a quarter turn clockwise, by moving the subsections
+	/// of every node around it.
+	///
+	/// As with `flip_horizontal`, gradients are not drawn exactly rotated.
+	pub fn rotate_cw(&mut self) {
+		if let Some(sections) = &mut self.sections {
+			let [top_left, top_right, bottom_left, bottom_right] = std::mem::take(&mut **sections);
+			**sections = [bottom_left, top_left, bottom_right, top_right];
+			sections.iter_mut().for_each(|s| s.rotate_cw());
+		}
+	}
+
+	/// Rotates the image a quarter turn counterclockwise, by moving the
+	/// subsections of every node around it.
+	///
+	/// As with `flip_horizontal`, gradients are not drawn exactly rotated.
+	pub fn rotate_ccw(&mut self) {
+		if let Some(sections) = &mut self.sections {
+			let [top_left, top_right, bottom_left, bottom_right] = std::mem::take(&mut **sections);
+			**sections = [top_right, bottom_right, top_left, bottom_left];
+			sections.iter_mut().for_each(|s| s.rotate_ccw());
+		}
+	}
+
+	/// A copy of the tree with every node more than `depth` levels below
+	/// this one removed; the nodes left at that depth become leaves, keeping
+	/// their colors.
+	pub fn truncated(&self, depth: usize) -> Self {
+		let sections = match self.sections {
+			Some(ref sections) if depth > 0 => Some(Box::new([
+				sections[0].truncated(depth - 1),
+				sections[1].truncated(depth - 1),
+				sections[2].truncated(depth - 1),
+				sections[3].truncated(depth - 1),
+			])),
+			_ => None
+		};
+		Self { color: self.color, sections, _pal: std::marker::PhantomData }
+	}
+
+	/// The node reached by following `path` from this one, each element
+	/// (0 to 3) picking a subsection in the same order as `sections`; `None`
+	/// if the path goes past a leaf or has an element above 3.
+	///
+	/// An empty path gives this node.
+	pub fn subtree(&self, path: &[u8]) -> Option<&Self> {
+		path.iter().try_fold(self, |node, &ind| node.sections.as_ref()?.get(ind as usize))
+	}
+
+	/// Like `subtree`, but mutable.
+	pub fn subtree_mut(&mut self, path: &[u8]) -> Option<&mut Self> {
+		path.iter().try_fold(self, |node, &ind| node.sections.as_mut()?.get_mut(ind as usize))
+	}
+
+	/// A copy of the node at `path` (see `subtree`) and everything below it,
+	/// as a tree of its own: the image cropped to that node's square.
+	pub fn crop(&self, path: &[u8]) -> Option<Self> {
+		self.subtree(path).map(|node| node.truncated(usize::MAX))
+	}
+
+	/// Replaces the node at `path` (see `subtree`) with a copy of `other`,
+	/// placing it in that node's square: the counterpart of `crop`.
+	///
+	/// Leaves along the path are first split into four leaves of their own
+	/// color, so that the rest of their squares look the same. Returns
+	/// `false`, leaving the tree as it was, if the path has an element above
+	/// 3.
+	pub fn stamp(&mut self, other: &Self, path: &[u8]) -> bool {
+		if path.iter().any(|&ind| ind > 3) {
+			return false;
+		}
+		let mut node = self;
+		for &ind in path {
+			let color = node.color;
+			node = &mut node.sections.get_or_insert_with(|| {
+				let mut sections: Box<[Self; 4]> = Default::default();
+				sections.iter_mut().for_each(|s| s.color = color);
+				sections
+			})[ind as usize];
+		}
+		*node = other.truncated(usize::MAX);
+		true
+	}
+
+	/// A tree whose root, of color `root_color`, has `quadrants` as its
+	/// subsections (in the same order as `sections`): the image of four
+	/// tiles side by side, as from `crop`ping each quadrant of it.
+	///
+	/// Trees of tiles analyzed separately (each with `from_image_with`, say,
+	/// perhaps on several threads) can be put together this way.
+	pub fn combine(quadrants: [Self; 4], root_color: u32) -> Self {
+		Self { color: root_color, sections: Some(Box::new(quadrants)), _pal: std::marker::PhantomData }
+	}
+
+	/// Like `combine`, but first checking that no quadrant is more than
+	/// `max_depth` levels deep, as none of a tile `2^max_depth` pixels wide
+	/// should be; a deeper one is a tile of another size, and would be drawn
+	/// at the wrong scale.
+	pub fn combine_checked(
+		quadrants: [Self; 4],
+		root_color: u32,
+		max_depth: usize
+	) -> Result<Self, super::error::CombineError> {
+		if let Some(ind) = quadrants.iter().position(|q| q.depth() > max_depth) {
+			return Err(super::error::CombineError::TooDeep(ind));
+		}
+		Ok(Self::combine(quadrants, root_color))
+	}
+
+	/// The paths (see `subtree`) of the nodes at which this tree and `other`
+	/// differ, in color number or in whether they are branches, parents
+	/// before their subsections.
+	///
+	/// Branches of both trees are compared further down even if their own
+	/// colors differ; nothing below a node that is a branch in only one of
+	/// them is listed.
+	pub fn diff(&self, other: &Self) -> Vec<TreePath> {
+		let mut diffs = Vec::new();
+		self.diff_into(other, &mut Vec::new(), &mut diffs);
+		diffs
+	}
+
+	/// Helper for `diff`.
+	fn diff_into(&self, other: &Self, path: &mut TreePath, diffs: &mut Vec<TreePath>) {
+		if self.color != other.color || self.sections.is_some() != other.sections.is_some() {
+			diffs.push(path.clone());
+		}
+		if let (Some(sects), Some(other_sects)) = (&self.sections, &other.sections) {
+			for (ind, (section, other_section)) in sects.iter().zip(other_sects.iter()).enumerate() {
+				path.push(ind as u8);
+				section.diff_into(other_section, path, diffs);
+				path.pop();
+			}
+		}
+	}
+
+	/// Whether this tree and `other` have the same structure, with each pair
+	/// of nodes' colors within `max_distance` (squared distance in RGBA) of
+	/// each other in `palette`; for comparing the results of lossy steps
+	/// that may round colors differently.
+	///
+	/// Colors outside the palette are never close to anything.
+	pub fn approx_eq(&self, other: &Self, palette: &P, max_distance: u32) -> bool {
+		let close = match (palette.to_rgba(self.color), palette.to_rgba(other.color)) {
+			(Ok(a), Ok(b)) => quantize::DistanceMetric::Euclidean.distance(&a, &b) <= max_distance,
+			_ => false
+		};
+		close && match (&self.sections, &other.sections) {
+			(Some(sects), Some(other_sects)) => sects.iter()
+				.zip(other_sects.iter())
+				.all(|(s, o)| s.approx_eq(o, palette, max_distance)),
+			(None, None) => true,
+			_ => false
+		}
+	}
+
+	/// Successively coarser versions of the tree, for picking a level of
+	/// detail: the full tree first, then with its deepest level of nodes
+	/// removed, and so on, down to the root alone.
+	pub fn mip_chain(&self) -> Vec<Self> {
+		(0..=self.depth()).rev().map(|depth| self.truncated(depth)).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::image::RenderOptions;
+	use crate::qti::DecodeOptions;
+	use crate::quantize::palette::DynamicPaletteView;
+	use crate::test_util::test_image;
+	use crate::QuadtreeNode;
+
+	/// A tree of `test_image(size, seed)` on a palette of 16 colors.
+	fn test_tree(size: u32, seed: u64) -> (QuadtreeNode<DynamicPaletteView>, DynamicPaletteView) {
+		let img = test_image(size, seed);
+		let palette: DynamicPaletteView = crate::quantize::generate_palette_median_cut(&img, 16);
+		let mut tree = QuadtreeNode::default();
+		tree.from_image_with(&img, &palette, &Default::default()).unwrap();
+		(tree, palette)
+	}
+
+	/// A leaf of `color`.
+	fn leaf(color: u32) -> QuadtreeNode<DynamicPaletteView> {
+		QuadtreeNode { color, ..Default::default() }
+	}
+
+	/// A branch of `color` over `sections`.
+	fn branch(sections: [QuadtreeNode<DynamicPaletteView>; 4], color: u32) -> QuadtreeNode<DynamicPaletteView> {
+		QuadtreeNode { color, sections: Some(Box::new(sections)), _pal: std::marker::PhantomData }
+	}
+
+	/// Draws `tree` at `size` by `size`, as set in `options`.
+	fn draw(tree: &QuadtreeNode<DynamicPaletteView>, palette: &DynamicPaletteView, size: u32, options: &RenderOptions) -> image::RgbaImage {
+		let mut img = image::RgbaImage::new(size, size);
+		tree.to_image_with(&mut img, palette, options).unwrap();
+		img
+	}
+
+	#[test]
+	fn mip_chain_coarsens_to_the_root() {
+		let (tree, palette) = test_tree(64, 1);
+		let chain = tree.mip_chain();
+		assert_eq!(chain.len(), tree.depth() + 1);
+		assert_eq!(chain[0], tree);
+		assert!(chain.windows(2).all(|pair| pair[1].node_count() <= pair[0].node_count()));
+		assert_eq!(chain.last().unwrap().node_count(), 1);
+		assert_eq!(chain.last().unwrap().color, tree.color);
+		for (level, mip) in chain.iter().enumerate() {
+			let depth = tree.depth() - level;
+			assert_eq!(mip.depth(), depth);
+			let preview = RenderOptions { max_depth: Some(depth), ..Default::default() };
+			assert!(draw(mip, &palette, 64, &Default::default()) == draw(&tree, &palette, 64, &preview));
+		}
+	}
+
+	#[test]
+	fn normalizing_shrinks_without_changing_the_image() {
+		let uniform = |color| branch([leaf(color), leaf(color), leaf(color), leaf(color)], color);
+		// Two redundant branches, one only once the branch below it is
+		// collapsed, and one that must stay
+		let redundant = || branch([
+			uniform(3),
+			branch([uniform(5), leaf(5), leaf(5), leaf(5)], 5),
+			leaf(1),
+			branch([leaf(1), leaf(2), leaf(1), leaf(2)], 1)
+		], 0);
+		let tree = redundant();
+		let palette = DynamicPaletteView::from((0..8).map(|c| image::Rgba([c * 30, 255 - c * 30, c * 10, 255])).collect::<Vec<_>>());
+
+		let mut normalized = redundant();
+		normalized.normalize();
+		assert_eq!(tree.redundant_node_count(), 12);
+		assert_eq!(normalized.node_count(), tree.node_count() - 12);
+		assert_eq!(normalized.redundant_node_count(), 0);
+		let mut again = redundant();
+		again.normalize();
+		again.normalize();
+		assert_eq!(again, normalized);
+		assert_eq!(normalized.sections.as_ref().unwrap()[3], tree.sections.as_ref().unwrap()[3]);
+		assert!(draw(&normalized, &palette, 16, &Default::default()) == draw(&tree, &palette, 16, &Default::default()));
+
+		let data = tree.to_qti(&palette).unwrap();
+		assert!(normalized.to_qti(&palette).unwrap().len() < data.len());
+		let (decoded, _) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+		assert_eq!(decoded, tree);
+		let options = DecodeOptions { normalize: true, ..Default::default() };
+		let (decoded, _) = QuadtreeNode::<DynamicPaletteView>::from_qti_with(&data, &options).unwrap();
+		assert_eq!(decoded, normalized);
+	}
+
+	#[test]
+	fn normalizing_gradients_keeps_the_image() {
+		let uniform = |color| branch([leaf(color), leaf(color), leaf(color), leaf(color)], color);
+		// Collapsing every uniform branch of the second quadrant would leave
+		// it all leaves of differing colors, so one stays a branch
+		let redundant = || branch([
+			uniform(3),
+			branch([uniform(5), uniform(2), leaf(1), uniform(6)], 5),
+			leaf(1),
+			branch([leaf(1), leaf(2), leaf(1), leaf(2)], 1)
+		], 0);
+		let tree = redundant();
+		let palette = DynamicPaletteView::from((0..8).map(|c| image::Rgba([c * 30, 255 - c * 30, c * 10, 255])).collect::<Vec<_>>());
+
+		let mut normalized = redundant();
+		normalized.normalize_gradients();
+		let sections = normalized.sections.as_ref().unwrap();
+		assert_eq!(sections[0], leaf(3));
+		assert_eq!(sections[1], branch([leaf(5), leaf(2), leaf(1), uniform(6)], 5));
+		assert_eq!(sections[3], tree.sections.as_ref().unwrap()[3]);
+		let gradient = RenderOptions { gradient: true, ..Default::default() };
+		assert!(draw(&normalized, &palette, 16, &gradient) == draw(&tree, &palette, 16, &gradient));
+
+		let mut solid = uniform(4);
+		solid.normalize_gradients();
+		assert_eq!(solid, leaf(4));
+	}
+
+	#[test]
+	fn four_rotations_are_the_identity() {
+		let (tree, palette) = test_tree(64, 2);
+		let mut rotated = test_tree(64, 2).0;
+		rotated.rotate_cw();
+		assert!(rotated != tree);
+		let upright = draw(&tree, &palette, 64, &Default::default());
+		assert!(draw(&rotated, &palette, 64, &Default::default()) == image::imageops::rotate90(&upright));
+		rotated.rotate_ccw();
+		assert_eq!(rotated, tree);
+		(0..4).for_each(|_| rotated.rotate_cw());
+		assert_eq!(rotated, tree);
+		(0..4).for_each(|_| rotated.rotate_ccw());
+		assert_eq!(rotated, tree);
+	}
+
+	#[test]
+	fn error_free_trimming_only_merges_one_color() {
+		// Mounting leaves no branches of one color
+		let (mut trimmed, palette) = test_tree(64, 3);
+		trimmed.trim_by_error(&palette, 0);
+		assert_eq!(trimmed, test_tree(64, 3).0);
+
+		// Colors a step apart are merged only with a budget for that step
+		let palette = DynamicPaletteView::from(vec![image::Rgba([100, 100, 100, 255]), image::Rgba([101, 100, 100, 255])]);
+		let tree = || branch([
+			branch([leaf(1), leaf(1), leaf(1), leaf(1)], 1),
+			branch([leaf(0), leaf(0), leaf(0), leaf(1)], 0),
+			leaf(0),
+			leaf(0)
+		], 0);
+		let mut trimmed = tree();
+		trimmed.trim_by_error(&palette, 0);
+		let mut normalized = tree();
+		normalized.normalize();
+		assert_eq!(trimmed, normalized);
+		assert_eq!(trimmed.node_count(), 9);
+		trimmed.trim_by_error(&palette, 1);
+		assert_eq!(trimmed, leaf(0));
+	}
+
+	#[test]
+	fn diff_lists_changed_nodes() {
+		let tree = || branch([
+			leaf(0),
+			branch([leaf(1), leaf(2), leaf(3), leaf(0)], 1),
+			leaf(2),
+			leaf(3)
+		], 0);
+		assert_eq!(tree(), tree());
+		assert!(tree().diff(&tree()).is_empty());
+
+		let mut changed = tree();
+		changed.subtree_mut(&[0]).unwrap().color = 4;
+		changed.subtree_mut(&[1]).unwrap().color = 2;
+		changed.subtree_mut(&[1, 2]).unwrap().color = 5;
+		changed.stamp(&leaf(1), &[3, 0]);
+		assert_ne!(changed, tree());
+		let paths = vec![vec![0], vec![1], vec![1, 2], vec![3]];
+		assert_eq!(changed.diff(&tree()), paths);
+		assert_eq!(tree().diff(&changed), paths);
+
+		// Below a root of another color, the trees are the same
+		let tree = test_tree(32, 4).0;
+		let mut recolored = test_tree(32, 4).0;
+		recolored.color = (tree.color + 1) % 16;
+		assert_ne!(recolored, tree);
+		assert_eq!(recolored.diff(&tree), vec![vec![]]);
+	}
+
+	#[test]
+	fn stamps_render_in_place() {
+		let (sprite, palette) = test_tree(16, 5);
+		let sprite_img = draw(&sprite, &palette, 16, &Default::default());
+		// Into a tree deep enough, and into a leaf to be split
+		for mut scene in [test_tree(64, 6).0, QuadtreeNode { color: 3, ..Default::default() }] {
+			let before = draw(&scene, &palette, 64, &Default::default());
+			assert!(!scene.stamp(&sprite, &[1, 4]));
+			assert!(scene.stamp(&sprite, &[1, 2]));
+			assert_eq!(scene.subtree(&[1, 2]), Some(&sprite));
+			let after = draw(&scene, &palette, 64, &Default::default());
+			for (x, y, pixel) in after.enumerate_pixels() {
+				let expected = if (32..48).contains(&x) && (16..32).contains(&y) {
+					sprite_img.get_pixel(x - 32, y - 16)
+				} else {
+					before.get_pixel(x, y)
+				};
+				assert_eq!(pixel, expected, "({}, {})", x, y);
+			}
+		}
+	}
+
+	#[test]
+	fn lossy_trimming_differs_from_trim() {
+		let group = |colors: [u32; 4]| branch([leaf(colors[0]), leaf(colors[1]), leaf(colors[2]), leaf(colors[3])], 9);
+		// Whether each of `trim`, and `trim_lossy` at 0.75 and 0.5, removes
+		// the leaves, and the color each gives their parent
+		let cases = [
+			([1, 2, 1, 1], Some(9), Some(1), Some(1)),
+			([1, 1, 1, 1], None, Some(1), Some(1)),
+			([1, 2, 3, 1], Some(9), None, Some(1)),
+			([1, 2, 2, 1], None, None, Some(1)),
+			([1, 2, 3, 4], None, None, None)
+		];
+		for (colors, trim, three_quarters, half) in cases.iter().copied() {
+			let expected = |color: Option<u32>| color.map_or_else(|| group(colors), leaf);
+			let mut trimmed = group(colors);
+			trimmed.trim(0);
+			assert_eq!(trimmed, expected(trim), "{:?}", colors);
+			for (dominance, result) in [(0.75, three_quarters), (0.5, half)] {
+				let mut trimmed = group(colors);
+				trimmed.trim_lossy(0, dominance);
+				assert_eq!(trimmed, expected(result), "{:?} at {}", colors, dominance);
+			}
+		}
+
+		// Leaves above `depth` are kept
+		let tree = || branch([group([1, 1, 1, 2]), leaf(1), leaf(1), leaf(1)], 1);
+		let mut trimmed = tree();
+		trimmed.trim_lossy(2, 0.5);
+		assert_eq!(trimmed, tree());
+		trimmed.trim_lossy(1, 0.5);
+		assert_eq!(trimmed, branch([leaf(1), leaf(1), leaf(1), leaf(1)], 1));
+		trimmed.trim_lossy(0, 0.5);
+		assert_eq!(trimmed, leaf(1));
+	}
+
+	#[test]
+	fn repeated_subtrees_are_counted_once() {
+		let leaf = |color| QuadtreeNode::<DynamicPaletteView> { color, ..Default::default() };
+		let checkered = || QuadtreeNode::combine([leaf(1), leaf(2), leaf(2), leaf(1)], 1);
+		let uniform = QuadtreeNode::combine([leaf(1), leaf(1), leaf(1), leaf(1)], 1);
+		// Two leaf colors, the checkered branch, the uniform one and the root
+		let mut tree = QuadtreeNode::combine([checkered(), uniform, checkered(), leaf(1)], 0);
+		assert_eq!(tree.node_count(), 17);
+		assert_eq!(tree.count_unique_subtrees(), 5);
+		tree.canonicalize();
+		assert_eq!(tree, QuadtreeNode::combine([checkered(), leaf(1), checkered(), leaf(1)], 0));
+		assert_eq!(tree.count_unique_subtrees(), 4);
+
+		// An image of four copies of one tile has one more distinct subtree
+		// than the tile: the root
+		let (mut tile, _) = test_tree(16, 7);
+		tile.canonicalize();
+		let copy = || tile.truncated(usize::MAX);
+		let tiled = QuadtreeNode::combine([copy(), copy(), copy(), copy()], tile.color);
+		assert!(tile.count_unique_subtrees() > 2);
+		assert_eq!(tiled.count_unique_subtrees(), tile.count_unique_subtrees() + 1);
+		assert_eq!(tiled.node_count(), 4 * tile.node_count() + 1);
+	}
+
+	#[test]
+	fn subtrees_follow_paths() {
+		let (mut tree, palette) = test_tree(64, 8);
+		let sections = tree.sections.as_ref().unwrap().iter().map(|s| s.truncated(usize::MAX)).collect::<Vec<_>>();
+		assert_eq!(tree.subtree(&[]), Some(&tree));
+		assert_eq!(tree.subtree(&[2]), Some(&sections[2]));
+		assert_eq!(tree.subtree(&[4]), None);
+		assert_eq!(tree.subtree_mut(&[4]), None);
+		assert_eq!(tree.crop(&[0, 255]), None);
+		// A path going on past a leaf
+		let path = (0..tree.depth() as u8).map(|_| 3).collect::<Vec<_>>();
+		let leaf_path = (1..=path.len()).map(|len| &path[..len])
+			.find(|p| tree.subtree(p).unwrap().sections.is_none())
+			.unwrap()
+			.to_vec();
+		let past_leaf = [&leaf_path[..], &[0, 0]].concat();
+		assert_eq!(tree.subtree(&past_leaf), None);
+		assert_eq!(tree.subtree_mut(&past_leaf), None);
+		assert_eq!(tree.crop(&past_leaf), None);
+
+		// Changes through `subtree_mut` show in `subtree`
+		tree.subtree_mut(&[1]).unwrap().color = 15 - sections[1].color;
+		assert_eq!(tree.subtree(&[1]).unwrap().color, 15 - sections[1].color);
+		assert_eq!(tree.subtree(&[1, 0]), sections[1].subtree(&[0]));
+
+		// Each quadrant cropped renders as that quadrant of the whole
+		let whole = draw(&tree, &palette, 64, &Default::default());
+		for (ind, (x, y)) in [(0, 0), (32, 0), (0, 32), (32, 32)].iter().copied().enumerate() {
+			let cropped = tree.crop(&[ind as u8]).unwrap();
+			assert_eq!(&cropped, tree.subtree(&[ind as u8]).unwrap());
+			let quadrant = image::imageops::crop_imm(&whole, x, y, 32, 32).to_image();
+			assert!(draw(&cropped, &palette, 32, &Default::default()) == quadrant, "quadrant {}", ind);
+		}
+	}
+}
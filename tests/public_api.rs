@@ -0,0 +1,50 @@
+//! The library as other crates see it: one `QuadtreeNode`, from `src/node`,
+//! re-exported from the crate root along with its modules.
+
+use quadtree_img::image::AnalyzeOptions;
+use quadtree_img::quantize::palette::{DynamicPaletteView, Palette};
+use quadtree_img::{qti, quantize, QuadtreeNode};
+
+/// A 16 by 16 image of four flat quadrants, one with a single odd pixel.
+fn quadrants() -> image::RgbaImage {
+	let colors = [[200, 30, 30, 255], [30, 200, 30, 255], [30, 30, 200, 255], [240, 240, 240, 255]];
+	image::RgbaImage::from_fn(16, 16, |x, y| match (x, y) {
+		(12, 12) => image::Rgba([0, 0, 0, 255]),
+		_ => image::Rgba(colors[(x / 8 + y / 8 * 2) as usize]),
+	})
+}
+
+#[test]
+fn root_and_node_paths_are_one_type() {
+	let tree: QuadtreeNode<DynamicPaletteView> = quadtree_img::node::QuadtreeNode::default();
+	assert_eq!(tree.node_count(), 1);
+}
+
+#[test]
+fn trees_round_trip_through_qti() {
+	let img = quadrants();
+	let palette: DynamicPaletteView = quantize::generate_palette(&img, 256);
+	assert_eq!(palette.len(), 5);
+	let options = AnalyzeOptions::default().sensitivity(16384).blur(0.).gradient_fit(None);
+	let mut tree = QuadtreeNode::default();
+	tree.from_image_with(&img, &palette, &options).unwrap();
+	let data = tree.to_qti(&palette).unwrap();
+	assert_eq!(qti::read_header(&data).unwrap().palette_width(), 3);
+	let (decoded, decoded_palette) = QuadtreeNode::<DynamicPaletteView>::from_qti(&data).unwrap();
+	assert_eq!((&decoded, &decoded_palette), (&tree, &palette));
+	assert_eq!(decoded.render(&decoded_palette, false).unwrap(), img);
+
+	// The odd pixel goes with a trim: three of its four siblings agree
+	let mut trimmed = decoded;
+	trimmed.trim(0);
+	assert!(trimmed.node_count() < tree.node_count());
+}
+
+#[test]
+fn images_round_trip_through_encode_and_decode() {
+	let img = quadrants();
+	let options = quadtree_img::EncodeImageOptions::default().sensitivity(1.).blur(0.).gradient(None);
+	let data = quadtree_img::encode(&img, &options).unwrap();
+	assert!(qti::is_qti(&data));
+	assert_eq!(quadtree_img::decode(&data, &Default::default()).unwrap(), img);
+}